@@ -0,0 +1,11 @@
+fn main() {
+    // The embed feature builds in the directory named by EMBED_DIR, so it
+    // needs building again when either changes.
+    println!("cargo:rerun-if-env-changed=EMBED_DIR");
+    if std::env::var_os("CARGO_FEATURE_EMBED").is_some() {
+        match std::env::var("EMBED_DIR") {
+            Ok(dir) => println!("cargo:rerun-if-changed={dir}"),
+            Err(_) => panic!("the embed feature needs EMBED_DIR set to the directory to embed"),
+        }
+    }
+}
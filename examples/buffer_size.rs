@@ -0,0 +1,53 @@
+//! Compare how fast a large file streams with different `--buffer-size`
+//! values, the same way `respond_with_file` reads files.
+//!
+//! ```text
+//! cargo run --release --example buffer_size [FILE]
+//! ```
+//!
+//! Without a file, a 256 MiB temporary file is used. Run it twice, since the
+//! first run may read from disk rather than the page cache.
+
+use futures::StreamExt;
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+
+const SIZES: &[usize] = &[4 * 1024, 8 * 1024, 64 * 1024, 256 * 1024];
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let (path, temporary) = match std::env::args_os().nth(1) {
+        Some(path) => (PathBuf::from(path), false),
+        None => {
+            let path = std::env::temp_dir().join("basic-http-server-buffer-size");
+            std::fs::write(&path, vec![0u8; 256 * 1024 * 1024])?;
+            (path, true)
+        }
+    };
+
+    for &size in SIZES {
+        let file = File::open(&path).await?;
+        let mut stream = ReaderStream::with_capacity(file, size);
+        let start = Instant::now();
+        let mut bytes = 0;
+        let mut chunks = 0;
+        while let Some(chunk) = stream.next().await {
+            bytes += chunk?.len();
+            chunks += 1;
+        }
+        let secs = start.elapsed().as_secs_f64();
+        println!(
+            "{:>4} KiB: {:>8.1} MiB/s, {} chunks",
+            size / 1024,
+            bytes as f64 / secs / (1024.0 * 1024.0),
+            chunks
+        );
+    }
+
+    if temporary {
+        std::fs::remove_file(&path)?;
+    }
+    Ok(())
+}
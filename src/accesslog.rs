@@ -0,0 +1,111 @@
+//! The access log line for a request is written once its response body has
+//! been sent, or the client has gone before it was, so that aborted
+//! downloads are marked as such.
+//!
+//! The lines are events with their own target, in the request's span, so
+//! they can be filtered apart from the rest of the log, as with
+//! `RUST_LOG=basic_http_server=info,basic_http_server::access=off`.
+//! Events, rather than a `Layer` of their own, go through the same writer as
+//! the rest of the log, so `--log-file` and its rotation apply to them too.
+
+use crate::error::Error;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tracing::{debug, info, Span};
+
+/// The target of access log events.
+const TARGET: &str = "basic_http_server::access";
+
+/// A response body that writes the access log line when it's dropped.
+pub struct Logged {
+    inner: BoxBody<Bytes, Error>,
+    /// The access log line, without any marker.
+    line: String,
+    /// The request, for the debug log.
+    request: String,
+    /// The bytes of the body sent so far.
+    sent: u64,
+    /// The bytes the client is owed, if that's known.
+    expected: Option<u64>,
+    /// Whether the body ended.
+    finished: bool,
+    /// Whether reading the body failed, rather than the client leaving.
+    failed: bool,
+    /// The request's span, which the body outlives.
+    span: Span,
+}
+
+impl Logged {
+    /// Wrap a response body to log `line` when it's done. `expected` is the
+    /// length of the body hyper sends, if known: 0 for bodies it leaves off,
+    /// like a HEAD response's.
+    pub fn wrap(
+        inner: BoxBody<Bytes, Error>,
+        line: String,
+        request: String,
+        expected: Option<u64>,
+    ) -> BoxBody<Bytes, Error> {
+        Logged {
+            inner,
+            line,
+            request,
+            sent: 0,
+            expected,
+            finished: false,
+            failed: false,
+            span: Span::current(),
+        }
+        .boxed()
+    }
+}
+
+impl Body for Logged {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    this.sent += data.len() as u64;
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.failed = true,
+            Poll::Ready(None) => this.finished = true,
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Logged {
+    fn drop(&mut self) {
+        // hyper stops polling once it has sent the Content-Length.
+        let complete = self.finished || self.expected.is_some_and(|len| self.sent >= len);
+        let _entered = self.span.enter();
+        if complete {
+            info!(target: TARGET, "{}", self.line);
+        } else if self.failed {
+            info!(target: TARGET, "{} (failed)", self.line);
+        } else {
+            debug!("client left {} after {} bytes", self.request, self.sent);
+            info!(target: TARGET, "{} (aborted)", self.line);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! `--acme-dir`: answering ACME HTTP-01 challenges (RFC 8555) from a
+//! directory outside the root, as certbot's `--webroot` and other clients
+//! write them.
+//!
+//! Only `/.well-known/acme-challenge/<token>` is served from there. The rest
+//! of `/.well-known/` is served from the root as usual.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::vfs;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header;
+use hyper::{Method, Response, StatusCode};
+use std::path::Path;
+use tracing::debug;
+
+/// Where challenges are requested.
+pub const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// The largest challenge file served. Real ones are under 100 bytes.
+const MAX_CHALLENGE_SIZE: u64 = 4096;
+
+/// Answer a request for a challenge from `--acme-dir`, or `None` if the
+/// request isn't for one or there's no ACME directory.
+pub async fn serve(
+    config: &Config,
+    method: &Method,
+    url_path: &str,
+) -> Option<Result<Response<BoxBody<Bytes, Error>>>> {
+    let dir = config.acme_dir.as_ref()?;
+    if method != Method::GET && method != Method::HEAD {
+        return None;
+    }
+    let token = url_path.strip_prefix(CHALLENGE_PREFIX)?;
+    Some(respond_with_challenge(dir, token).await)
+}
+
+/// Whether a token could be an ACME token: base64url without padding. This
+/// also means it can't name anything outside the directory.
+fn is_token(token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+async fn respond_with_challenge(
+    dir: &Path,
+    token: &str,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    if !is_token(token) {
+        debug!("not an ACME token: {}", token);
+        return Err(vfs::not_found());
+    }
+
+    let path = dir.join(token);
+    let meta = tokio::fs::metadata(&path).await?;
+    if !meta.is_file() || meta.len() > MAX_CHALLENGE_SIZE {
+        debug!("not a challenge file: {}", path.display());
+        return Err(vfs::not_found());
+    }
+
+    debug!("serving ACME challenge {}", path.display());
+    let body = tokio::fs::read(&path).await?;
+    let resp = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, mime::TEXT_PLAIN.as_ref())
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(
+            Full::new(Bytes::from(body))
+                .map_err(|never| match never {})
+                .boxed(),
+        )?;
+    Ok(resp)
+}
@@ -0,0 +1,125 @@
+//! The admin endpoint on `--admin-addr`, a separate listener for inspecting
+//! and controlling the server at runtime.
+//!
+//! Every response is JSON:
+//!
+//! - `GET /config`: the effective configuration.
+//! - `GET /stats`: the request and connection counters.
+//! - `GET /errors`: the latest warnings and errors logged.
+//! - `POST /maintenance/on`, `POST /maintenance/off`: switch maintenance
+//!   mode.
+//! - `POST /reload`: reload the configuration, as SIGHUP does.
+//!
+//! So that web pages in a local browser can't reach it, a loopback endpoint
+//! only answers requests whose `Host` is the loopback address it's bound to,
+//! and `POST` requests carrying an `Origin` header are refused.
+
+use crate::config::{self, Config, SharedConfig};
+use crate::error::{Error, Result};
+use crate::{logfile, maintenance, stats};
+use http::uri::Authority;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header;
+use hyper::{Method, Request, Response, StatusCode};
+use serde_json::json;
+use std::net::IpAddr;
+use tracing::debug;
+
+/// Answer an admin request.
+pub async fn serve(
+    config: &SharedConfig,
+    req: Request<Incoming>,
+) -> Response<BoxBody<Bytes, Error>> {
+    debug!("admin request: {} {}", req.method(), req.uri().path());
+    super::transform_error(route(config, &req), None)
+}
+
+fn route(
+    config: &SharedConfig,
+    req: &Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    let (status, body) = match (req.method(), req.uri().path()) {
+        _ if !host_allowed(&config.load(), req) => (
+            StatusCode::MISDIRECTED_REQUEST,
+            json!({ "error": "unexpected host" }),
+        ),
+        (&Method::POST, _) if req.headers().contains_key(header::ORIGIN) => (
+            StatusCode::FORBIDDEN,
+            json!({ "error": "cross-origin requests are refused" }),
+        ),
+        (&Method::GET, "/config") => (StatusCode::OK, serde_json::to_value(&**config.load())?),
+        (&Method::GET, "/stats") => {
+            let mut stats = serde_json::to_value(stats::snapshot())?;
+            stats["maintenance"] = json!(maintenance::is_on());
+            (StatusCode::OK, stats)
+        }
+        (&Method::GET, "/errors") => (StatusCode::OK, json!(logfile::recent_errors())),
+        (&Method::POST, "/maintenance/on") => {
+            maintenance::set(true);
+            (StatusCode::OK, json!({ "maintenance": true }))
+        }
+        (&Method::POST, "/maintenance/off") => {
+            maintenance::set(false);
+            (StatusCode::OK, json!({ "maintenance": false }))
+        }
+        (&Method::POST, "/reload") => {
+            let reloaded = config::reload_shared(config);
+            (StatusCode::OK, json!({ "reloaded": reloaded }))
+        }
+        (
+            _,
+            "/config" | "/stats" | "/errors" | "/maintenance/on" | "/maintenance/off" | "/reload",
+        ) => (
+            StatusCode::METHOD_NOT_ALLOWED,
+            json!({ "error": "method not allowed" }),
+        ),
+        _ => (StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+    };
+
+    let body = format!("{body}\n");
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(
+            Full::new(Bytes::from(body))
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .map_err(Error::from)
+}
+
+/// Whether the `Host` of a request names the address the endpoint is bound
+/// to. A browser sends the name it was given, so this stops DNS rebinding
+/// from pointing a web page's own host name at the endpoint. Endpoints
+/// opened up with `--admin-allow-remote` may be reached by any name.
+fn host_allowed(config: &Config, req: &Request<Incoming>) -> bool {
+    if config.admin_allow_remote {
+        return true;
+    }
+    let Some(addr) = config.admin_addr else {
+        return false;
+    };
+    let Some(authority) = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<Authority>().ok())
+    else {
+        return false;
+    };
+    let host = authority.host();
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    let ip_matches = host
+        .parse::<IpAddr>()
+        .is_ok_and(|ip| ip.to_canonical() == addr.ip().to_canonical());
+    // With port 0 the bound port isn't known here, so any port is accepted.
+    let port_matches = addr.port() == 0 || authority.port_u16().unwrap_or(80) == addr.port();
+    ip_matches && port_matches
+}
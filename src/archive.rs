@@ -0,0 +1,314 @@
+//! Serving the contents of a zip or tar archive given as the root, without
+//! unpacking it.
+//!
+//! The archive is indexed when the configuration is loaded. Entries of zip
+//! and plain tar archives are read from the archive file when requested:
+//! stored entries are streamed from their offset, and deflated zip entries
+//! are decompressed whole. A gzipped tar archive can't be read from an
+//! offset, so its files are decompressed into memory up front.
+
+use crate::error::{Error, Result};
+use crate::vfs::{self, relative_key, tree_key, Vfs};
+use flate2::read::{DeflateDecoder, GzDecoder};
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::{Bytes, Frame};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+use zip::{CompressionMethod, ZipArchive};
+
+/// An indexed archive.
+pub struct Archive {
+    /// The archive file, which is also the root directory in URL mapping.
+    path: PathBuf,
+    /// The files, by their "/"-separated paths in the archive.
+    files: BTreeMap<String, Entry>,
+    /// The directories, including those only implied by the files in them,
+    /// and "" for the root.
+    dirs: BTreeSet<String>,
+    /// When the archive file was modified, which stands for its entries.
+    modified: Option<SystemTime>,
+}
+
+struct Entry {
+    /// The uncompressed size.
+    len: u64,
+    data: Data,
+}
+
+enum Data {
+    /// Uncompressed, at this offset in the archive file.
+    Stored { offset: u64 },
+    /// Deflated, at this offset in the archive file.
+    Deflated { offset: u64, compressed_len: u64 },
+    /// Already decompressed.
+    Memory(Bytes),
+}
+
+/// The kinds of archive served.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl Archive {
+    /// Index the archive at `path`, if it's a file rather than a directory.
+    ///
+    /// The kind of archive is told by its first bytes, or failing that, its
+    /// extension.
+    pub fn open(path: &Path) -> Result<Option<Archive>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let kind = Kind::detect(path)?.ok_or_else(|| {
+            Error::Config(format!(
+                "{} is not a directory, or a zip or tar archive",
+                path.display()
+            ))
+        })?;
+
+        let mut archive = Archive {
+            path: path.to_owned(),
+            files: BTreeMap::new(),
+            dirs: BTreeSet::from([String::new()]),
+            modified: None,
+        };
+        let file = std::fs::File::open(path)?;
+        archive.modified = file.metadata()?.modified().ok();
+        match kind {
+            Kind::Zip => archive.index_zip(file)?,
+            Kind::Tar => archive.index_tar(file, false)?,
+            Kind::TarGz => archive.index_tar(GzDecoder::new(file), true)?,
+        }
+        Ok(Some(archive))
+    }
+
+    fn index_zip(&mut self, file: std::fs::File) -> Result<()> {
+        let mut zip = ZipArchive::new(file)?;
+        for i in 0..zip.len() {
+            let entry = zip.by_index_raw(i)?;
+            let Some(key) = entry.enclosed_name().as_deref().and_then(relative_key) else {
+                warn!("skipping archive entry {:?}", entry.name());
+                continue;
+            };
+            if entry.is_dir() {
+                self.add_dir(key);
+                continue;
+            }
+            let offset = entry.data_start();
+            let data = match entry.compression() {
+                CompressionMethod::Stored => Data::Stored { offset },
+                CompressionMethod::Deflated => Data::Deflated {
+                    offset,
+                    compressed_len: entry.compressed_size(),
+                },
+                method => {
+                    warn!("skipping archive entry {key} compressed with {method:?}");
+                    continue;
+                }
+            };
+            self.add_file(key, entry.size(), data);
+        }
+        Ok(())
+    }
+
+    /// Index a tar archive, keeping the files in memory if `in_memory` is
+    /// set, as it must be when the archive can't be seeked in.
+    fn index_tar(&mut self, reader: impl Read, in_memory: bool) -> Result<()> {
+        let mut tar = tar::Archive::new(reader);
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let Some(key) = relative_key(&path) else {
+                warn!("skipping archive entry {}", path.display());
+                continue;
+            };
+            let kind = entry.header().entry_type();
+            if kind.is_dir() {
+                self.add_dir(key);
+                continue;
+            }
+            if !kind.is_file() {
+                continue;
+            }
+            let len = entry.size();
+            let data = if in_memory {
+                let mut buf = Vec::new();
+                entry.read_to_end(&mut buf)?;
+                Data::Memory(Bytes::from(buf))
+            } else {
+                Data::Stored {
+                    offset: entry.raw_file_position(),
+                }
+            };
+            self.add_file(key, len, data);
+        }
+        Ok(())
+    }
+
+    fn add_file(&mut self, key: String, len: u64, data: Data) {
+        if let Some((parent, _)) = key.rsplit_once('/') {
+            self.add_dir(parent.to_string());
+        }
+        self.files.insert(key, Entry { len, data });
+    }
+
+    fn add_dir(&mut self, mut key: String) {
+        while self.dirs.insert(key.clone()) {
+            match key.rsplit_once('/') {
+                Some((parent, _)) => key = parent.to_string(),
+                None => key = String::new(),
+            }
+        }
+    }
+
+    /// The file entry for a local path under the root.
+    fn entry(&self, path: &Path) -> Option<&Entry> {
+        let (key, dir_only) = tree_key(&self.path, path)?;
+        if dir_only {
+            return None;
+        }
+        self.files.get(&key)
+    }
+}
+
+impl Vfs for Archive {
+    fn describe(&self) -> String {
+        format!(
+            "archive {} ({} files)",
+            self.path.display(),
+            self.files.len()
+        )
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        tree_key(&self.path, path).is_some_and(|(key, _)| self.dirs.contains(&key))
+    }
+
+    fn file_meta(&self, path: &Path) -> Option<(u64, Option<SystemTime>)> {
+        let entry = self.entry(path)?;
+        Some((entry.len, self.modified))
+    }
+
+    fn list(&self, path: &Path) -> Option<Vec<(PathBuf, Option<u64>)>> {
+        let (key, _) = tree_key(&self.path, path)?;
+        if !self.dirs.contains(&key) {
+            return None;
+        }
+        let prefix = if key.is_empty() {
+            key
+        } else {
+            format!("{key}/")
+        };
+        let is_child = |k: &str| k.strip_prefix(&prefix).is_some_and(|n| !n.contains('/'));
+        let local = |k: &str| self.path.join(k);
+
+        let dirs = self
+            .dirs
+            .range(prefix.clone()..)
+            .take_while(|k| k.starts_with(&prefix))
+            .filter(|k| !k.is_empty() && is_child(k))
+            .map(|k| (local(k), None));
+        let files = self
+            .files
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter(|(k, _)| is_child(k))
+            .map(|(k, entry)| (local(k), Some(entry.len)));
+        Some(dirs.chain(files).collect())
+    }
+
+    fn read<'a>(
+        &'a self,
+        path: &'a Path,
+        buffer_size: usize,
+    ) -> BoxFuture<'a, Result<(u64, BoxBody<Bytes, Error>)>> {
+        Box::pin(async move {
+            let entry = self.entry(path).ok_or_else(vfs::not_found)?;
+
+            let body = match &entry.data {
+                Data::Stored { offset } => {
+                    let mut file = tokio::fs::File::open(&self.path).await?;
+                    file.seek(SeekFrom::Start(*offset)).await?;
+                    let reader_stream =
+                        ReaderStream::with_capacity(file.take(entry.len), buffer_size);
+                    StreamBody::new(reader_stream.map_ok(Frame::data).map_err(Error::Io)).boxed()
+                }
+                Data::Deflated {
+                    offset,
+                    compressed_len,
+                } => {
+                    let (archive, offset, compressed_len, len) =
+                        (self.path.clone(), *offset, *compressed_len, entry.len);
+                    let data = tokio::task::spawn_blocking(move || {
+                        inflate(&archive, offset, compressed_len, len)
+                    })
+                    .await
+                    .map_err(io::Error::other)??;
+                    Full::new(Bytes::from(data))
+                        .map_err(|never| match never {})
+                        .boxed()
+                }
+                Data::Memory(data) => Full::new(data.clone())
+                    .map_err(|never| match never {})
+                    .boxed(),
+            };
+            Ok((entry.len, body))
+        })
+    }
+}
+
+impl Kind {
+    fn detect(path: &Path) -> io::Result<Option<Kind>> {
+        let mut head = Vec::with_capacity(512);
+        std::fs::File::open(path)?
+            .take(512)
+            .read_to_end(&mut head)?;
+
+        if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+            return Ok(Some(Kind::Zip));
+        }
+        if head.starts_with(&[0x1f, 0x8b]) {
+            return Ok(Some(Kind::TarGz));
+        }
+        if head.get(257..262) == Some(&b"ustar"[..]) {
+            return Ok(Some(Kind::Tar));
+        }
+
+        // Old tar archives have no magic.
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if name.ends_with(".tar") {
+            return Ok(Some(Kind::Tar));
+        }
+        Ok(None)
+    }
+}
+
+/// Decompress a deflated zip entry, checking it's the size the archive says.
+fn inflate(archive: &Path, offset: u64, compressed_len: u64, len: u64) -> io::Result<Vec<u8>> {
+    let mut file = std::fs::File::open(archive)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::with_capacity(len.try_into().unwrap_or(0));
+    DeflateDecoder::new(file.take(compressed_len))
+        .take(len.saturating_add(1))
+        .read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "archive entry is not the size recorded",
+        ));
+    }
+    Ok(data)
+}
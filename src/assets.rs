@@ -0,0 +1,78 @@
+//! Assets built into the binary for the pages the developer extensions make,
+//! so they look right without fetching anything from elsewhere.
+//!
+//! They're served under `--assets-prefix`, "/__basic_http_server/assets" by
+//! default, which is checked before looking for files, so a real directory
+//! at that path is hidden. Pick another prefix if that collides with
+//! something.
+//!
+//! Asset URLs include the server's version, so they change whenever the
+//! assets might, and clients can cache them forever.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header;
+use hyper::{Response, StatusCode};
+use tracing::debug;
+
+/// The stylesheet for extension pages.
+static STYLE: &str = include_str!("style.css");
+
+/// The assets, by name.
+static ASSETS: &[(&str, &str, &str)] = &[("style.css", "text/css; charset=utf-8", STYLE)];
+
+/// The URL path to serve assets under, including the server version.
+fn versioned_prefix(config: &Config) -> String {
+    format!(
+        "{}/{}",
+        config.assets_prefix.trim_end_matches('/'),
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// The URL of the stylesheet for extension pages.
+pub fn stylesheet_url(config: &Config) -> String {
+    format!("{}/style.css", versioned_prefix(config))
+}
+
+/// Answer a request for an asset, or `None` if the path isn't under
+/// `--assets-prefix`.
+///
+/// Anything else under the prefix, including assets of other versions, is
+/// 404.
+pub fn serve(config: &Config, path: &str) -> Option<Result<Response<BoxBody<Bytes, Error>>>> {
+    let prefix = config.assets_prefix.trim_end_matches('/');
+    let rest = path.strip_prefix(prefix)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+
+    let asset = path
+        .strip_prefix(&versioned_prefix(config))
+        .and_then(|name| name.strip_prefix('/'))
+        .and_then(|name| ASSETS.iter().find(|(n, _, _)| *n == name));
+    let Some((name, content_type, body)) = asset else {
+        debug!("no such asset: {}", path);
+        return Some(super::make_error_response_from_code(
+            StatusCode::NOT_FOUND,
+            None,
+        ));
+    };
+
+    debug!("serving asset {}", name);
+    let resp = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, *content_type)
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable")
+        .body(
+            Full::new(Bytes::from_static(body.as_bytes()))
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .map_err(Error::from);
+    Some(resp)
+}
@@ -0,0 +1,81 @@
+//! Exiting without being asked, for servers that tooling starts and forgets:
+//! `--exit-on-root-removed` and `--idle-timeout`.
+//!
+//! Either way the server stops like it does on ctrl-c, logging why and the
+//! summary of the session.
+
+use crate::error::{Error, Result};
+use crate::ext::dir_id;
+use crate::stats;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use tracing::info;
+
+/// The exit code when the root directory is removed or replaced, to tell it
+/// apart from an error.
+pub const ROOT_REMOVED_EXIT_CODE: u8 = 3;
+
+/// How often the root and the idle time are checked.
+const CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Why the server exits on its own.
+#[derive(Debug)]
+pub enum Reason {
+    RootRemoved(PathBuf),
+    Idle(Duration),
+}
+
+impl Reason {
+    /// Log why the server is exiting.
+    pub fn log(&self) {
+        match self {
+            Reason::RootRemoved(root) => {
+                info!(
+                    "root directory {} was removed or replaced, exiting",
+                    root.display()
+                );
+            }
+            Reason::Idle(timeout) => {
+                info!("no requests for {}s, exiting", timeout.as_secs());
+            }
+        }
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Reason::RootRemoved(_) => ExitCode::from(ROOT_REMOVED_EXIT_CODE),
+            Reason::Idle(_) => ExitCode::SUCCESS,
+        }
+    }
+}
+
+/// Wait until the root directory is gone, or another has been put in its
+/// place. Fails at once if the root can't be looked at to begin with.
+pub async fn root_removed(root: PathBuf) -> Result<Reason> {
+    let id = dir_id(&root).await.map_err(|e| {
+        Error::Config(format!(
+            "--exit-on-root-removed can't watch {}: {e}",
+            root.display()
+        ))
+    })?;
+    let mut interval = tokio::time::interval(CHECK_PERIOD);
+    loop {
+        interval.tick().await;
+        if !dir_id(&root).await.is_ok_and(|current| current == id) {
+            return Ok(Reason::RootRemoved(root));
+        }
+    }
+}
+
+/// Wait until nothing has been served, and no connection has been open, for
+/// `timeout`.
+pub async fn idle(timeout: Duration) -> Reason {
+    let mut interval = tokio::time::interval(CHECK_PERIOD);
+    loop {
+        interval.tick().await;
+        if stats::idle_for().is_some_and(|idle| idle >= timeout) {
+            return Reason::Idle(timeout);
+        }
+    }
+}
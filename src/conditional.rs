@@ -0,0 +1,301 @@
+//! Conditional requests, as in RFC 9110 section 13.
+//!
+//! The preconditions are evaluated in one place, in the RFC's order:
+//! `If-Match`, then `If-Unmodified-Since`, then `If-None-Match`, then
+//! `If-Modified-Since`, and last `If-Range`. They're only evaluated once
+//! there's a file to send, so redirects and errors are never turned into
+//! 304s or 412s.
+
+use crate::error::{Error, Result};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderMap, HeaderName};
+use hyper::{Method, Response, StatusCode};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The validators of the representation about to be sent. Either can be
+/// missing: an entity tag condition can't be met without an entity tag, and
+/// a date condition is ignored without a date.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Validators<'a> {
+    pub etag: Option<&'a str>,
+    pub modified: Option<SystemTime>,
+}
+
+/// What to do about a request's preconditions.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Send the representation, honoring any `Range`.
+    Send,
+    /// Send the whole representation, as `If-Range` didn't match.
+    SendWhole,
+    /// Respond 304.
+    NotModified,
+    /// Respond 412.
+    PreconditionFailed,
+}
+
+/// Evaluate the request's preconditions against the representation's
+/// validators.
+///
+/// Headers that can't be parsed are ignored, as are the date conditions
+/// when there's an entity tag condition that takes their place, or no
+/// modification date to compare them to.
+pub fn evaluate(method: &Method, headers: &HeaderMap, validators: &Validators) -> Outcome {
+    let is_get_or_head = method == Method::GET || method == Method::HEAD;
+
+    // 1 and 2: the client's copy must be the current one.
+    if let Some(tags) = header_str(headers, header::IF_MATCH) {
+        if !matches_any(tags, validators.etag, strong_eq) {
+            return Outcome::PreconditionFailed;
+        }
+    } else if let Some(since) = header_date(headers, header::IF_UNMODIFIED_SINCE) {
+        if validators.modified.is_some_and(|m| secs(m) > secs(since)) {
+            return Outcome::PreconditionFailed;
+        }
+    }
+
+    // 3 and 4: the client's copy mustn't be the current one.
+    if let Some(tags) = header_str(headers, header::IF_NONE_MATCH) {
+        if matches_any(tags, validators.etag, weak_eq) {
+            return if is_get_or_head {
+                Outcome::NotModified
+            } else {
+                Outcome::PreconditionFailed
+            };
+        }
+    } else if let Some(since) = header_date(headers, header::IF_MODIFIED_SINCE) {
+        if is_get_or_head && validators.modified.is_some_and(|m| secs(m) <= secs(since)) {
+            return Outcome::NotModified;
+        }
+    }
+
+    // 5: a range of the client's copy is only useful if it's current.
+    if method == Method::GET && headers.contains_key(header::RANGE) {
+        if let Some(value) = header_str(headers, header::IF_RANGE) {
+            if !if_range_matches(value, validators) {
+                return Outcome::SendWhole;
+            }
+        }
+    }
+
+    Outcome::Send
+}
+
+/// The response for a request that its preconditions answer, a 304 or a 412,
+/// or `None` to send the representation.
+pub fn response(
+    outcome: &Outcome,
+    validators: &Validators,
+) -> Option<Result<Response<BoxBody<Bytes, Error>>>> {
+    match outcome {
+        Outcome::NotModified => Some(not_modified_response(validators)),
+        Outcome::PreconditionFailed => Some(super::make_error_response_from_code(
+            StatusCode::PRECONDITION_FAILED,
+            None,
+        )),
+        Outcome::Send | Outcome::SendWhole => None,
+    }
+}
+
+/// A 304 response with the representation's validators.
+fn not_modified_response(validators: &Validators) -> Result<Response<BoxBody<Bytes, Error>>> {
+    let mut builder = Response::builder().status(StatusCode::NOT_MODIFIED);
+    if let Some(etag) = validators.etag {
+        builder = builder.header(header::ETAG, etag);
+    }
+    if let Some(modified) = validators.modified {
+        builder = builder.header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+
+    builder
+        .body(
+            Empty::<Bytes>::new()
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .map_err(Error::from)
+}
+
+/// Whether an `If-Match` or `If-None-Match` list matches the current entity
+/// tag. "*" matches any current representation, which there is whenever
+/// preconditions are evaluated.
+fn matches_any(tags: &str, etag: Option<&str>, eq: fn(&str, &str) -> bool) -> bool {
+    if tags.trim() == "*" {
+        return true;
+    }
+    etag.is_some_and(|etag| tags.split(',').any(|tag| eq(tag.trim(), etag)))
+}
+
+/// The strong comparison: neither tag is weak, and they're the same.
+fn strong_eq(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+/// The weak comparison: the same, ignoring whether either is weak.
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+/// Whether `If-Range` names the current representation, with a strong
+/// entity tag or its exact modification date.
+fn if_range_matches(value: &str, validators: &Validators) -> bool {
+    let value = value.trim();
+    if value.starts_with('"') || value.starts_with("W/") {
+        return validators.etag.is_some_and(|etag| strong_eq(value, etag));
+    }
+    match (httpdate::parse_http_date(value), validators.modified) {
+        (Ok(date), Some(modified)) => secs(date) == secs(modified),
+        _ => false,
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: HeaderName) -> Option<&str> {
+    headers.get(name)?.to_str().ok()
+}
+
+fn header_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(header_str(headers, name)?).ok()
+}
+
+/// HTTP dates only have one second resolution.
+fn secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const ETAG: &str = "\"abc\"";
+
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    fn validators() -> Validators<'static> {
+        Validators {
+            etag: Some(ETAG),
+            modified: Some(modified()),
+        }
+    }
+
+    fn date(offset_secs: i64) -> String {
+        let t = if offset_secs < 0 {
+            modified() - Duration::from_secs(offset_secs.unsigned_abs())
+        } else {
+            modified() + Duration::from_secs(offset_secs as u64)
+        };
+        httpdate::fmt_http_date(t)
+    }
+
+    #[test]
+    fn evaluate_each_header_combination() {
+        let same = date(0);
+        let earlier = date(-60);
+        let later = date(60);
+        let range = ("range", "bytes=0-1");
+        #[rustfmt::skip]
+        let cases: Vec<(Method, Vec<(&str, &str)>, Outcome)> = vec![
+            (Method::GET, vec![], Outcome::Send),
+            // If-Match
+            (Method::GET, vec![("if-match", ETAG)], Outcome::Send),
+            (Method::GET, vec![("if-match", "\"xyz\"")], Outcome::PreconditionFailed),
+            (Method::GET, vec![("if-match", "\"xyz\", \"abc\"")], Outcome::Send),
+            (Method::GET, vec![("if-match", "*")], Outcome::Send),
+            (Method::GET, vec![("if-match", "W/\"abc\"")], Outcome::PreconditionFailed),
+            (Method::HEAD, vec![("if-match", "\"xyz\"")], Outcome::PreconditionFailed),
+            // If-Unmodified-Since, and If-Match taking its place
+            (Method::GET, vec![("if-unmodified-since", same.as_str())], Outcome::Send),
+            (Method::GET, vec![("if-unmodified-since", later.as_str())], Outcome::Send),
+            (
+                Method::GET,
+                vec![("if-unmodified-since", earlier.as_str())],
+                Outcome::PreconditionFailed,
+            ),
+            (
+                Method::GET,
+                vec![("if-match", ETAG), ("if-unmodified-since", earlier.as_str())],
+                Outcome::Send,
+            ),
+            // If-None-Match
+            (Method::GET, vec![("if-none-match", ETAG)], Outcome::NotModified),
+            (Method::HEAD, vec![("if-none-match", ETAG)], Outcome::NotModified),
+            (Method::GET, vec![("if-none-match", "W/\"abc\"")], Outcome::NotModified),
+            (Method::GET, vec![("if-none-match", "*")], Outcome::NotModified),
+            (Method::GET, vec![("if-none-match", "\"xyz\"")], Outcome::Send),
+            (Method::PUT, vec![("if-none-match", ETAG)], Outcome::PreconditionFailed),
+            // If-Modified-Since, and If-None-Match winning over it
+            (Method::GET, vec![("if-modified-since", same.as_str())], Outcome::NotModified),
+            (Method::GET, vec![("if-modified-since", later.as_str())], Outcome::NotModified),
+            (Method::GET, vec![("if-modified-since", earlier.as_str())], Outcome::Send),
+            (Method::POST, vec![("if-modified-since", same.as_str())], Outcome::Send),
+            (
+                Method::GET,
+                vec![("if-none-match", "\"xyz\""), ("if-modified-since", same.as_str())],
+                Outcome::Send,
+            ),
+            (
+                Method::GET,
+                vec![("if-none-match", ETAG), ("if-modified-since", earlier.as_str())],
+                Outcome::NotModified,
+            ),
+            // If-Range, falling back to the whole body
+            (Method::GET, vec![range, ("if-range", ETAG)], Outcome::Send),
+            (Method::GET, vec![range, ("if-range", "\"xyz\"")], Outcome::SendWhole),
+            (Method::GET, vec![range, ("if-range", "W/\"abc\"")], Outcome::SendWhole),
+            (Method::GET, vec![range, ("if-range", same.as_str())], Outcome::Send),
+            (Method::GET, vec![range, ("if-range", earlier.as_str())], Outcome::SendWhole),
+            (Method::GET, vec![("if-range", "\"xyz\"")], Outcome::Send),
+            (Method::HEAD, vec![range, ("if-range", "\"xyz\"")], Outcome::Send),
+            // Unparsable dates are ignored.
+            (Method::GET, vec![("if-modified-since", "yesterday")], Outcome::Send),
+            (Method::GET, vec![("if-unmodified-since", "yesterday")], Outcome::Send),
+        ];
+
+        for (method, pairs, expected) in cases {
+            let mut headers = HeaderMap::new();
+            for (name, value) in &pairs {
+                let name = HeaderName::from_bytes(name.as_bytes()).expect("valid header name");
+                headers.append(name, value.parse().expect("valid header value"));
+            }
+            let outcome = evaluate(&method, &headers, &validators());
+            assert_eq!(outcome, expected, "{method} {pairs:?}");
+        }
+    }
+
+    #[test]
+    fn conditions_need_validators() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_MATCH, ETAG.parse().unwrap());
+        let none = Validators::default();
+        assert_eq!(
+            evaluate(&Method::GET, &headers, &none),
+            Outcome::PreconditionFailed
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_MODIFIED_SINCE, date(0).parse().unwrap());
+        assert_eq!(evaluate(&Method::GET, &headers, &none), Outcome::Send);
+    }
+
+    #[test]
+    fn response_for_each_outcome() {
+        let resp = response(&Outcome::NotModified, &validators())
+            .expect("a response")
+            .expect("built");
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[header::ETAG], ETAG);
+        assert_eq!(resp.headers()[header::LAST_MODIFIED], date(0).as_str());
+
+        let resp = response(&Outcome::PreconditionFailed, &validators())
+            .expect("a response")
+            .expect("built");
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+
+        assert!(response(&Outcome::Send, &validators()).is_none());
+        assert!(response(&Outcome::SendWhole, &validators()).is_none());
+    }
+}
@@ -0,0 +1,1504 @@
+//! Command line options and the TOML configuration file.
+//!
+//! Configuration file keys are the field names of `Config`. Rather than
+//! maintaining a second parser, each key is translated back into the
+//! equivalent command line arguments, so file values are validated by
+//! exactly the same rules as the command line.
+
+use crate::archive::Archive;
+#[cfg(feature = "embed")]
+use crate::embed::Embedded;
+use crate::error::{Error, Result};
+use crate::ext::{HidePatterns, TextTypes, THEME_SET};
+use crate::headers::HeaderRules;
+use crate::policy::Policy;
+use crate::proxy::Cidr;
+use crate::redirects::Redirects;
+use crate::rewrites::Rewrite;
+use crate::templates::Templates;
+use crate::vfs::Vfs;
+use crate::vhost::VHost;
+use arc_swap::ArcSwap;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use http::{HeaderValue, StatusCode};
+use serde::{Serialize, Serializer};
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+/// The configuration shared by all connections, which may be replaced when
+/// reloaded.
+pub type SharedConfig = Arc<ArcSwap<Config>>;
+
+/// The configuration object, parsed from command line options.
+#[derive(Clone, Parser, Serialize)]
+#[command(version, about = "A basic HTTP file server")]
+pub struct Config {
+    /// The HOST:PORT combination to listen on, where the host is a name or
+    /// an IP address (default "127.0.0.1:4000", unless only listening on a
+    /// Unix domain socket). Every address the host resolves to is bound.
+    #[arg(short = 'a', long = "addr", value_name = "ADDR", value_parser = parse_host_port)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addr: Option<String>,
+
+    /// Bind only the first address `--addr` resolves to.
+    #[arg(long = "bind-first")]
+    pub bind_first: bool,
+
+    /// The number of TCP listeners per address, each with its own accept
+    /// loop, sharing the address with `SO_REUSEPORT`.
+    #[arg(
+        long = "workers",
+        value_name = "COUNT",
+        default_value = "1",
+        value_parser = clap::value_parser!(u16).range(1..).map(usize::from)
+    )]
+    pub workers: usize,
+
+    /// Listen on a Unix domain socket at this path, in addition to TCP if
+    /// `--addr` is also given.
+    #[arg(long = "uds", value_name = "PATH")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uds: Option<PathBuf>,
+
+    /// The permissions of the Unix domain socket file, in octal.
+    #[arg(long = "uds-mode", value_name = "MODE", value_parser = parse_octal_mode)]
+    #[serde(
+        serialize_with = "serialize_octal_mode",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub uds_mode: Option<u32>,
+
+    /// The root directory for serving files, or a zip or tar archive to
+    /// serve the contents of. It's made absolute when the configuration is
+    /// loaded.
+    #[arg(value_name = "ROOT", default_value = ".")]
+    pub root_dir: PathBuf,
+
+    /// Create the root directory, and any missing parents, if it doesn't
+    /// exist.
+    #[arg(long = "create-root")]
+    pub create_root: bool,
+
+    /// Serve the tree built into the binary, rather than files on disk.
+    #[cfg(feature = "embed")]
+    #[arg(long = "embedded")]
+    pub embedded: bool,
+
+    /// The tree served in place of the root directory: the archive, when the
+    /// root is one, or the embedded tree.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub vfs: Option<Arc<dyn Vfs>>,
+
+    /// The status code of redirects: 301, 302, 307 or 308.
+    #[arg(
+        long = "redirect-code",
+        value_name = "CODE",
+        default_value = "302",
+        value_parser = parse_redirect_code
+    )]
+    pub redirect_code: u16,
+
+    /// The names of the index file served for a directory URL, tried in
+    /// order. Defaults to "index.html". With extensions, "index.md" and then
+    /// "README.md" are tried after these. Repeatable.
+    #[arg(long = "index", value_name = "NAME", value_parser = parse_index_name)]
+    pub index: Vec<String>,
+
+    /// Redirect requests for a directory's index file to the directory, like
+    /// "/docs/index.html" to "/docs/", with `--redirect-code`.
+    #[arg(long = "canonical-index")]
+    pub canonical_index: bool,
+
+    /// Whether to redirect directory URLs to add a trailing "/", redirect
+    /// file URLs to strip one, or keep URLs as they are.
+    #[arg(long = "trailing-slash", value_enum, default_value_t = TrailingSlash::Add)]
+    pub trailing_slash: TrailingSlash,
+
+    /// The maximum size of the request line and headers, in bytes. Must be at
+    /// least 8192.
+    #[arg(
+        long = "max-header-size",
+        value_name = "BYTES",
+        default_value = "65536",
+        value_parser = parse_max_header_size
+    )]
+    pub max_header_size: usize,
+
+    /// The maximum number of request headers.
+    #[arg(long = "max-headers", value_name = "COUNT", default_value = "100")]
+    pub max_headers: usize,
+
+    /// The maximum length of a request URI, in bytes. Longer URIs get a 414.
+    #[arg(long = "max-uri-length", value_name = "BYTES", default_value = "8192")]
+    pub max_uri_length: usize,
+
+    /// Let clients cache successful responses for this many seconds.
+    #[arg(long = "cache", value_name = "SECONDS", conflicts_with = "no_cache")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<u64>,
+
+    /// Tell clients not to store any responses, and always send full
+    /// responses rather than "304 Not Modified", for development.
+    #[arg(long = "no-cache")]
+    pub no_cache: bool,
+
+    /// Let clients cache successful responses for URL paths matching this
+    /// glob forever, for fingerprinted files like "app.3f9ab2.js". Overrides
+    /// `--cache`. Repeatable.
+    #[arg(long = "immutable", value_name = "GLOB")]
+    pub immutable: Vec<String>,
+
+    /// The `--immutable` patterns, compiled.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub immutable_patterns: GlobSet,
+
+    /// Set a policy for a URL prefix and every path under it: KEY is
+    /// "cache-control", for the `Cache-Control` of successful responses, or
+    /// "cors", for `Access-Control-Allow-Origin`. The most specific prefix
+    /// setting a key wins, and overrides `--cache` and `--immutable`, though
+    /// not `--no-cache`. Repeatable.
+    #[arg(long = "policy", value_name = "PREFIX:KEY=VALUE")]
+    pub policy: Vec<Policy>,
+
+    /// The value of the `Server` header sent with every response. Defaults
+    /// to "basic-http-server/<version>".
+    #[arg(
+        long = "server-id",
+        value_name = "STRING",
+        value_parser = parse_server_id,
+        conflicts_with = "no_server_id"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_id: Option<String>,
+
+    /// Don't send a `Server` header.
+    #[arg(long = "no-server-id")]
+    pub no_server_id: bool,
+
+    /// Ask search engines to stay away: send `X-Robots-Tag: noindex,
+    /// nofollow` with every response, and serve a `robots.txt` disallowing
+    /// everything, unless the root has its own.
+    #[arg(long = "deny-robots")]
+    pub deny_robots: bool,
+
+    /// The `Server` header value, from the two options above.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub server_header: Option<HeaderValue>,
+
+    /// The size of the chunks files are read and sent in, in bytes. The
+    /// default, 4096, suits small files; large files are sent with fewer
+    /// system calls with a bigger buffer. See `examples/buffer_size.rs`.
+    #[arg(
+        long = "buffer-size",
+        value_name = "BYTES",
+        default_value = "4096",
+        value_parser = clap::value_parser!(u32).range(1..).map(|n| n as usize)
+    )]
+    pub buffer_size: usize,
+
+    /// Leave Nagle's algorithm on for TCP connections, rather than setting
+    /// `TCP_NODELAY`.
+    #[arg(long = "no-nodelay")]
+    pub no_nodelay: bool,
+
+    /// Remember file metadata for half a second, saving filesystem calls
+    /// when many files are requested at once. Changes on disk can take that
+    /// long to be seen. Not used with `--no-cache`.
+    #[arg(long = "stat-cache")]
+    pub stat_cache: bool,
+
+    /// Close every connection after one response, rather than keeping it
+    /// open for more requests.
+    #[arg(long = "no-keepalive")]
+    pub no_keepalive: bool,
+
+    /// Close connections that have been idle between requests for this many
+    /// seconds. By default they stay open until the client closes them.
+    #[arg(
+        long = "keepalive-timeout",
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "no_keepalive"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keepalive_timeout: Option<u64>,
+
+    /// Refuse to send more than this many bytes of a file, responding 403.
+    /// This is the whole file, or the bytes asked for by a range request, so
+    /// parts of a larger file can still be fetched. It doesn't apply to
+    /// directory listings or rendered markdown.
+    #[arg(long = "max-file-size", value_name = "BYTES")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+
+    /// Send whole files without a `Content-Length`, using chunked encoding,
+    /// so a file still being written is sent up to wherever it ends when
+    /// it's read, as for following a log in a browser.
+    #[arg(long = "no-content-length")]
+    pub no_content_length: bool,
+
+    /// Only serve files with these extensions, responding 404 to others.
+    /// Comma-separated, or repeatable.
+    #[arg(
+        long = "allow-ext",
+        value_name = "EXT",
+        value_delimiter = ',',
+        conflicts_with = "deny_ext"
+    )]
+    pub allow_ext: Vec<String>,
+
+    /// With `--allow-ext`, also serve files without an extension.
+    #[arg(long = "allow-noext")]
+    pub allow_noext: bool,
+
+    /// Don't serve files with these extensions, responding 404 instead.
+    /// Comma-separated, or repeatable.
+    #[arg(long = "deny-ext", value_name = "EXT", value_delimiter = ',')]
+    pub deny_ext: Vec<String>,
+
+    /// Respond 429 to clients making more than this many requests per
+    /// second, after a burst of twice as many.
+    #[arg(
+        long = "rate-limit",
+        value_name = "REQUESTS",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<u32>,
+
+    /// Apply `--rate-limit` to local clients too.
+    #[arg(long = "rate-limit-local")]
+    pub rate_limit_local: bool,
+
+    /// Log the client address from `X-Forwarded-For` for requests that come
+    /// through a trusted proxy.
+    #[arg(long = "trust-proxy")]
+    pub trust_proxy: bool,
+
+    /// A proxy address or network to trust with `--trust-proxy`, like
+    /// "10.0.0.0/8". Defaults to loopback addresses. Repeatable.
+    #[arg(long = "trusted-proxy", value_name = "CIDR", value_delimiter = ',')]
+    pub trusted_proxy: Vec<Cidr>,
+
+    /// Only let clients in this network connect, like "10.1.2.0/24", along
+    /// with those in any other `--allow`. The most specific `--allow` or
+    /// `--deny` containing a client decides. Repeatable.
+    #[arg(long = "allow", value_name = "CIDR", value_delimiter = ',')]
+    pub allow: Vec<Cidr>,
+
+    /// Refuse connections from clients in this network, unless a more
+    /// specific `--allow` contains them. Repeatable.
+    #[arg(long = "deny", value_name = "CIDR", value_delimiter = ',')]
+    pub deny: Vec<Cidr>,
+
+    /// Answer every request from a refused client with 403, rather than
+    /// closing the connection, so browsers show why.
+    #[arg(long = "deny-with-403")]
+    pub deny_with_403: bool,
+
+    /// Respond 404 to "/favicon.ico" when the root directory has no
+    /// favicon, rather than serving a built-in one.
+    #[arg(long = "no-default-favicon")]
+    pub no_default_favicon: bool,
+
+    /// Enable developer extensions.
+    #[arg(short = 'x')]
+    pub use_extensions: bool,
+
+    /// List directories without an index file, as plain text with one name
+    /// per line and a "/" after directories, or as the HTML listing for
+    /// clients that prefer HTML. This is for use without extensions, which
+    /// always list directories as HTML.
+    #[arg(long = "list-dirs")]
+    pub list_dirs: bool,
+
+    /// The largest markdown file, in bytes, that the extensions render as
+    /// HTML. Larger files are served as plain text.
+    #[arg(long = "max-md-size", value_name = "BYTES", default_value = "8388608")]
+    pub max_md_size: u64,
+
+    /// Extra markdown features to turn on, as a comma-separated list.
+    #[arg(
+        long = "md-options",
+        value_name = "OPTIONS",
+        value_enum,
+        value_delimiter = ','
+    )]
+    pub md_options: Vec<MdOption>,
+
+    /// How the extensions render `$...$` and `$$...$$` math in markdown:
+    /// with KaTeX, or not at all.
+    #[arg(long = "md-math", value_enum, default_value_t = MdMath::Katex)]
+    pub md_math: MdMath,
+
+    /// Where to load KaTeX from. Point this at a copy of KaTeX's `dist`
+    /// directory to render math offline.
+    #[arg(
+        long = "md-math-url",
+        value_name = "URL",
+        default_value = "https://cdn.jsdelivr.net/npm/katex@0.16.11/dist"
+    )]
+    pub md_math_url: String,
+
+    /// The URL path to serve the stylesheet for extension pages under. Files
+    /// under it aren't served, so change it if it collides with a real
+    /// path.
+    #[arg(
+        long = "assets-prefix",
+        value_name = "PATH",
+        default_value = "/__basic_http_server/assets",
+        value_parser = parse_assets_prefix
+    )]
+    pub assets_prefix: String,
+
+    /// Render ```mermaid code blocks in markdown as diagrams.
+    #[arg(long = "md-mermaid")]
+    pub md_mermaid: bool,
+
+    /// Where to load the mermaid ES module from. Point this at a local copy
+    /// to render diagrams offline.
+    #[arg(
+        long = "md-mermaid-url",
+        value_name = "URL",
+        default_value = "https://cdn.jsdelivr.net/npm/mermaid@11/dist/mermaid.esm.min.mjs"
+    )]
+    pub md_mermaid_url: String,
+
+    /// The largest source file, in bytes, that the extensions show as a page
+    /// with line numbers. Larger files are served as plain text.
+    #[arg(
+        long = "max-source-view-size",
+        value_name = "BYTES",
+        default_value = "1048576"
+    )]
+    pub max_source_view_size: u64,
+
+    /// The largest source file, in bytes, that the extensions highlight.
+    /// Larger files are shown without highlighting.
+    #[arg(
+        long = "max-highlight-size",
+        value_name = "BYTES",
+        default_value = "262144"
+    )]
+    pub max_highlight_size: u64,
+
+    /// The syntax highlighting theme for source files.
+    #[arg(
+        long = "highlight-theme",
+        value_name = "THEME",
+        default_value = "InspiredGitHub",
+        value_parser = parse_highlight_theme
+    )]
+    pub highlight_theme: String,
+
+    /// Give SHA-256 digests of files: as JSON for `?hash=sha256`, and in a
+    /// `Repr-Digest` header when asked for with `Want-Repr-Digest`.
+    #[arg(long = "digests")]
+    pub digests: bool,
+
+    /// The largest file, in bytes, that `--digests` hashes.
+    #[arg(
+        long = "max-digest-size",
+        value_name = "BYTES",
+        default_value = "1073741824"
+    )]
+    pub max_digest_size: u64,
+
+    /// The largest JSON file, in bytes, that the extensions show
+    /// pretty-printed. Larger files are served as they are.
+    #[arg(
+        long = "max-json-view-size",
+        value_name = "BYTES",
+        default_value = "4194304"
+    )]
+    pub max_json_view_size: u64,
+
+    /// Also serve files with this extension as text. Repeatable.
+    #[arg(long = "text-ext", value_name = "EXT")]
+    pub text_ext: Vec<String>,
+
+    /// Also serve files with this name as text. Repeatable.
+    #[arg(long = "text-file", value_name = "NAME")]
+    pub text_file: Vec<String>,
+
+    /// Don't serve files with this extension as text, even if they are by
+    /// default. Repeatable.
+    #[arg(long = "no-text-ext", value_name = "EXT")]
+    pub no_text_ext: Vec<String>,
+
+    /// The file extensions and names served as text, built from the three
+    /// options above.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub text_types: TextTypes,
+
+    /// Leave paths matching this gitignore-style glob out of directory
+    /// listings. Patterns match the path relative to the root, and patterns
+    /// without a "/" match at any depth. Repeatable.
+    #[arg(long = "hide", value_name = "GLOB")]
+    pub hide: Vec<String>,
+
+    /// Also respond 404 to requests for hidden paths, and anything inside
+    /// hidden directories.
+    #[arg(long = "hide-strict")]
+    pub hide_strict: bool,
+
+    /// Respond 403 to requests for FIFOs, sockets and device files, rather
+    /// than 404 as if they weren't there. They're never opened or listed.
+    #[arg(long = "forbid-special-files")]
+    pub forbid_special_files: bool,
+
+    /// The `--hide` patterns, compiled.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub hide_patterns: HidePatterns,
+
+    /// A directory of translated error pages, named like "404.de.html",
+    /// chosen by the request's `Accept-Language`. The built-in English pages
+    /// are the fallback.
+    #[arg(long = "error-lang-dir", value_name = "DIR")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_lang_dir: Option<PathBuf>,
+
+    /// A directory of handlebars templates for error pages, named for a
+    /// status like "404.hbs", a class like "5xx.hbs", or "default.hbs". The
+    /// most specific one is used, with `status`, `reason`, `message`, why
+    /// the request was refused if that's known, `path` and `method`. A
+    /// translated page from `--error-lang-dir` comes first.
+    #[arg(long = "error-template-dir", value_name = "DIR")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_template_dir: Option<PathBuf>,
+
+    /// Serve ACME HTTP-01 challenges, "/.well-known/acme-challenge/TOKEN",
+    /// from this directory rather than the root, as with certbot's
+    /// `--webroot`. The rest of "/.well-known/" is still served from the
+    /// root.
+    #[arg(long = "acme-dir", value_name = "DIR")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acme_dir: Option<PathBuf>,
+
+    /// Always make error responses JSON, like `{"status":404,...}`, rather
+    /// than only for requests whose `Accept` prefers JSON to HTML.
+    #[arg(long = "json-errors")]
+    pub json_errors: bool,
+
+    /// When a requested path doesn't exist, look for one whose names differ
+    /// only in Unicode normalization, like the decomposed (NFD) names macOS
+    /// stores and the composed (NFC) names browsers send.
+    #[arg(long = "normalize-unicode")]
+    pub normalize_unicode: bool,
+
+    /// When a requested path doesn't exist, look for one whose names differ
+    /// only in case. A name matching more than one file is still not found.
+    #[arg(long = "case-insensitive")]
+    pub case_insensitive: bool,
+
+    /// Answer WebDAV `PROPFIND` requests, so the server can be browsed as a
+    /// read-only network drive.
+    #[arg(long = "webdav")]
+    pub webdav: bool,
+
+    /// Start in maintenance mode, answering every request with 503 except
+    /// health checks at "/__health". SIGUSR1 toggles maintenance mode.
+    #[arg(long = "maintenance")]
+    pub maintenance: bool,
+
+    /// The `Retry-After` of responses in maintenance mode, in seconds.
+    #[arg(
+        long = "maintenance-retry-after",
+        value_name = "SECONDS",
+        default_value = "60"
+    )]
+    pub maintenance_retry_after: u64,
+
+    /// Leave the requested path off error pages, showing only the status.
+    #[arg(long = "quiet-errors")]
+    pub quiet_errors: bool,
+
+    /// Read options from a TOML file. Options given on the command line take
+    /// precedence.
+    #[arg(long = "config", value_name = "FILE")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// Reload the configuration file whenever it changes. SIGHUP reloads it
+    /// regardless.
+    #[arg(long = "watch-config")]
+    pub watch_config: bool,
+
+    /// Serve requests for one URL path from another, without a redirect.
+    /// FROM may end in "*" to match a prefix, and a "*" in TO is replaced
+    /// with what it matched. Rewrites come before redirect rules. Repeatable.
+    #[arg(long = "rewrite", value_name = "FROM=TO")]
+    pub rewrite: Vec<Rewrite>,
+
+    /// Serve requests for a host from another root directory, like
+    /// "a.localhost=./site-a". HOST may start with "*." to match subdomains.
+    /// Requests for other hosts are served from ROOT. Repeatable.
+    #[arg(long = "vhost", value_name = "HOST=DIR")]
+    pub vhost: Vec<VHost>,
+
+    /// Respond 421 to requests for hosts that don't match a `--vhost`,
+    /// rather than serving them from ROOT.
+    #[arg(long = "vhost-strict")]
+    pub vhost_strict: bool,
+
+    /// The configuration for each `--vhost`, in the same order: this one with
+    /// the host's root directory.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub vhost_configs: Vec<Arc<Config>>,
+
+    /// Listen on this address for the admin endpoint, which reports on and
+    /// controls the server. See `src/admin.rs`.
+    #[arg(long = "admin-addr", value_name = "ADDR")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_addr: Option<SocketAddr>,
+
+    /// Allow `--admin-addr` to be an address other clients can reach.
+    #[arg(long = "admin-allow-remote")]
+    pub admin_allow_remote: bool,
+
+    /// Log a summary of requests served every this many seconds, as well as
+    /// at shutdown.
+    #[arg(
+        long = "stats-interval",
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats_interval: Option<u64>,
+
+    /// Exit when the root directory is removed or replaced, with exit code 3,
+    /// checking once a second. For servers that tooling starts on temporary
+    /// directories.
+    #[arg(long = "exit-on-root-removed")]
+    pub exit_on_root_removed: bool,
+
+    /// Exit when no request has been served, and no connection has been
+    /// open, for this many seconds.
+    #[arg(
+        long = "idle-timeout",
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<u64>,
+
+    /// Show a status line of connections, requests, bytes served and the
+    /// latest request below the log, redrawn every second. Only when stderr
+    /// is a terminal, and not when logging more than the info level.
+    #[arg(long = "status-line")]
+    pub status_line: bool,
+
+    /// Log the headers of every request and response, with credentials left
+    /// out. Without this they're logged at the trace level.
+    #[arg(long = "dump-headers")]
+    pub dump_headers: bool,
+
+    /// Also write the log to this file.
+    #[arg(long = "log-file", value_name = "FILE")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
+
+    /// Where to write the log when there's a `--log-file`.
+    #[arg(long = "log-to", value_name = "WHERE", default_value = "both")]
+    pub log_to: LogTo,
+
+    /// Rotate the log file before it grows past this many bytes.
+    #[arg(long = "log-rotate-size", value_name = "BYTES")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_rotate_size: Option<u64>,
+
+    /// The number of rotated log files to keep.
+    #[arg(long = "log-keep", value_name = "COUNT", default_value = "5")]
+    pub log_keep: usize,
+
+    /// Whether to color the console output: `auto` colors it when stderr is
+    /// a terminal. The log file is never colored.
+    #[arg(long = "color", value_name = "WHEN", default_value = "auto")]
+    pub color: ColorChoice,
+
+    /// Redirect rules, one `FROM TO [STATUS]` per line, in the format of
+    /// Netlify's `_redirects` files. Defaults to `_redirects` in the root
+    /// directory, if there is one.
+    #[arg(long = "redirects", value_name = "FILE")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirects: Option<PathBuf>,
+
+    /// Reload the redirect rules whenever the redirects file changes.
+    #[arg(long = "watch-redirects")]
+    pub watch_redirects: bool,
+
+    /// The rules from the redirects file.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub redirect_rules: Redirects,
+
+    /// Response headers for URL paths, in the format of Netlify's `_headers`
+    /// files. Defaults to `_headers` in the root directory, if there is one.
+    #[arg(long = "headers-file", value_name = "FILE")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers_file: Option<PathBuf>,
+
+    /// Reload the header rules whenever the headers file changes.
+    #[arg(long = "watch-headers")]
+    pub watch_headers: bool,
+
+    /// The rules from the headers file.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub header_rules: HeaderRules,
+
+    /// A directory of handlebars templates for HTML pages. Each `.hbs` or
+    /// `.html` file is a partial named after the file, and one named `page`
+    /// replaces the built-in page template.
+    #[arg(long = "template-dir", value_name = "DIR")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template_dir: Option<PathBuf>,
+
+    /// The templates from the template directory.
+    #[arg(skip)]
+    #[serde(skip)]
+    pub templates: Arc<Templates>,
+
+    /// Print the effective configuration as TOML and exit.
+    #[arg(long = "print-config")]
+    #[serde(skip)]
+    pub print_config: bool,
+
+    /// Print the file extensions and names served as text, and exit.
+    #[arg(long = "print-text-types")]
+    #[serde(skip)]
+    pub print_text_types: bool,
+
+    /// Print the rules that decide how each request is served, in the order
+    /// they're checked, and exit.
+    #[arg(long = "print-routes")]
+    #[serde(skip)]
+    pub print_routes: bool,
+
+    /// Print how the Content-Type of a file at PATH is decided, and exit.
+    #[arg(long = "print-mime", value_name = "PATH")]
+    #[serde(skip)]
+    pub print_mime: Option<PathBuf>,
+
+    /// Write the listing of each directory under the root to `index.html`
+    /// in the same place under OUTDIR, and exit.
+    #[arg(long = "generate-index", value_name = "OUTDIR")]
+    #[serde(skip)]
+    pub generate_index: Option<PathBuf>,
+
+    /// Let `--generate-index` overwrite existing files.
+    #[arg(long = "force", requires = "generate_index")]
+    #[serde(skip)]
+    pub force: bool,
+}
+
+/// The trailing "/" policy for redirects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingSlash {
+    /// Redirect directory URLs without a trailing "/" to add one.
+    Add,
+    /// Redirect file URLs with a trailing "/" to remove it.
+    Strip,
+    /// Never redirect, serving directory indexes at either URL.
+    Keep,
+}
+
+/// Where the log goes when there's a log file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTo {
+    /// Only the log file.
+    File,
+    /// The log file and stderr.
+    Both,
+}
+
+/// When to color the console output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    /// When stderr is a terminal.
+    Auto,
+    /// Always, even when stderr is redirected.
+    Always,
+    /// Never.
+    Never,
+}
+
+/// Optional markdown features, beyond the GitHub-like defaults.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MdOption {
+    /// Footnote references and definitions.
+    Footnotes,
+    /// Curly quotes, en and em dashes, and ellipses.
+    Smart,
+    /// Superscript with `^text^`.
+    Superscript,
+    /// Definition lists.
+    DescriptionLists,
+    /// Line breaks in paragraphs are kept as `<br>`.
+    Hardbreaks,
+}
+
+/// How markdown math is rendered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MdMath {
+    /// Render math with KaTeX.
+    Katex,
+    /// Leave `$` delimiters as plain text.
+    Off,
+}
+
+/// Options that only make sense on the command line.
+static COMMAND_LINE_ONLY: &[&str] = &[
+    "config",
+    "force",
+    "generate_index",
+    "print_config",
+    "print_mime",
+    "print_routes",
+    "print_text_types",
+];
+
+/// Options that are fixed once the listeners are bound, and so are not
+/// changed by a reload.
+static FIXED_AT_STARTUP: &[&str] = &[
+    "addr",
+    "bind_first",
+    "workers",
+    "color",
+    "log_file",
+    "log_keep",
+    "log_rotate_size",
+    "log_to",
+    "maintenance",
+    "stats_interval",
+    "status_line",
+    "exit_on_root_removed",
+    "idle_timeout",
+    "admin_addr",
+    "admin_allow_remote",
+    "uds",
+    "uds_mode",
+    "watch_config",
+    "watch_headers",
+    "watch_redirects",
+];
+
+impl Config {
+    /// Create the configuration from the process arguments, merged with the
+    /// configuration file if `--config` was given.
+    ///
+    /// Like `Config::parse`, this exits the process on bad arguments or
+    /// `--help`.
+    pub fn load() -> Result<Config> {
+        let args: Vec<OsString> = std::env::args_os().collect();
+        let matches = Config::command().get_matches_from(&args);
+        Config::from_args_and_file(args, &matches)
+    }
+
+    /// Create the configuration from these arguments, the first being the
+    /// program name, as `load` does from the process's, but returning an
+    /// error rather than exiting.
+    #[cfg(test)]
+    pub fn from_args(args: &[&str]) -> Result<Config> {
+        let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        let matches = Config::command()
+            .try_get_matches_from(&args)
+            .map_err(|e| Error::Config(clap_message(&e)))?;
+        Config::from_args_and_file(args, &matches)
+    }
+
+    /// Merge the configuration file into already-parsed arguments, and
+    /// prepare the result for use.
+    fn from_args_and_file(args: Vec<OsString>, matches: &ArgMatches) -> Result<Config> {
+        let mut config = Config::merge_file(args, matches)?;
+        config.prepare()?;
+        Ok(config)
+    }
+
+    /// Merge the configuration file into already-parsed arguments.
+    fn merge_file(args: Vec<OsString>, matches: &ArgMatches) -> Result<Config> {
+        let config =
+            Config::from_arg_matches(matches).map_err(|e| Error::Config(clap_message(&e)))?;
+
+        let Some(path) = &config.config else {
+            return Ok(config);
+        };
+
+        let (file_opts, file_positionals) = config_file_args(path, matches)?;
+
+        // The file's options go first, then the real command line, which
+        // never repeats them since command-line keys were skipped.
+        let mut merged = Vec::with_capacity(args.len() + file_opts.len() + 1);
+        merged.push(program_name(&args));
+        merged.extend(file_opts);
+        merged.extend(args.into_iter().skip(1));
+        if !file_positionals.is_empty() {
+            merged.push(OsString::from("--"));
+            merged.extend(file_positionals);
+        }
+
+        let matches = Config::command()
+            .try_get_matches_from(merged)
+            .map_err(|e| Error::Config(clap_message(&e)))?;
+        Config::from_arg_matches(&matches).map_err(|e| Error::Config(clap_message(&e)))
+    }
+
+    /// Build the lookup structures derived from the options, once, rather
+    /// than on every request.
+    fn prepare(&mut self) -> Result<()> {
+        #[cfg(feature = "embed")]
+        let embedded = self.embedded;
+        #[cfg(not(feature = "embed"))]
+        let embedded = false;
+        if !embedded {
+            self.check_root()?;
+        }
+
+        self.vfs = self.open_vfs()?;
+        if self.vfs.is_some() {
+            let needs_dir = [
+                ("--webdav", self.webdav),
+                ("--generate-index", self.generate_index.is_some()),
+                ("--case-insensitive", self.case_insensitive),
+                ("--normalize-unicode", self.normalize_unicode),
+            ];
+            if let Some((flag, _)) = needs_dir.iter().find(|(_, set)| *set) {
+                return Err(Error::Config(format!(
+                    "{flag} needs a root directory on disk"
+                )));
+            }
+        }
+
+        self.text_types = TextTypes::new(&self.text_ext, &self.text_file, &self.no_text_ext);
+        self.hide_patterns = HidePatterns::new(&self.hide)?;
+
+        let redirects = self.redirects_path();
+        if self.redirects.is_some() || redirects.is_file() {
+            self.redirect_rules = Redirects::load(&redirects)?;
+        }
+
+        let headers = self.headers_path();
+        if self.headers_file.is_some() || headers.is_file() {
+            self.header_rules = HeaderRules::load(&headers)?;
+        }
+
+        if let Some(dir) = &self.acme_dir {
+            if !dir.is_dir() {
+                return Err(Error::Config(format!(
+                    "ACME directory {} isn't a directory",
+                    dir.display()
+                )));
+            }
+        }
+
+        if self.template_dir.is_some() || self.error_template_dir.is_some() {
+            self.templates = Arc::new(Templates::load(
+                self.template_dir.as_deref(),
+                self.error_template_dir.as_deref(),
+            )?);
+        }
+
+        self.server_header = if self.no_server_id {
+            None
+        } else {
+            let id = match &self.server_id {
+                Some(id) => id.clone(),
+                None => format!("basic-http-server/{}", env!("CARGO_PKG_VERSION")),
+            };
+            let value = HeaderValue::from_str(&id)
+                .map_err(|e| Error::Config(format!("invalid --server-id: {e}")))?;
+            Some(value)
+        };
+
+        let mut immutable = GlobSetBuilder::new();
+        for pattern in &self.immutable {
+            let glob = Glob::new(pattern).map_err(|e| {
+                Error::Config(format!("invalid --immutable pattern {pattern:?}: {e}"))
+            })?;
+            immutable.add(glob);
+        }
+        self.immutable_patterns = immutable
+            .build()
+            .map_err(|e| Error::Config(format!("invalid --immutable patterns: {e}")))?;
+
+        self.build_vhost_configs();
+        Ok(())
+    }
+
+    /// Build the configuration for each `--vhost`, after everything else it
+    /// copies is set.
+    fn build_vhost_configs(&mut self) {
+        self.vhost_configs = self
+            .vhost
+            .iter()
+            .map(|vhost| {
+                Arc::new(Config {
+                    root_dir: vhost.root_dir().to_owned(),
+                    vfs: None,
+                    vhost_configs: Vec::new(),
+                    ..self.clone()
+                })
+            })
+            .collect();
+    }
+
+    /// Check that the root and every `--vhost` root exist, creating them
+    /// with `--create-root`, and make them absolute, so that a mistake in a
+    /// path is an error at startup rather than a 404 for every request.
+    ///
+    /// A root that is empty or can't be read is only warned about.
+    fn check_root(&mut self) -> Result<()> {
+        self.root_dir = check_dir(&self.root_dir, "root directory", self.create_root)?;
+        for vhost in &mut self.vhost {
+            let what = format!("root directory for --vhost {}", vhost.host());
+            let root_dir = check_dir(vhost.root_dir(), &what, self.create_root)?;
+            vhost.set_root_dir(root_dir);
+        }
+        Ok(())
+    }
+
+    /// The tree to serve in place of the root directory, if any.
+    fn open_vfs(&self) -> Result<Option<Arc<dyn Vfs>>> {
+        #[cfg(feature = "embed")]
+        if self.embedded {
+            return Ok(Some(Arc::new(Embedded::new(&self.root_dir))));
+        }
+        let archive = Archive::open(&self.root_dir)?;
+        Ok(archive.map(|archive| Arc::new(archive) as Arc<dyn Vfs>))
+    }
+
+    /// Re-read the command line and configuration file for a reload.
+    ///
+    /// Options that can't change at runtime keep their current values, with a
+    /// warning if the new configuration tried to change them. Everything that
+    /// did change is logged.
+    pub fn reload(&self) -> Result<Config> {
+        let args: Vec<OsString> = std::env::args_os().collect();
+        let matches = Config::command()
+            .try_get_matches_from(&args)
+            .map_err(|e| Error::Config(clap_message(&e)))?;
+        let mut new = Config::from_args_and_file(args, &matches)?;
+
+        let old_table = self.to_table()?;
+        let new_table = new.to_table()?;
+        let keys: BTreeSet<&String> = old_table.keys().chain(new_table.keys()).collect();
+        for key in keys {
+            let old_value = old_table.get(key);
+            let new_value = new_table.get(key);
+            if old_value == new_value {
+                continue;
+            }
+            let show = |v: Option<&toml::Value>| v.map_or("(unset)".to_string(), |v| v.to_string());
+            if FIXED_AT_STARTUP.contains(&key.as_str()) {
+                warn!(
+                    "config: `{}` can't change without a restart, keeping {}",
+                    key,
+                    show(old_value)
+                );
+            } else {
+                info!(
+                    "config: {}: {} -> {}",
+                    key,
+                    show(old_value),
+                    show(new_value)
+                );
+            }
+        }
+
+        new.addr = self.addr.clone();
+        new.bind_first = self.bind_first;
+        new.workers = self.workers;
+        new.uds = self.uds.clone();
+        new.uds_mode = self.uds_mode;
+        new.watch_config = self.watch_config;
+        new.watch_redirects = self.watch_redirects;
+        new.watch_headers = self.watch_headers;
+        new.color = self.color;
+        new.log_file = self.log_file.clone();
+        new.log_to = self.log_to;
+        new.log_rotate_size = self.log_rotate_size;
+        new.log_keep = self.log_keep;
+        new.maintenance = self.maintenance;
+        new.stats_interval = self.stats_interval;
+        new.status_line = self.status_line;
+        new.exit_on_root_removed = self.exit_on_root_removed;
+        new.idle_timeout = self.idle_timeout;
+        new.admin_addr = self.admin_addr;
+        new.admin_allow_remote = self.admin_allow_remote;
+        new.build_vhost_configs();
+
+        Ok(new)
+    }
+
+    /// The configuration as a TOML table, for comparing configurations.
+    fn to_table(&self) -> Result<toml::Table> {
+        match toml::Value::try_from(self) {
+            Ok(toml::Value::Table(table)) => Ok(table),
+            Ok(_) => Ok(toml::Table::new()),
+            Err(e) => Err(Error::Config(e.to_string())),
+        }
+    }
+
+    /// Render the configuration in the configuration file format.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).map_err(|e| Error::Config(e.to_string()))
+    }
+
+    /// The status code of redirects.
+    pub fn redirect_status(&self) -> StatusCode {
+        StatusCode::from_u16(self.redirect_code).unwrap_or(StatusCode::FOUND)
+    }
+
+    /// The `--index` names, or "index.html" if none were given.
+    pub fn index_names(&self) -> Vec<&str> {
+        if self.index.is_empty() {
+            vec!["index.html"]
+        } else {
+            self.index.iter().map(String::as_str).collect()
+        }
+    }
+
+    /// The names of the index file of a directory, in order: the `--index`
+    /// names, then with extensions "index.md" and "README.md", which are
+    /// rendered as pages.
+    pub fn index_candidates(&self) -> Vec<&str> {
+        let mut names = self.index_names();
+        if self.use_extensions {
+            names.extend(["index.md", "README.md"]);
+        }
+        names
+    }
+
+    /// Whether `--allow-ext` and `--deny-ext` let a file be served.
+    pub fn is_servable(&self, path: &Path) -> bool {
+        let ext = path.extension().and_then(OsStr::to_str);
+        let listed = |exts: &[String]| {
+            ext.is_some_and(|ext| {
+                exts.iter()
+                    .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(ext))
+            })
+        };
+
+        if self.allow_ext.is_empty() {
+            !listed(&self.deny_ext)
+        } else if ext.is_none() {
+            self.allow_noext
+        } else {
+            listed(&self.allow_ext)
+        }
+    }
+
+    /// The redirects file, given or default.
+    fn redirects_path(&self) -> PathBuf {
+        match &self.redirects {
+            Some(path) => path.clone(),
+            None => self.root_dir.join("_redirects"),
+        }
+    }
+
+    /// The headers file, given or default.
+    fn headers_path(&self) -> PathBuf {
+        match &self.headers_file {
+            Some(path) => path.clone(),
+            None => self.root_dir.join("_headers"),
+        }
+    }
+
+    /// The files to watch for changes, to reload the configuration.
+    pub fn watched_files(&self) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        if self.watch_config {
+            match &self.config {
+                Some(path) => paths.push(path.clone()),
+                None => warn!("--watch-config has no effect without --config"),
+            }
+        }
+        if self.watch_redirects {
+            paths.push(self.redirects_path());
+        }
+        if self.watch_headers {
+            paths.push(self.headers_path());
+        }
+        paths
+    }
+
+    /// The TCP address to listen on, if any.
+    ///
+    /// TCP is on by default, but asking only for a Unix domain socket turns it
+    /// off.
+    pub fn tcp_addr(&self) -> Option<String> {
+        match (&self.addr, &self.uds) {
+            (Some(addr), _) => Some(addr.clone()),
+            (None, None) => Some("127.0.0.1:4000".to_string()),
+            (None, Some(_)) => None,
+        }
+    }
+
+    /// Resolve the TCP address to the socket addresses to bind.
+    pub async fn resolve_tcp_addr(addr: &str, bind_first: bool) -> Result<Vec<SocketAddr>> {
+        let resolved = tokio::net::lookup_host(addr)
+            .await
+            .map_err(|e| Error::Config(format!("can't resolve address {addr}: {e}")))?;
+        let mut addrs: Vec<SocketAddr> = resolved.collect();
+        addrs.dedup();
+        if addrs.is_empty() {
+            return Err(Error::Config(format!("address {addr} resolved to nothing")));
+        }
+        if bind_first {
+            addrs.truncate(1);
+        }
+        Ok(addrs)
+    }
+}
+
+/// Check one root directory for `Config::check_root`, returning it
+/// canonicalized. `what` names it in messages.
+fn check_dir(dir: &Path, what: &str, create: bool) -> Result<PathBuf> {
+    let shown = dir.display().to_string();
+    if !dir.exists() {
+        if dir.starts_with("~") {
+            return Err(Error::Config(format!(
+                "{what} {shown} doesn't exist; \"~\" is only expanded by \
+                 shells, so use $HOME or an absolute path"
+            )));
+        }
+        if !create {
+            return Err(Error::Config(format!(
+                "{what} {shown} doesn't exist; use --create-root to create it"
+            )));
+        }
+        std::fs::create_dir_all(dir)
+            .map_err(|e| Error::Config(format!("can't create {what} {shown}: {e}")))?;
+        info!("created {} {}", what, shown);
+    }
+
+    let dir = std::fs::canonicalize(dir)
+        .map_err(|e| Error::Config(format!("can't resolve {what} {shown}: {e}")))?;
+
+    if dir.is_dir() {
+        match std::fs::read_dir(&dir) {
+            Ok(mut entries) => {
+                if entries.next().is_none() {
+                    warn!("{} {} is empty", what, dir.display());
+                }
+            }
+            Err(e) => warn!(
+                "{} {} can't be read, so nothing will be served: {}",
+                what,
+                dir.display(),
+                e
+            ),
+        }
+    }
+    Ok(dir)
+}
+
+/// Whether an argument conflicting with `arg`, in either direction, was given
+/// on the command line, so that e.g. `--no-cache` overrides a `cache` key.
+fn conflicts_with_command_line(cmd: &clap::Command, arg: &Arg, matches: &ArgMatches) -> bool {
+    let own = cmd.get_arg_conflicts_with(arg);
+    cmd.get_arguments().any(|other| {
+        let conflicts = own.iter().any(|a| a.get_id() == other.get_id())
+            || cmd
+                .get_arg_conflicts_with(other)
+                .iter()
+                .any(|a| a.get_id() == arg.get_id());
+        conflicts && matches.value_source(other.get_id().as_str()) == Some(ValueSource::CommandLine)
+    })
+}
+
+/// Read a configuration file and translate it into command line arguments,
+/// returned as the options and the positional arguments.
+///
+/// Keys already given on the command line, or overridden by a conflicting
+/// flag given there, are skipped. Unknown keys are only warned about so that
+/// configuration files stay forward compatible.
+fn config_file_args(path: &Path, matches: &ArgMatches) -> Result<(Vec<OsString>, Vec<OsString>)> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("failed to read {}: {e}", path.display())))?;
+    let table: toml::Table = text
+        .parse()
+        .map_err(|e| Error::Config(format!("{}: {e}", path.display())))?;
+
+    let cmd = Config::command();
+    let mut opts = Vec::new();
+    let mut positionals = Vec::new();
+
+    for (key, value) in &table {
+        let Some(arg) = cmd
+            .get_arguments()
+            .find(|a| a.get_id().as_str() == key.as_str())
+        else {
+            warn!("unknown key `{}` in {}", key, path.display());
+            continue;
+        };
+
+        if COMMAND_LINE_ONLY.contains(&key.as_str()) {
+            warn!(
+                "ignoring command-line-only key `{}` in {}",
+                key,
+                path.display()
+            );
+            continue;
+        }
+
+        if matches.value_source(key) == Some(ValueSource::CommandLine)
+            || conflicts_with_command_line(&cmd, arg, matches)
+        {
+            continue;
+        }
+
+        let key_args = value_to_args(arg, value)
+            .map_err(|msg| Error::Config(format!("{}: key `{key}` {msg}", path.display())))?;
+
+        // Validate each key on its own so that errors name the key rather
+        // than the command line flag.
+        let mut check = vec![OsString::from(env!("CARGO_PKG_NAME"))];
+        if arg.is_positional() {
+            check.push(OsString::from("--"));
+        }
+        check.extend(key_args.iter().cloned());
+        if let Err(e) = Config::command().try_get_matches_from(check) {
+            return Err(Error::Config(format!(
+                "{}: invalid value for key `{key}`: {}",
+                path.display(),
+                clap_message(&e)
+            )));
+        }
+
+        if arg.is_positional() {
+            positionals.extend(key_args);
+        } else {
+            opts.extend(key_args);
+        }
+    }
+
+    Ok((opts, positionals))
+}
+
+/// Translate one configuration file value into command line arguments.
+fn value_to_args(arg: &Arg, value: &toml::Value) -> std::result::Result<Vec<OsString>, String> {
+    let flag = match (arg.get_long(), arg.get_short()) {
+        (Some(long), _) => format!("--{long}"),
+        (None, Some(short)) => format!("-{short}"),
+        (None, None) => String::new(),
+    };
+
+    if matches!(arg.get_action(), ArgAction::SetTrue) {
+        return match value {
+            toml::Value::Boolean(true) => Ok(vec![OsString::from(flag)]),
+            toml::Value::Boolean(false) => Ok(Vec::new()),
+            _ => Err("must be a boolean".to_string()),
+        };
+    }
+
+    let values = match value {
+        toml::Value::Array(items) => items
+            .iter()
+            .map(scalar_to_string)
+            .collect::<std::result::Result<Vec<_>, _>>()?,
+        value => vec![scalar_to_string(value)?],
+    };
+
+    let mut args = Vec::new();
+    for value in values {
+        if arg.is_positional() {
+            args.push(OsString::from(value));
+        } else if arg.get_long().is_some() {
+            args.push(OsString::from(format!("{flag}={value}")));
+        } else {
+            args.push(OsString::from(&flag));
+            args.push(OsString::from(value));
+        }
+    }
+
+    Ok(args)
+}
+
+/// Convert a non-container TOML value to its command line spelling.
+fn scalar_to_string(value: &toml::Value) -> std::result::Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        toml::Value::Datetime(d) => Ok(d.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => {
+            Err("must not contain nested arrays or tables".to_string())
+        }
+    }
+}
+
+/// The program name to put in front of a merged command line.
+fn program_name(args: &[OsString]) -> OsString {
+    args.first()
+        .cloned()
+        .unwrap_or_else(|| OsString::from(env!("CARGO_PKG_NAME")))
+}
+
+/// The first line of a clap error, without clap's "error: " prefix or usage
+/// hints.
+fn clap_message(e: &clap::Error) -> String {
+    let rendered = e.to_string();
+    let first = rendered.lines().next().unwrap_or_default();
+    first.strip_prefix("error: ").unwrap_or(first).to_string()
+}
+
+/// Parse a redirect status code.
+fn parse_redirect_code(s: &str) -> std::result::Result<u16, String> {
+    match s {
+        "301" | "302" | "307" | "308" => Ok(s.parse().expect("valid status code")),
+        _ => Err("expected one of 301, 302, 307 or 308".to_string()),
+    }
+}
+
+/// Check that an address looks like HOST:PORT, leaving name resolution for
+/// startup.
+fn parse_host_port(s: &str) -> std::result::Result<String, String> {
+    let Some((host, port)) = s.rsplit_once(':') else {
+        return Err("expected HOST:PORT".to_string());
+    };
+    if host.is_empty() {
+        return Err("missing host".to_string());
+    }
+    port.parse::<u16>()
+        .map_err(|e| format!("invalid port: {e}"))?;
+    Ok(s.to_string())
+}
+
+/// Check that a server id can be sent as a header value.
+fn parse_server_id(s: &str) -> std::result::Result<String, String> {
+    HeaderValue::from_str(s).map_err(|e| format!("{e}"))?;
+    Ok(s.to_string())
+}
+
+/// Parse the request head size limit, which hyper requires to be at least
+/// 8192 bytes.
+fn parse_max_header_size(s: &str) -> std::result::Result<usize, String> {
+    let size: usize = s.parse().map_err(|e| format!("{e}"))?;
+    if size < 8192 {
+        return Err("must be at least 8192".to_string());
+    }
+    Ok(size)
+}
+
+/// Check that a highlighting theme is one of syntect's built-in themes.
+fn parse_highlight_theme(s: &str) -> std::result::Result<String, String> {
+    let themes = &THEME_SET.themes;
+    if themes.contains_key(s) {
+        Ok(s.to_string())
+    } else {
+        let names: Vec<&str> = themes.keys().map(String::as_str).collect();
+        Err(format!(
+            "unknown theme, expected one of: {}",
+            names.join(", ")
+        ))
+    }
+}
+
+/// Check that the assets prefix is an absolute URL path other than "/",
+/// which would hide every file.
+fn parse_assets_prefix(s: &str) -> std::result::Result<String, String> {
+    if !s.starts_with('/') {
+        return Err("must start with /".to_string());
+    }
+    if s.trim_end_matches('/').is_empty() {
+        return Err("must not be /".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Check an `--index` name is a plain file name.
+fn parse_index_name(s: &str) -> std::result::Result<String, String> {
+    if s.is_empty() || s == "." || s == ".." || s.contains(['/', '\\']) {
+        return Err("must be a file name".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Parse file permissions like "660" or "0660".
+fn parse_octal_mode(s: &str) -> std::result::Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|e| format!("invalid octal mode: {e}"))
+}
+
+/// Serialize file permissions in octal, the way `parse_octal_mode` reads them.
+fn serialize_octal_mode<S: Serializer>(
+    mode: &Option<u32>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match mode {
+        Some(mode) => s.serialize_str(&format!("{mode:o}")),
+        None => s.serialize_none(),
+    }
+}
+
+/// Reload the configuration, keeping the old one if the new one is invalid.
+/// Returns whether it was reloaded.
+pub fn reload_shared(config: &ArcSwap<Config>) -> bool {
+    match config.load().reload() {
+        Ok(new) => {
+            crate::templates::install(new.templates.clone());
+            config.store(Arc::new(new));
+            info!("configuration reloaded");
+            true
+        }
+        Err(e) => {
+            error!("failed to reload configuration, keeping the previous one");
+            crate::log_error_chain(&e);
+            false
+        }
+    }
+}
+
+/// Reload the configuration on every SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_sighup(config: SharedConfig) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        info!("received SIGHUP, reloading configuration");
+        reload_shared(&config);
+    }
+    Ok(())
+}
+
+/// Reload the configuration whenever the modification time of one of the
+/// watched files changes, checking once a second.
+pub async fn reload_on_change(config: SharedConfig, paths: Vec<PathBuf>) -> Result<()> {
+    let modified = |path: &Path| -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    };
+
+    let mut last: Vec<_> = paths.iter().map(|p| modified(p.as_path())).collect();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        for (path, last) in paths.iter().zip(&mut last) {
+            let current = modified(path);
+            if current != *last {
+                *last = current;
+                info!("{} changed, reloading configuration", path.display());
+                reload_shared(&config);
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Colors on the console, as chosen with `--color`.
+//!
+//! The logger always writes color codes, and `logfile::LogWriter` strips
+//! them from stderr when colors are off, as it does for the log file. Lines
+//! built here only add codes of their own when colors are on.
+
+use crate::config::ColorChoice;
+use hyper::{Method, StatusCode};
+use std::fmt::Display;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The width of the client column in the access log, enough for most IPv4
+/// addresses with a port.
+const PEER_WIDTH: usize = 21;
+
+static ON: AtomicBool = AtomicBool::new(false);
+
+/// Turn colors on or off.
+///
+/// `Auto` means colors when stderr is a terminal. Windows consoles only
+/// understand color codes once asked to, so there it means no colors.
+pub fn set(choice: ColorChoice) {
+    let on = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => !cfg!(windows) && io::stderr().is_terminal(),
+    };
+    ON.store(on, Ordering::Relaxed);
+}
+
+/// Whether colors are on.
+pub fn is_on() -> bool {
+    ON.load(Ordering::Relaxed)
+}
+
+/// A line of the access log, with the client, status and request in aligned
+/// columns, and the status colored by its class.
+pub fn access_line(
+    peer: &dyn Display,
+    method: &Method,
+    uri: &str,
+    status: StatusCode,
+    reused: bool,
+) -> String {
+    let code = match status.as_u16() {
+        200..=299 => "32",
+        300..=399 => "36",
+        400..=499 => "33",
+        500..=599 => "31",
+        _ => "0",
+    };
+    format!(
+        "{:<width$} {} \"{} {}\"{}",
+        peer.to_string(),
+        paint(code, status.as_str()),
+        method,
+        uri,
+        if reused { " (reused)" } else { "" },
+        width = PEER_WIDTH,
+    )
+}
+
+/// Make text stand out, as for the address in the startup banner.
+pub fn highlight(text: &str) -> String {
+    paint("1;4", text)
+}
+
+/// Wrap text in an SGR color code, if colors are on.
+fn paint(code: &str, text: &str) -> String {
+    if is_on() {
+        format!("\x1b[{code}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
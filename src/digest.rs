@@ -0,0 +1,210 @@
+//! `--digests`: SHA-256 digests of files, for checking copies without
+//! downloading them again.
+//!
+//! `?hash=sha256` is answered with the digest as JSON in place of the file.
+//! A `Want-Repr-Digest` header (RFC 9530), or the older `Want-Digest`,
+//! asking for sha-256 gets a `Repr-Digest` header on the file's response.
+//!
+//! Files are hashed by streaming them, off the async threads, and digests
+//! are cached by path, size and modification time. Files over
+//! `--max-digest-size` aren't hashed, and only a few are hashed at once, so
+//! clients can't keep the CPU busy hashing.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::vfs;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Request, Response, StatusCode};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::SystemTime;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+/// The most files hashed at once.
+static HASHING: Semaphore = Semaphore::const_new(2);
+
+/// Digests by path, with the size and modification time they're for.
+static CACHE: LazyLock<Mutex<HashMap<PathBuf, Cached>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The most digests cached. When there are more the cache starts over.
+const MAX_CACHED: usize = 4096;
+
+struct Cached {
+    len: u64,
+    modified: SystemTime,
+    digest: [u8; 32],
+}
+
+/// The algorithm asked for with `?hash=`, if the query has one.
+pub fn hash_query(req: &Request<Incoming>) -> Option<&str> {
+    req.uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("hash="))
+}
+
+/// Answer `?hash=` with a file's digest, as JSON.
+pub async fn query_response(
+    req: &Request<Incoming>,
+    config: &Config,
+    path: &Path,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    let algorithm = hash_query(req).unwrap_or_default();
+    if !is_sha256(algorithm) {
+        debug!("unsupported digest algorithm: {}", algorithm);
+        return super::make_error_response_from_code(StatusCode::BAD_REQUEST, None);
+    }
+
+    let (len, digest) = file_digest(config, path).await?;
+    let body = json!({
+        "path": req.uri().path(),
+        "algorithm": "sha-256",
+        "digest": digest.iter().map(|b| format!("{b:02x}")).collect::<String>(),
+        "size": len,
+    });
+    let body = format!("{body}\n");
+    let resp = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(
+            Full::new(Bytes::from(body))
+                .map_err(|never| match never {})
+                .boxed(),
+        )?;
+    Ok(resp)
+}
+
+/// Whether the request asks for a `Repr-Digest` with sha-256.
+///
+/// `Want-Repr-Digest` gives each algorithm a preference from 0 to 10, and
+/// `Want-Digest` a q-value. Either way, 0 means not at all.
+pub fn wants_repr_digest(headers: &HeaderMap) -> bool {
+    let names = [
+        HeaderName::from_static("want-repr-digest"),
+        HeaderName::from_static("want-digest"),
+    ];
+    names
+        .iter()
+        .flat_map(|name| headers.get_all(name))
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let (name, weight) = item.split_once(['=', ';']).unwrap_or((item, ""));
+            let weight = weight.trim().trim_start_matches("q=");
+            is_sha256(name.trim()) && !weight.parse::<f32>().is_ok_and(|w| w == 0.0)
+        })
+}
+
+/// Add a `Repr-Digest` header to the response for a file.
+///
+/// If the file can't be hashed, the response goes without.
+pub async fn add_repr_digest(
+    config: &Config,
+    path: &Path,
+    resp: &mut Response<BoxBody<Bytes, Error>>,
+) {
+    if !matches!(resp.status(), StatusCode::OK | StatusCode::PARTIAL_CONTENT) {
+        return;
+    }
+    match file_digest(config, path).await {
+        Ok((_, digest)) => {
+            let value = format!("sha-256=:{}:", BASE64.encode(digest));
+            resp.headers_mut().insert(
+                HeaderName::from_static("repr-digest"),
+                HeaderValue::from_str(&value).expect("base64 is valid header"),
+            );
+        }
+        Err(e) => debug!("no digest for {}: {}", path.display(), e),
+    }
+}
+
+fn is_sha256(name: &str) -> bool {
+    name.eq_ignore_ascii_case("sha-256") || name.eq_ignore_ascii_case("sha256")
+}
+
+/// The size and SHA-256 digest of a file, from the cache if the file hasn't
+/// changed.
+///
+/// Files over `--max-digest-size` are `Error::FileTooLarge`.
+pub async fn file_digest(config: &Config, path: &Path) -> Result<(u64, [u8; 32])> {
+    let (len, modified) = match &config.vfs {
+        Some(vfs) => vfs.file_meta(path).ok_or_else(vfs::not_found)?,
+        None => {
+            let meta = tokio::fs::metadata(path).await?;
+            if !meta.is_file() {
+                return Err(vfs::not_found());
+            }
+            (meta.len(), meta.modified().ok())
+        }
+    };
+    if len > config.max_digest_size {
+        return Err(Error::FileTooLarge);
+    }
+
+    if let Some(modified) = modified {
+        let cache = CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(cached) = cache.get(path) {
+            if cached.len == len && cached.modified == modified {
+                return Ok((len, cached.digest));
+            }
+        }
+    }
+
+    let _permit = HASHING.acquire().await.map_err(io::Error::other)?;
+    debug!("hashing {}", path.display());
+    let digest = match &config.vfs {
+        Some(vfs) => {
+            let data = vfs.read_all(path).await?;
+            tokio::task::spawn_blocking(move || <[u8; 32]>::from(Sha256::digest(&data)))
+                .await
+                .map_err(io::Error::other)?
+        }
+        None => {
+            let path = path.to_owned();
+            tokio::task::spawn_blocking(move || hash_file(&path))
+                .await
+                .map_err(io::Error::other)??
+        }
+    };
+
+    if let Some(modified) = modified {
+        let mut cache = CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+        if cache.len() >= MAX_CACHED {
+            cache.clear();
+        }
+        let cached = Cached {
+            len,
+            modified,
+            digest,
+        };
+        cache.insert(path.to_owned(), cached);
+    }
+    Ok((len, digest))
+}
+
+/// Hash a file on disk, a buffer at a time.
+fn hash_file(path: &Path) -> io::Result<[u8; 32]> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().into())
+}
@@ -0,0 +1,98 @@
+//! The tree built into the binary, served with `--embedded`.
+//!
+//! Only built with `--features embed`, which embeds the directory named by
+//! `EMBED_DIR` when building, relative to the crate directory.
+
+use crate::error::{Error, Result};
+use crate::vfs::{self, tree_key, Vfs};
+use futures::future::BoxFuture;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use include_dir::{include_dir, Dir, DirEntry, File};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+static TREE: Dir<'static> = include_dir!("$EMBED_DIR");
+
+/// The embedded tree, standing in for the root directory.
+pub struct Embedded {
+    /// The root directory that URLs map to paths under.
+    root: PathBuf,
+}
+
+impl Embedded {
+    pub fn new(root: &Path) -> Embedded {
+        Embedded {
+            root: root.to_owned(),
+        }
+    }
+
+    fn dir(&self, path: &Path) -> Option<&'static Dir<'static>> {
+        let (key, _) = tree_key(&self.root, path)?;
+        if key.is_empty() {
+            Some(&TREE)
+        } else {
+            TREE.get_dir(key)
+        }
+    }
+
+    fn file(&self, path: &Path) -> Option<&'static File<'static>> {
+        let (key, dir_only) = tree_key(&self.root, path)?;
+        if dir_only {
+            return None;
+        }
+        TREE.get_file(key)
+    }
+}
+
+impl Vfs for Embedded {
+    fn describe(&self) -> String {
+        format!("embedded tree ({} files)", count_files(&TREE))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.dir(path).is_some()
+    }
+
+    fn file_meta(&self, path: &Path) -> Option<(u64, Option<SystemTime>)> {
+        let file = self.file(path)?;
+        let modified = file.metadata().map(|m| m.modified());
+        Some((file.contents().len() as u64, modified))
+    }
+
+    fn list(&self, path: &Path) -> Option<Vec<(PathBuf, Option<u64>)>> {
+        let entries = self.dir(path)?.entries().iter().map(|entry| match entry {
+            DirEntry::Dir(dir) => (self.root.join(dir.path()), None),
+            DirEntry::File(file) => (
+                self.root.join(file.path()),
+                Some(file.contents().len() as u64),
+            ),
+        });
+        Some(entries.collect())
+    }
+
+    fn read<'a>(
+        &'a self,
+        path: &'a Path,
+        _buffer_size: usize,
+    ) -> BoxFuture<'a, Result<(u64, BoxBody<Bytes, Error>)>> {
+        Box::pin(async move {
+            let contents = self.file(path).ok_or_else(vfs::not_found)?.contents();
+            let body = Full::new(Bytes::from_static(contents))
+                .map_err(|never| match never {})
+                .boxed();
+            Ok((contents.len() as u64, body))
+        })
+    }
+}
+
+fn count_files(dir: &Dir) -> usize {
+    dir.entries()
+        .iter()
+        .map(|entry| match entry {
+            DirEntry::Dir(dir) => count_files(dir),
+            DirEntry::File(_) => 1,
+        })
+        .sum()
+}
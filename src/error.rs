@@ -33,30 +33,75 @@ pub enum Error {
     #[display("Hyper error")]
     Hyper(hyper::Error),
 
+    #[display("invalid URI")]
+    InvalidUri(http::uri::InvalidUri),
+
+    #[display("invalid URI parts")]
+    InvalidUriParts(http::uri::InvalidUriParts),
+
     #[display("I/O error")]
     Io(std::io::Error),
 
+    #[display("JSON error")]
+    Json(serde_json::Error),
+
     // custom "semantic" error types
     #[display("failed to parse IP address")]
     AddrParse(std::net::AddrParseError),
 
+    #[display("configuration error: {_0}")]
+    Config(String),
+
+    #[display("file is larger than the --max-file-size limit")]
+    FileTooLarge,
+
     #[display("markdown is not UTF-8")]
     MarkdownUtf8,
 
     #[display("failed to strip prefix in directory listing")]
     StripPrefixInDirList(std::path::StripPrefixError),
 
-    #[display("failed to render template")]
+    #[display(
+        "failed to render template {}",
+        _0.template_name.as_deref().unwrap_or(crate::templates::PAGE)
+    )]
     TemplateRender(handlebars::RenderError),
 
+    #[display("a file that is not a socket exists at the Unix socket path")]
+    UdsPathNotSocket,
+
+    #[cfg(not(unix))]
+    #[display("Unix domain sockets are not supported on this platform")]
+    UdsUnsupported,
+
+    #[display("requested URI contains an encoded control character")]
+    UriControlChar,
+
+    #[display("requested URI contains an encoded / in a path segment")]
+    UriEncodedSlash,
+
+    #[display("requested URI climbs above the root directory")]
+    UriEscapesRoot,
+
+    #[display("requested URI names a different host than the Host header")]
+    UriHostMismatch,
+
     #[display("requested URI is not an absolute path")]
     UriNotAbsolute,
 
+    #[cfg(not(unix))]
     #[display("requested URI is not UTF-8")]
     UriNotUtf8,
 
+    #[cfg(windows)]
+    #[display("requested URI names a path Windows treats specially")]
+    UriUnsafeName,
+
     #[display("formatting error while creating directory listing")]
     WriteInDirList(std::fmt::Error),
+
+    #[display("failed to read zip archive")]
+    Zip(zip::result::ZipError),
 }
 
 impl std::error::Error for Error {}
@@ -0,0 +1,302 @@
+//! Error responses for the client: JSON for API clients, and otherwise
+//! localized error pages from `--error-lang-dir`, chosen by the request's
+//! `Accept-Language`, or pages from the templates in `--error-template-dir`.
+//!
+//! The directory holds HTML pages named like `404.de.html`, for a status
+//! code and a language tag, served as they are. Each page is read once and
+//! kept, as is the fact that there isn't one, so the disk isn't read on every
+//! error.
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::reason::Reason;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderValue};
+use hyper::{Method, Response, StatusCode};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, PoisonError};
+
+/// The most pages, or absences of pages, to remember. Clients choose the
+/// language tags, so this is limited.
+const MAX_CACHED_PAGES: usize = 1024;
+
+/// The longest language tag looked for.
+const MAX_LANG_TAG_LEN: usize = 35;
+
+type PageKey = (PathBuf, StatusCode, String);
+
+static PAGES: LazyLock<Mutex<HashMap<PageKey, Option<Bytes>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Make a generated HTML error response into what the client wants: JSON
+/// with `--json-errors` or when the request's `Accept` prefers it, or else
+/// the page in the client's language, or else the page from the error
+/// template for the status.
+///
+/// Unless `--json-errors` is set, error responses vary with `Accept`.
+pub fn negotiate(
+    config: &Config,
+    method: &Method,
+    accept: Option<&HeaderValue>,
+    accept_language: Option<&HeaderValue>,
+    path: Option<&str>,
+    resp: &mut Response<BoxBody<Bytes, Error>>,
+) {
+    if !is_error_page(resp) {
+        return;
+    }
+    if !config.json_errors {
+        resp.headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept"));
+    }
+
+    if config.json_errors || prefers_json(accept) {
+        to_json(path, resp);
+        return;
+    }
+    if config.error_template_dir.is_some() {
+        from_template(config, method, path, resp);
+    }
+    if let Some(dir) = &config.error_lang_dir {
+        localize(dir, accept_language, resp);
+    }
+}
+
+/// Replace the body of an error response with the page from the error
+/// template for its status, if there is one.
+///
+/// A template that fails to render is logged, and the built-in page kept.
+fn from_template(
+    config: &Config,
+    method: &Method,
+    path: Option<&str>,
+    resp: &mut Response<BoxBody<Bytes, Error>>,
+) {
+    let status = resp.status();
+    let data = json!({
+        "status": status.as_u16(),
+        "reason": status.canonical_reason().unwrap_or("Unknown"),
+        "message": message(resp),
+        "path": path.unwrap_or_default(),
+        "method": method.as_str(),
+    });
+    let page = match config.templates.render_error(status, &data) {
+        None => return,
+        Some(Ok(page)) => page,
+        Some(Err(e)) => {
+            crate::log_error_chain_once(&e);
+            return;
+        }
+    };
+
+    let headers = resp.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(mime::TEXT_HTML.as_ref()).expect("valid mime type"),
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(page.len()));
+    *resp.body_mut() = Full::new(Bytes::from(page))
+        .map_err(|never| match never {})
+        .boxed();
+}
+
+/// Whether a response is an error with an HTML page, rather than, say, a
+/// health check's JSON.
+fn is_error_page(resp: &Response<BoxBody<Bytes, Error>>) -> bool {
+    let status = resp.status();
+    (status.is_client_error() || status.is_server_error())
+        && resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|v| v.as_bytes().starts_with(b"text/html"))
+}
+
+/// The explanation of why a request was refused, if the response has one.
+fn message(resp: &Response<BoxBody<Bytes, Error>>) -> Option<String> {
+    resp.extensions().get::<Reason>().map(Reason::message)
+}
+
+/// Replace the body of an error response with JSON giving the status, its
+/// reason, why the request was refused if that's known, and the requested
+/// path if it's shown.
+fn to_json(path: Option<&str>, resp: &mut Response<BoxBody<Bytes, Error>>) {
+    let status = resp.status();
+    let mut body = json!({
+        "status": status.as_u16(),
+        "error": status.canonical_reason().unwrap_or("Unknown"),
+    });
+    if let Some(message) = message(resp) {
+        body["message"] = json!(message);
+    }
+    if let Some(path) = path {
+        body["path"] = json!(path);
+    }
+    let body = format!("{body}\n");
+
+    let headers = resp.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(mime::APPLICATION_JSON.as_ref()).expect("valid mime type"),
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    *resp.body_mut() = Full::new(Bytes::from(body))
+        .map_err(|never| match never {})
+        .boxed();
+}
+
+/// Whether an `Accept` header ranks JSON above HTML. Each is ranked by its
+/// most specific matching media range, so `*/*` alone prefers neither.
+fn prefers_json(accept: Option<&HeaderValue>) -> bool {
+    let Some(accept) = accept.and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    accept_q(accept, "application", "json") > accept_q(accept, "text", "html")
+}
+
+/// The q-value an `Accept` header gives a media type, 0 if none matches.
+pub fn accept_q(accept: &str, ty: &str, subtype: &str) -> f32 {
+    let mut best: Option<(u8, f32)> = None;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let Some((t, s)) = params.next().unwrap_or_default().trim().split_once('/') else {
+            continue;
+        };
+        let specificity = match (t, s) {
+            ("*", "*") => 0,
+            (t, "*") if t.eq_ignore_ascii_case(ty) => 1,
+            (t, s) if t.eq_ignore_ascii_case(ty) && s.eq_ignore_ascii_case(subtype) => 2,
+            _ => continue,
+        };
+        let mut q = 1.0;
+        for param in params {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    q = value.trim().parse::<f32>().unwrap_or(0.0);
+                }
+            }
+        }
+        let better = match best {
+            Some((best_specificity, _)) => specificity > best_specificity,
+            None => true,
+        };
+        if better {
+            best = Some((specificity, q));
+        }
+    }
+    best.map_or(0.0, |(_, q)| q)
+}
+
+/// Replace the body of an error response with the page for the client's
+/// language, if there is one, or else leave the built-in English page.
+///
+/// Either way the response varies with `Accept-Language`.
+fn localize(
+    dir: &Path,
+    accept_language: Option<&HeaderValue>,
+    resp: &mut Response<BoxBody<Bytes, Error>>,
+) {
+    let status = resp.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return;
+    }
+    resp.headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-language"));
+
+    // Anything unparsable is no preference at all.
+    let Some(accept_language) = accept_language.and_then(|v| v.to_str().ok()) else {
+        return;
+    };
+    let found = lookup_order(accept_language)
+        .into_iter()
+        .find_map(|lang| Some((page(dir, status, &lang)?, lang)));
+    let Some((page, lang)) = found else {
+        return;
+    };
+
+    let headers = resp.headers_mut();
+    headers.insert(
+        header::CONTENT_LANGUAGE,
+        HeaderValue::from_str(&lang).expect("valid language tag"),
+    );
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(mime::TEXT_HTML.as_ref()).expect("valid mime type"),
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(page.len()));
+    *resp.body_mut() = Full::new(page).map_err(|never| match never {}).boxed();
+}
+
+/// The page for a status and language, from the cache or the disk.
+fn page(dir: &Path, status: StatusCode, lang: &str) -> Option<Bytes> {
+    let key = (dir.to_owned(), status, lang.to_string());
+    let mut pages = PAGES.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(page) = pages.get(&key) {
+        return page.clone();
+    }
+
+    let path = dir.join(format!("{}.{}.html", status.as_u16(), lang));
+    let page = std::fs::read(path).ok().map(Bytes::from);
+    if pages.len() < MAX_CACHED_PAGES {
+        pages.insert(key, page.clone());
+    }
+    page
+}
+
+/// The language tags to look for pages in, in order: each accepted tag by
+/// descending q-value, followed by its shorter prefixes, as in "de-at" then
+/// "de".
+///
+/// Malformed entries are skipped, and `*` means the built-in page.
+/// https://tools.ietf.org/html/rfc4647#section-3.4
+fn lookup_order(accept_language: &str) -> Vec<String> {
+    let mut accepted: Vec<(f32, String)> = Vec::new();
+    for item in accept_language.split(',') {
+        let mut params = item.split(';');
+        let tag = params.next().unwrap_or_default().trim();
+        let mut q = Some(1.0);
+        for param in params {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    q = value.trim().parse::<f32>().ok();
+                }
+            }
+        }
+        match q {
+            Some(q) if q > 0.0 && q <= 1.0 && is_lang_tag(tag) => {
+                accepted.push((q, tag.to_ascii_lowercase()));
+            }
+            _ => {}
+        }
+    }
+    // A stable sort, keeping the client's order for equal q-values.
+    accepted.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut order: Vec<String> = Vec::new();
+    for (_, tag) in accepted {
+        let mut tag = tag.as_str();
+        loop {
+            if !order.iter().any(|t| t == tag) {
+                order.push(tag.to_string());
+            }
+            match tag.rsplit_once('-') {
+                Some((prefix, _)) => tag = prefix,
+                None => break,
+            }
+        }
+    }
+    order
+}
+
+/// Whether a string looks like a language tag, which also makes it safe to
+/// put in a file name.
+fn is_lang_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= MAX_LANG_TAG_LEN
+        && !tag.starts_with('-')
+        && !tag.ends_with('-')
+        && tag.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
@@ -3,60 +3,172 @@
 //! This code is not as clean and well-documented as main.rs,
 //! but could still be a useful read.
 
-use super::{Config, HtmlCfg};
+use super::{Config, HtmlCfg, PageKind, RequestContext};
+use crate::conditional::{self, Validators};
+use crate::config::{MdMath, MdOption};
 use comrak::Options;
 
 use crate::error::{Error, Result};
+use crate::errorpages;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use http::StatusCode;
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Full};
+use http_body_util::{BodyExt, Empty, Full};
 use hyper::body::{Bytes, Incoming};
-use hyper::header;
-use hyper::{Request, Response};
-use log::{trace, warn};
-use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use hyper::header::{self, HeaderValue};
+use hyper::{Method, Request, Response};
+use percent_encoding::{
+    percent_decode_str, percent_encode, utf8_percent_encode, AsciiSet, CONTROLS,
+};
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
-use std::fmt::Write;
+use std::fmt::{self, Write};
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::ThemeSet;
+use syntect::html::{styled_line_to_highlighted_html, IncludeBackground};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+use tracing::{debug, info, trace, warn};
 
 /// The entry point to extensions. Extensions are given both the request and the
 /// response result from regular file serving, and have the opportunity to
 /// replace the response with their own response.
-pub async fn serve(
-    config: Config,
+///
+/// An extension that fails leaves the regular response as it was, and one
+/// that doesn't apply to the request's method isn't tried.
+pub(crate) async fn serve(
+    config: Arc<Config>,
+    method: &Method,
     req: Request<Incoming>,
+    ctx: &RequestContext,
     resp: Result<Response<BoxBody<Bytes, Error>>>,
 ) -> Result<Response<BoxBody<Bytes, Error>>> {
     trace!("checking extensions");
 
     if !config.use_extensions {
+        if config.list_dirs && Extension::Listing.applies_to(method) {
+            return maybe_negotiate_dir_list(&req, &config, ctx, resp).await;
+        }
         return resp;
     }
+    let applies = |extension: Extension| extension.applies_to(method);
 
-    let path = super::local_path_for_request(req.uri(), &config.root_dir)?;
+    // The file served, which for a directory is its index file, like a
+    // README.md to render.
+    let path = &ctx.file;
     let file_ext = path.extension().and_then(OsStr::to_str).unwrap_or("");
 
-    if file_ext == "md" {
-        trace!("using markdown extension");
-        return md_path_to_html(&path).await;
+    // Render markdown for browsers, but give everything else the markdown
+    // itself. Only a file that was found is rendered: redirects, like the
+    // one adding a directory's trailing slash, and errors pass through.
+    if file_ext == "md" && applies(Extension::Markdown) && config.is_servable(path) {
+        let ok = resp.as_ref().is_ok_and(|r| r.status() == StatusCode::OK);
+        if !ok {
+            return resp;
+        }
+        let mut resp = if wants_html_view(&req, &TEXT_MARKDOWN) {
+            trace!("using markdown extension");
+            md_path_to_html(&config, &req, ctx).await
+        } else {
+            resp.map(|mut resp| {
+                resp.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("text/markdown; charset=utf-8"),
+                );
+                resp
+            })
+        };
+        if let Ok(resp) = &mut resp {
+            resp.headers_mut()
+                .insert(header::VARY, HeaderValue::from_static("Accept"));
+        }
+        return resp;
     }
 
     match resp {
         Ok(mut resp) => {
+            let ok = resp.status() == StatusCode::OK;
+
+            // Show JSON to people in browsers pretty-printed, unless they ask
+            // for the raw file.
+            if ok && file_ext == "json" && applies(Extension::JsonView) {
+                if wants_html_view(&req, &mime::APPLICATION_JSON) {
+                    match maybe_json_view(&config, ctx).await {
+                        Ok(Some(view)) => {
+                            trace!("using JSON view extension");
+                            return Ok(view);
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!("no JSON view for {}: {}", path.display(), e);
+                        }
+                    }
+                }
+                resp.headers_mut()
+                    .insert(header::VARY, HeaderValue::from_static("Accept"));
+                return Ok(resp);
+            }
+
+            // Show source code to people in browsers as a page with line
+            // numbers, unless they ask for the raw file.
+            if ok
+                && applies(Extension::SourceView)
+                && config.text_types.matches(&req)
+                && wants_html_view(&req, &mime::TEXT_PLAIN)
+            {
+                match maybe_source_view(&config, ctx).await {
+                    Ok(Some(view)) => {
+                        trace!("using source view extension");
+                        return Ok(view);
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!("no source view for {}: {}", path.display(), e);
+                    }
+                }
+            }
+
             // Serve source code as plain text to render them in the browser
-            maybe_convert_mime_type_to_text(&req, &mut resp);
+            if applies(Extension::TextTypes) {
+                maybe_convert_mime_type_to_text(&config.text_types, &req, &mut resp);
+            }
             Ok(resp)
         }
         Err(Error::Io(e)) => {
             // If the requested file was not found, then try doing a directory listing.
-            if e.kind() == io::ErrorKind::NotFound {
-                let list_dir_resp = maybe_list_dir(&config.root_dir, &path).await?;
+            // If it's not a directory either, suggest similarly-named files.
+            if e.kind() == io::ErrorKind::NotFound && applies(Extension::Listing) {
+                let list_dir_resp = maybe_list_dir(&req, &config, ctx).await;
                 trace!("using directory list extension");
-                if let Some(f) = list_dir_resp {
-                    Ok(f)
-                } else {
-                    Err(Error::from(e))
+                match list_dir_resp {
+                    Ok(Some(f)) => Ok(f),
+                    Ok(None) => Err(Error::from(e)),
+                    Err(Error::Io(ref e2))
+                        if matches!(
+                            e2.kind(),
+                            io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+                        ) =>
+                    {
+                        match maybe_suggest_similar(&req, &config, &ctx.path).await {
+                            Ok(Some(f)) => Ok(f),
+                            Ok(None) => Err(Error::from(e)),
+                            Err(e2) => {
+                                warn!("no suggestions for {}: {}", ctx.path.display(), e2);
+                                Err(Error::from(e))
+                            }
+                        }
+                    }
+                    Err(e2) => {
+                        warn!("no directory list for {}: {}", ctx.path.display(), e2);
+                        Err(Error::from(e))
+                    }
                 }
             } else {
                 Err(Error::from(e))
@@ -66,30 +178,112 @@ pub async fn serve(
     }
 }
 
+/// The extensions, for deciding which apply to a request.
+#[derive(Clone, Copy, Debug)]
+enum Extension {
+    /// Rendering markdown files as pages.
+    Markdown,
+    /// Showing JSON files pretty-printed.
+    JsonView,
+    /// Showing source files with line numbers.
+    SourceView,
+    /// Serving source files as plain text.
+    TextTypes,
+    /// Listing directories, and suggesting similar names for missing files.
+    Listing,
+}
+
+impl Extension {
+    /// Whether the extension applies to requests with this method.
+    ///
+    /// Pages apply to GET and HEAD, which gets the same headers, since hyper
+    /// leaves the body off. Changing the type of a file applies to both for
+    /// the same reason. Nothing applies to methods that would change files.
+    fn applies_to(self, method: &Method) -> bool {
+        let get_or_head = method == Method::GET || method == Method::HEAD;
+        match self {
+            Extension::Markdown
+            | Extension::JsonView
+            | Extension::SourceView
+            | Extension::Listing
+            | Extension::TextTypes => get_or_head,
+        }
+    }
+}
+
 /// Load a markdown file, render to HTML, and return the response.
-async fn md_path_to_html(path: &Path) -> Result<Response<BoxBody<Bytes, Error>>> {
-    // Render Markdown like GitHub
-    let buf = tokio::fs::read(path).await?;
+///
+/// The response carries the source file's `Last-Modified` and a weak `ETag`
+/// covering both the file and the rendering options, and a conditional
+/// request that matches them gets a 304 without reading or rendering the file.
+///
+/// Files larger than `--max-md-size` aren't rendered at all, but streamed as
+/// plain text.
+async fn md_path_to_html(
+    config: &Config,
+    req: &Request<Incoming>,
+    ctx: &RequestContext,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    let path = &ctx.file;
+    let options = md_options(config);
+
+    let (len, modified) = file_meta(config, path).await?;
+
+    if len > config.max_md_size {
+        info!(
+            "not rendering {} as markdown: {} bytes is over the {} byte limit",
+            path.display(),
+            len,
+            config.max_md_size
+        );
+        let mut resp =
+            super::respond_with_file(path.to_owned(), config, req.method(), req.headers()).await?;
+        let val = HeaderValue::from_str(mime::TEXT_PLAIN.as_ref()).expect("mime is valid header");
+        resp.headers_mut().insert(header::CONTENT_TYPE, val);
+        return Ok(resp);
+    }
+    let etag = md_etag(len, modified, &options, config);
+
+    if !config.no_cache {
+        let validators = Validators {
+            etag: Some(&etag),
+            modified,
+        };
+        let outcome = conditional::evaluate(req.method(), req.headers(), &validators);
+        if let Some(resp) = conditional::response(&outcome, &validators) {
+            trace!("markdown precondition: {:?}", outcome);
+            return resp;
+        }
+    }
+
+    let buf = read_file(config, path).await?;
     let s = String::from_utf8(buf).map_err(|_| Error::MarkdownUtf8)?;
-    let mut options = Options::default();
-    options.extension.autolink = true;
-    options.extension.header_ids = Some("user-content-".to_string());
-    options.extension.table = true;
-    options.extension.strikethrough = true;
-    options.extension.tagfilter = true;
-    options.extension.tasklist = true;
-    options.render.github_pre_lang = true;
-    let html = comrak::markdown_to_html(&s, &options);
+    let mut html = comrak::markdown_to_html(&s, &options);
+    let mut head = md_math_head(config, &html);
+    if config.md_mermaid && convert_mermaid_blocks(&mut html) {
+        head.push_str(&md_mermaid_head(config));
+    }
     let cfg = HtmlCfg {
-        title: String::new(),
+        kind: PageKind::Markdown,
+        stylesheet: crate::assets::stylesheet_url(config),
+        head,
+        crumbs: breadcrumbs(&ctx.url_path, Links::Absolute),
+        url_path: ctx.url_path.clone(),
         body: html,
+        ..HtmlCfg::default()
     };
     let html = super::render_html(&cfg)?;
 
-    Response::builder()
+    let mut builder = Response::builder()
         .status(StatusCode::OK)
         .header(header::CONTENT_LENGTH, html.len() as u64)
         .header(header::CONTENT_TYPE, mime::TEXT_HTML.as_ref())
+        .header(header::ETAG, etag);
+    if let Some(modified) = modified {
+        builder = builder.header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+
+    builder
         .body(
             Full::new(html.into())
                 .map_err(|never| match never {})
@@ -98,32 +292,642 @@ async fn md_path_to_html(path: &Path) -> Result<Response<BoxBody<Bytes, Error>>>
         .map_err(Error::from)
 }
 
+/// The size and modification time of a file, from the tree served in place
+/// of the root directory if there is one.
+async fn file_meta(config: &Config, path: &Path) -> Result<(u64, Option<SystemTime>)> {
+    match &config.vfs {
+        Some(vfs) => vfs.file_meta(path).ok_or_else(crate::vfs::not_found),
+        None => {
+            let meta = tokio::fs::metadata(path).await?;
+            Ok((meta.len(), meta.modified().ok()))
+        }
+    }
+}
+
+/// Read a whole file, from the tree served in place of the root directory if
+/// there is one.
+///
+/// Anything but a regular file is not found, so a FIFO is never opened.
+async fn read_file(config: &Config, path: &Path) -> Result<Vec<u8>> {
+    match &config.vfs {
+        Some(vfs) => Ok(vfs.read_all(path).await?.to_vec()),
+        None => {
+            if !tokio::fs::metadata(path).await?.is_file() {
+                return Err(crate::vfs::not_found());
+            }
+            Ok(tokio::fs::read(path).await?)
+        }
+    }
+}
+
+/// The options for rendering Markdown like GitHub.
+fn md_options(config: &Config) -> Options<'static> {
+    let mut options = Options::default();
+    options.extension.autolink = true;
+    options.extension.header_ids = Some("user-content-".to_string());
+    options.extension.table = true;
+    options.extension.strikethrough = true;
+    options.extension.tagfilter = true;
+    options.extension.tasklist = true;
+    options.render.github_pre_lang = true;
+    options.extension.math_dollars = config.md_math != MdMath::Off;
+    for option in &config.md_options {
+        match option {
+            MdOption::Footnotes => options.extension.footnotes = true,
+            MdOption::Smart => options.parse.smart = true,
+            MdOption::Superscript => options.extension.superscript = true,
+            MdOption::DescriptionLists => options.extension.description_lists = true,
+            MdOption::Hardbreaks => options.render.hardbreaks = true,
+        }
+    }
+    options
+}
+
+/// The KaTeX assets and the script that renders math, for pages that
+/// contain any.
+///
+/// Comrak has already found the `$` delimiters and marked each formula with
+/// `data-math-style`, so this renders those elements directly rather than
+/// using KaTeX's delimiter-scanning auto-render.
+fn md_math_head(config: &Config, html: &str) -> String {
+    if config.md_math == MdMath::Off || !html.contains("data-math-style=") {
+        return String::new();
+    }
+
+    let base = config.md_math_url.trim_end_matches('/');
+    format!(
+        r#"<link rel="stylesheet" href="{base}/katex.min.css">
+    <script defer src="{base}/katex.min.js"></script>
+    <script>
+      document.addEventListener("DOMContentLoaded", function () {{
+        document.querySelectorAll("[data-math-style]").forEach(function (el) {{
+          katex.render(el.textContent, el, {{
+            displayMode: el.dataset.mathStyle === "display",
+            throwOnError: false
+          }});
+        }});
+      }});
+    </script>"#
+    )
+}
+
+/// Turn the rendered ```mermaid code blocks into the `<pre class="mermaid">`
+/// elements that mermaid looks for, returning whether there were any.
+///
+/// Other code blocks are left alone. The block contents are already HTML
+/// escaped, which is what mermaid expects since it reads the text content.
+fn convert_mermaid_blocks(html: &mut String) -> bool {
+    const OPEN: &str = "<pre lang=\"mermaid\"><code>";
+    const CLOSE: &str = "</code></pre>";
+
+    if !html.contains(OPEN) {
+        return false;
+    }
+
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html.as_str();
+    while let Some(start) = rest.find(OPEN) {
+        let after_open = &rest[start + OPEN.len()..];
+        let Some(end) = after_open.find(CLOSE) else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        out.push_str("<pre class=\"mermaid\">");
+        out.push_str(&after_open[..end]);
+        out.push_str("</pre>");
+        rest = &after_open[end + CLOSE.len()..];
+    }
+    out.push_str(rest);
+
+    *html = out;
+    true
+}
+
+/// The script that loads mermaid and renders diagrams.
+fn md_mermaid_head(config: &Config) -> String {
+    format!(
+        r#"
+    <script type="module">
+      import mermaid from "{}";
+      mermaid.initialize({{ startOnLoad: true }});
+    </script>"#,
+        config.md_mermaid_url
+    )
+}
+
+/// A weak ETag for a rendered markdown file, from the file's modification
+/// time and size, and a hash of the rendering options, since changing those
+/// changes the output too.
+fn md_etag(len: u64, modified: Option<SystemTime>, options: &Options, config: &Config) -> String {
+    let mut hasher = DefaultHasher::new();
+    format!("{options:?}").hash(&mut hasher);
+    config.md_math.hash(&mut hasher);
+    config.md_math_url.hash(&mut hasher);
+    config.md_mermaid.hash(&mut hasher);
+    config.md_mermaid_url.hash(&mut hasher);
+    config.assets_prefix.hash(&mut hasher);
+
+    let mtime = modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    format!(
+        "W/\"{:x}.{:x}-{:x}-{:x}\"",
+        mtime.as_secs(),
+        mtime.subsec_nanos(),
+        len,
+        hasher.finish()
+    )
+}
+
 fn maybe_convert_mime_type_to_text(
+    text_types: &TextTypes,
     req: &Request<Incoming>,
     resp: &mut Response<BoxBody<Bytes, Error>>,
 ) {
-    let path = req.uri().path();
-    let file_name = path.rsplit('/').next();
-    if let Some(file_name) = file_name {
-        let mut do_convert = false;
+    // A multi-range response's type describes the parts, not the file.
+    let multipart = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("multipart/"));
+    if text_types.matches(req) && !multipart {
+        let val = HeaderValue::from_str(mime::TEXT_PLAIN.as_ref()).expect("mime is valid header");
+        resp.headers_mut().insert(header::CONTENT_TYPE, val);
+        // The source view depends on the Accept header.
+        resp.headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("Accept"));
+    }
+}
+
+/// The type of markdown files as they are.
+static TEXT_MARKDOWN: LazyLock<mime::Mime> =
+    LazyLock::new(|| "text/markdown".parse().expect("valid mime type"));
+
+/// Whether to show a file as an HTML page rather than as it is, `raw` being
+/// the type it has as it is, like "text/markdown".
+///
+/// `?raw=1` and `?pretty=1` decide explicitly. Otherwise only clients whose
+/// `Accept` ranks HTML above the raw type, i.e. browsers, get the page, so
+/// that scripts fetching source files keep getting the plain text. A `*/*`
+/// alone ranks them the same, as `errorpages` does.
+fn wants_html_view(req: &Request<Incoming>, raw: &mime::Mime) -> bool {
+    if query_param(req, "raw").is_some_and(|v| v != "0") {
+        return false;
+    }
+    if query_param(req, "pretty").is_some_and(|v| v != "0") {
+        return true;
+    }
+    let Some(accept) = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    let html = errorpages::accept_q(accept, "text", "html");
+    let raw = errorpages::accept_q(accept, raw.type_().as_str(), raw.subtype().as_str());
+    html > raw
+}
+
+/// Get the value of a query parameter, undecoded. Parameters without a value
+/// have an empty one.
+fn query_param<'r>(req: &'r Request<Incoming>, name: &str) -> Option<&'r str> {
+    req.uri().query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (key == name).then_some(value)
+    })
+}
+
+/// Render a text file as an HTML page with numbered lines that can be linked
+/// to with `#L42`, unless it is bigger than `--max-source-view-size`.
+async fn maybe_source_view(
+    config: &Config,
+    ctx: &RequestContext,
+) -> Result<Option<Response<BoxBody<Bytes, Error>>>> {
+    let path = &ctx.file;
+    let (len, _) = file_meta(config, path).await?;
+    if len > config.max_source_view_size {
+        trace!("file too big for source view");
+        return Ok(None);
+    }
+
+    let buf = read_file(config, path).await?;
+
+    // Highlighting is CPU-heavy, so do it off the async threads, and only
+    // for files that are small enough and valid UTF-8.
+    let mut highlighted = None;
+    if len <= config.max_highlight_size {
+        if let Ok(text) = String::from_utf8(buf.clone()) {
+            let theme = config.highlight_theme.clone();
+            let path = path.to_owned();
+            highlighted =
+                tokio::task::spawn_blocking(move || highlight_lines(&theme, &path, &text))
+                    .await
+                    .ok()
+                    .flatten();
+        }
+    }
+
+    let (lines, background) = match highlighted {
+        Some(Highlighted { lines, background }) => (lines, background),
+        None => {
+            let text = String::from_utf8_lossy(&buf);
+            let lines: Vec<String> = text
+                .lines()
+                .map(|line| format!("{}\n", handlebars::html_escape(line)))
+                .collect();
+            (lines, None)
+        }
+    };
+
+    let mut body = String::with_capacity(buf.len() * 2);
+    body.push_str("<p><a href='?raw=1'>raw</a></p>\n");
+    match background {
+        Some(color) => body.push_str(&format!(
+            "<pre class='source' style='background-color: {color}'>"
+        )),
+        None => body.push_str("<pre class='source'>"),
+    }
+    for (i, line) in lines.iter().enumerate() {
+        let n = i + 1;
+        body.push_str(&format!(
+            "<span id='L{n}'><a class='ln' href='#L{n}'>{n}</a>{line}</span>"
+        ));
+    }
+    body.push_str("</pre>");
+
+    let title = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let cfg = HtmlCfg {
+        title,
+        kind: PageKind::Source,
+        stylesheet: crate::assets::stylesheet_url(config),
+        head: SOURCE_VIEW_STYLE.to_string(),
+        url_path: ctx.url_path.clone(),
+        body,
+        ..HtmlCfg::default()
+    };
+    let html = super::render_html(&cfg)?;
+    let mut resp = super::html_str_to_response(html, StatusCode::OK)?;
+    resp.headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("Accept"));
+    Ok(Some(resp))
+}
+
+/// Render a JSON file as an HTML page, pretty-printed and colored, with the
+/// top-level members collapsible, unless it is bigger than
+/// `--max-json-view-size`.
+///
+/// Invalid JSON is shown as plain text, under a note about where parsing
+/// failed.
+async fn maybe_json_view(
+    config: &Config,
+    ctx: &RequestContext,
+) -> Result<Option<Response<BoxBody<Bytes, Error>>>> {
+    let path = &ctx.file;
+    let (len, _) = file_meta(config, path).await?;
+    if len > config.max_json_view_size {
+        trace!("file too big for JSON view");
+        return Ok(None);
+    }
+
+    let buf = read_file(config, path).await?;
+
+    let mut body = String::with_capacity(buf.len() * 2);
+    body.push_str("<p><a href='?raw=1'>raw</a></p>\n");
+    match serde_json::from_slice::<serde_json::Value>(&buf) {
+        Ok(serde_json::Value::Object(map)) => {
+            for (key, value) in &map {
+                json_member_to_html(&mut body, &json_quote(key), value);
+            }
+        }
+        Ok(serde_json::Value::Array(items)) => {
+            for (i, value) in items.iter().enumerate() {
+                json_member_to_html(&mut body, &i.to_string(), value);
+            }
+        }
+        Ok(value) => {
+            body.push_str("<pre class='json'>");
+            json_value_to_html(&mut body, &value, 0);
+            body.push_str("</pre>");
+        }
+        Err(e) => {
+            let offset = byte_offset(&buf, e.line(), e.column());
+            body.push_str(&format!(
+                "<p class='json-error'>Invalid JSON at line {}, column {} (byte {}): {}</p>",
+                e.line(),
+                e.column(),
+                offset,
+                handlebars::html_escape(&e.to_string())
+            ));
+            body.push_str("<pre>");
+            body.push_str(&handlebars::html_escape(&String::from_utf8_lossy(&buf)));
+            body.push_str("</pre>");
+        }
+    }
+
+    let title = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
 
-        let ext = file_name.rsplit('.').next();
-        if let Some(ext) = ext {
-            if TEXT_EXTENSIONS.contains(&ext) {
-                do_convert = true;
+    let cfg = HtmlCfg {
+        title,
+        kind: PageKind::Json,
+        stylesheet: crate::assets::stylesheet_url(config),
+        head: JSON_VIEW_STYLE.to_string(),
+        url_path: ctx.url_path.clone(),
+        body,
+        ..HtmlCfg::default()
+    };
+    let html = super::render_html(&cfg)?;
+    let mut resp = super::html_str_to_response(html, StatusCode::OK)?;
+    resp.headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("Accept"));
+    Ok(Some(resp))
+}
+
+/// Render a top-level JSON member as a collapsible section.
+fn json_member_to_html(out: &mut String, key_html: &str, value: &serde_json::Value) {
+    let summary = match value {
+        serde_json::Value::Object(map) => format!(" {{…}} {} keys", map.len()),
+        serde_json::Value::Array(items) => format!(" […] {} items", items.len()),
+        _ => String::new(),
+    };
+    out.push_str(&format!(
+        "<details open><summary><span class='j-key'>{key_html}</span>{summary}</summary>"
+    ));
+    out.push_str("<pre class='json'>");
+    json_value_to_html(out, value, 0);
+    out.push_str("</pre></details>\n");
+}
+
+/// Pretty-print a JSON value as HTML, with spans for coloring.
+fn json_value_to_html(out: &mut String, value: &serde_json::Value, indent: usize) {
+    use serde_json::Value;
+
+    let pad = |n: usize| " ".repeat(n);
+    match value {
+        Value::Null => out.push_str("<span class='j-null'>null</span>"),
+        Value::Bool(b) => out.push_str(&format!("<span class='j-bool'>{b}</span>")),
+        Value::Number(n) => out.push_str(&format!("<span class='j-num'>{n}</span>")),
+        Value::String(s) => out.push_str(&json_string_to_html(s)),
+        Value::Array(items) if items.is_empty() => out.push_str("[]"),
+        Value::Array(items) => {
+            out.push_str("[\n");
+            for (i, item) in items.iter().enumerate() {
+                out.push_str(&pad(indent + 2));
+                json_value_to_html(out, item, indent + 2);
+                out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&pad(indent));
+            out.push(']');
+        }
+        Value::Object(map) if map.is_empty() => out.push_str("{}"),
+        Value::Object(map) => {
+            out.push_str("{\n");
+            for (i, (key, item)) in map.iter().enumerate() {
+                out.push_str(&pad(indent + 2));
+                out.push_str(&format!("<span class='j-key'>{}</span>: ", json_quote(key)));
+                json_value_to_html(out, item, indent + 2);
+                out.push_str(if i + 1 < map.len() { ",\n" } else { "\n" });
             }
+            out.push_str(&pad(indent));
+            out.push('}');
+        }
+    }
+}
+
+/// A JSON string value as HTML.
+fn json_string_to_html(s: &str) -> String {
+    format!("<span class='j-str'>{}</span>", json_quote(s))
+}
+
+/// A string quoted and escaped as JSON, then escaped as HTML.
+fn json_quote(s: &str) -> String {
+    let quoted = serde_json::Value::String(s.to_string()).to_string();
+    handlebars::html_escape(&quoted)
+}
+
+/// Convert serde_json's 1-based line and column of an error to a byte offset.
+fn byte_offset(buf: &[u8], line: usize, column: usize) -> usize {
+    let line_start: usize = buf
+        .split(|&b| b == b'\n')
+        .take(line.saturating_sub(1))
+        .map(|l| l.len() + 1)
+        .sum();
+    (line_start + column.saturating_sub(1)).min(buf.len())
+}
+
+static JSON_VIEW_STYLE: &str = r#"<style type="text/css">
+      .json {
+        overflow-x: auto;
+        margin: 0.25em 0 0.5em 2ch;
+      }
+      summary {
+        font-family: monospace;
+        cursor: pointer;
+      }
+      .j-key { color: #0451a5; }
+      .j-str { color: #a31515; }
+      .j-num { color: #098658; }
+      .j-bool, .j-null { color: #0000ff; }
+      .json-error {
+        padding: 0.5em;
+        background: #fdd;
+      }
+    </style>"#;
+
+/// Source code highlighted as HTML.
+struct Highlighted {
+    /// The HTML of each line, including its newline.
+    lines: Vec<String>,
+    /// The theme's background color, as CSS.
+    background: Option<String>,
+}
+
+/// The syntax definitions for highlighting, loaded on first use.
+static SYNTAX_SET: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+
+/// The highlighting themes, loaded on first use, and shared with the check
+/// of `--highlight-theme`.
+pub static THEME_SET: LazyLock<ThemeSet> = LazyLock::new(ThemeSet::load_defaults);
+
+/// Highlight source code with syntect, or return `None` if syntect doesn't
+/// recognize the language.
+///
+/// The syntax is found from the file's extension, then its whole name (for
+/// files like `Makefile`), then its first line (for `#!` scripts).
+fn highlight_lines(theme: &str, path: &Path, text: &str) -> Option<Highlighted> {
+    let syntaxes = &*SYNTAX_SET;
+    let by_name = |name: Option<&OsStr>| {
+        name.and_then(OsStr::to_str)
+            .and_then(|name| syntaxes.find_syntax_by_extension(name))
+    };
+    let syntax = by_name(path.extension())
+        .or_else(|| by_name(path.file_name()))
+        .or_else(|| syntaxes.find_syntax_by_first_line(text))?;
+    let theme = THEME_SET.themes.get(theme)?;
+
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    let mut lines = Vec::new();
+    for line in LinesWithEndings::from(text) {
+        let regions = highlighter.highlight_line(line, syntaxes).ok()?;
+        lines.push(styled_line_to_highlighted_html(&regions, IncludeBackground::No).ok()?);
+    }
+
+    let background = theme
+        .settings
+        .background
+        .map(|c| format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b));
+
+    Some(Highlighted { lines, background })
+}
+
+static SOURCE_VIEW_STYLE: &str = r#"<style type="text/css">
+      main:has(.source) {
+        max-width: none;
+      }
+      .source {
+        overflow-x: auto;
+      }
+      .source a.ln {
+        display: inline-block;
+        min-width: 5ch;
+        margin-right: 2ch;
+        text-align: right;
+        color: #999;
+        text-decoration: none;
+        user-select: none;
+      }
+      .source :target {
+        background: #ffc;
+      }
+    </style>"#;
+
+/// The file extensions and names of source code files that browsers should
+/// display as text.
+///
+/// This starts from `TEXT_EXTENSIONS` and `TEXT_FILES`, adjusted by the
+/// `--text-ext`, `--text-file` and `--no-text-ext` options.
+#[derive(Clone, Debug, Default)]
+pub struct TextTypes {
+    extensions: HashSet<String>,
+    files: HashSet<String>,
+}
+
+impl TextTypes {
+    pub fn new(add_exts: &[String], add_files: &[String], remove_exts: &[String]) -> TextTypes {
+        let normalize = |ext: &String| ext.trim_start_matches('.').to_string();
+
+        let mut extensions: HashSet<String> =
+            TEXT_EXTENSIONS.iter().map(|e| e.to_string()).collect();
+        extensions.extend(add_exts.iter().map(normalize));
+        for ext in remove_exts {
+            extensions.remove(&normalize(ext));
+        }
+
+        let mut files: HashSet<String> = TEXT_FILES.iter().map(|f| f.to_string()).collect();
+        files.extend(add_files.iter().cloned());
+
+        TextTypes { extensions, files }
+    }
+
+    /// Whether the request is for a source code file.
+    pub fn matches(&self, req: &Request<Incoming>) -> bool {
+        self.matches_path(req.uri().path())
+    }
+
+    /// Whether a path names a source code file.
+    pub fn matches_path(&self, path: &str) -> bool {
+        let Some(file_name) = path.rsplit('/').next() else {
+            return false;
+        };
+
+        if let Some((_, ext)) = file_name.rsplit_once('.') {
+            if self.extensions.contains(ext) {
+                return true;
+            }
+        }
+
+        self.files.contains(file_name)
+    }
+}
+
+/// The directory of well-known URIs, which `--hide` never hides.
+const WELL_KNOWN: &str = ".well-known";
+
+/// The compiled `--hide` patterns.
+#[derive(Clone, Debug, Default)]
+pub struct HidePatterns {
+    set: GlobSet,
+}
+
+impl HidePatterns {
+    /// Compile gitignore-style patterns: a pattern without a "/" matches at
+    /// any depth, a leading "/" anchors it to the root, and a trailing "/" is
+    /// ignored.
+    pub fn new(patterns: &[String]) -> Result<HidePatterns> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let trimmed = pattern.trim_end_matches('/');
+            let glob = match trimmed.strip_prefix('/') {
+                Some(anchored) => anchored.to_string(),
+                None if trimmed.contains('/') => trimmed.to_string(),
+                None => format!("**/{trimmed}"),
+            };
+            let glob = GlobBuilder::new(&glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| Error::Config(format!("invalid --hide pattern {pattern:?}: {e}")))?;
+            builder.add(glob);
         }
+        let set = builder
+            .build()
+            .map_err(|e| Error::Config(format!("invalid --hide patterns: {e}")))?;
+        Ok(HidePatterns { set })
+    }
 
-        if TEXT_FILES.contains(&file_name) {
-            do_convert = true;
+    /// Whether a path relative to the root, or any directory containing it,
+    /// is hidden.
+    ///
+    /// The root's `.well-known` directory (RFC 8615) is never hidden itself,
+    /// so that hiding dotfiles doesn't hide ACME challenges and the like,
+    /// though what's in it can be.
+    pub fn is_hidden(&self, rel_path: &Path) -> bool {
+        if self.set.is_empty() {
+            return false;
         }
+        // Rebuild the path to drop any trailing separator, which globs
+        // wouldn't match.
+        let rel_path: PathBuf = rel_path.components().collect();
+        rel_path
+            .ancestors()
+            .filter(|p| !p.as_os_str().is_empty() && *p != Path::new(WELL_KNOWN))
+            .any(|p| self.set.is_match(p))
+    }
+}
+
+impl fmt::Display for TextTypes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut extensions: Vec<&String> = self.extensions.iter().collect();
+        extensions.sort();
+        let mut files: Vec<&String> = self.files.iter().collect();
+        files.sort();
 
-        if do_convert {
-            use http::header::HeaderValue;
-            let val =
-                HeaderValue::from_str(mime::TEXT_PLAIN.as_ref()).expect("mime is valid header");
-            resp.headers_mut().insert(header::CONTENT_TYPE, val);
+        writeln!(f, "extensions:")?;
+        for ext in extensions {
+            writeln!(f, "    {ext}")?;
         }
+        writeln!(f, "files:")?;
+        for file in files {
+            writeln!(f, "    {file}")?;
+        }
+        Ok(())
     }
 }
 
@@ -168,47 +972,868 @@ static TEXT_FILES: &[&str] = &[
 
 /// Try to treat the path as a directory and list the contents as HTML.
 async fn maybe_list_dir(
-    root_dir: &Path,
-    path: &Path,
+    req: &Request<Incoming>,
+    config: &Config,
+    ctx: &RequestContext,
 ) -> Result<Option<Response<BoxBody<Bytes, Error>>>> {
-    let meta = tokio::fs::metadata(path).await?;
-    if meta.is_dir() {
-        Ok(Some(list_dir(root_dir, path).await?))
+    let path = &ctx.path;
+    let is_dir = match &config.vfs {
+        Some(vfs) if vfs.is_dir(path) => true,
+        Some(_) => return Err(crate::vfs::not_found()),
+        None => tokio::fs::metadata(path).await?.is_dir(),
+    };
+    if !is_dir {
+        return Ok(None);
+    }
+    if config.vfs.is_none() && *path == config.root_dir {
+        if let Some(resp) = maybe_rustdoc_index(req, config, ctx).await? {
+            return Ok(Some(resp));
+        }
+    }
+    Ok(Some(list_dir(req, config, ctx).await?))
+}
+
+/// For `--list-dirs` without extensions: list a directory without an index
+/// file, as plain text unless the client prefers HTML. Anything else is left
+/// as it was.
+async fn maybe_negotiate_dir_list(
+    req: &Request<Incoming>,
+    config: &Config,
+    ctx: &RequestContext,
+    resp: Result<Response<BoxBody<Bytes, Error>>>,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    let is_not_found = matches!(&resp, Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound);
+    if !is_not_found {
+        return resp;
+    }
+    let is_dir = match &config.vfs {
+        Some(vfs) => vfs.is_dir(&ctx.path),
+        None => tokio::fs::metadata(&ctx.path)
+            .await
+            .is_ok_and(|meta| meta.is_dir()),
+    };
+    if !is_dir {
+        return resp;
+    }
+
+    let listing = if wants_html_view(req, &mime::TEXT_PLAIN) {
+        list_dir(req, config, ctx).await
     } else {
-        Ok(None)
+        list_dir_plain(config, ctx).await
+    };
+    match listing {
+        Ok(mut listing) => {
+            listing
+                .headers_mut()
+                .insert(header::VARY, HeaderValue::from_static("Accept"));
+            Ok(listing)
+        }
+        Err(e) => {
+            warn!("no directory list for {}: {}", ctx.path.display(), e);
+            resp
+        }
     }
 }
 
-/// List the contents of a directory as HTML.
-async fn list_dir(root_dir: &Path, path: &Path) -> Result<Response<BoxBody<Bytes, Error>>> {
-    let up_dir = path.join("..");
-    let path = path.to_owned();
+/// List the names in a directory as plain text, one per line, with a "/"
+/// after those of directories, for scripts.
+async fn list_dir_plain(
+    config: &Config,
+    ctx: &RequestContext,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    let (paths, _) = dir_list_paths(config, &ctx.path).await?;
+    let mut body = String::new();
+    for p in &paths {
+        let Some(name) = p.file_name() else {
+            continue;
+        };
+        let is_dir = match &config.vfs {
+            Some(vfs) => vfs.is_dir(p),
+            None => p.is_dir(),
+        };
+        body.push_str(&name.to_string_lossy());
+        if is_dir {
+            body.push('/');
+        }
+        body.push('\n');
+    }
+
+    let resp = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(
+            Full::new(Bytes::from(body))
+                .map_err(|never| match never {})
+                .boxed(),
+        )?;
+    Ok(resp)
+}
+
+/// In a rustdoc output directory like `target/doc`, link to the documented
+/// crates instead of listing the directory, or redirect to the only one.
+/// `?view=list` gets the listing.
+async fn maybe_rustdoc_index(
+    req: &Request<Incoming>,
+    config: &Config,
+    ctx: &RequestContext,
+) -> Result<Option<Response<BoxBody<Bytes, Error>>>> {
+    if query_param(req, "view").is_some() {
+        return Ok(None);
+    }
+    let Some(crates) = rustdoc_crates(config, &ctx.path).await? else {
+        return Ok(None);
+    };
+
+    let base = ctx.url_path.trim_end_matches('/');
+    let links: Vec<DirEntryLink> = crates
+        .into_iter()
+        .filter_map(|name| {
+            let url = format!("{base}/{}/index.html", encode_url_path(Path::new(&name))?);
+            Some(DirEntryLink {
+                name,
+                url,
+                link_target: None,
+                kind: "directory",
+                check: None,
+            })
+        })
+        .collect();
+
+    if let [only] = &links[..] {
+        debug!("redirecting to the docs for {}", only.name);
+        let resp = Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, &only.url)
+            .body(
+                Empty::<Bytes>::new()
+                    .map_err(|never| match never {})
+                    .boxed(),
+            )?;
+        return Ok(Some(resp));
+    }
+
+    let mut buf = String::new();
+    write_dir_list(&mut buf, &links)?;
+    writeln!(buf, "<p><a href='?view=list'>directory listing</a></p>")
+        .map_err(Error::WriteInDirList)?;
+    let cfg = HtmlCfg {
+        title: "Crates".to_string(),
+        kind: PageKind::Listing,
+        stylesheet: crate::assets::stylesheet_url(config),
+        crumbs: breadcrumbs(&ctx.url_path, Links::Absolute),
+        url_path: ctx.url_path.clone(),
+        body: buf,
+        ..HtmlCfg::default()
+    };
+    let html = super::render_html(&cfg)?;
+    Ok(Some(super::html_str_to_response(html, StatusCode::OK)?))
+}
+
+/// The crates documented in a rustdoc output directory, sorted, or `None` if
+/// it isn't one.
+///
+/// This is conservative, so other directories keep their listing: there must
+/// be rustdoc's `search-index.js` or `crates.js`, and the crates are the
+/// subdirectories with both the `index.html` and `all.html` rustdoc writes
+/// for each.
+async fn rustdoc_crates(config: &Config, dir: &Path) -> Result<Option<Vec<String>>> {
+    let is_rustdoc = ["search-index.js", "crates.js"]
+        .iter()
+        .any(|marker| dir.join(marker).is_file());
+    if !is_rustdoc {
+        return Ok(None);
+    }
+
+    let mut crates = Vec::new();
+    let mut dents = tokio::fs::read_dir(dir).await?;
+    while let Some(e) = dents.next_entry().await? {
+        let p = e.path();
+        let rel = p.strip_prefix(&config.root_dir).unwrap_or(&p);
+        if config.hide_patterns.is_hidden(rel) {
+            continue;
+        }
+        if !p.join("index.html").is_file() || !p.join("all.html").is_file() {
+            continue;
+        }
+        if let Some(name) = e.file_name().to_str() {
+            crates.push(name.to_string());
+        }
+    }
+    crates.sort();
+    Ok((!crates.is_empty()).then_some(crates))
+}
+
+/// List the contents of a directory as HTML, either as a list or, with
+/// `?view=gallery`, as a grid of images. Entries matching `--hide` are left
+/// out.
+async fn list_dir(
+    req: &Request<Incoming>,
+    config: &Config,
+    ctx: &RequestContext,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    let path = &ctx.path;
+    let (mut paths, totals) = dir_list_paths(config, path).await?;
+    let checks = verify_sums(config, &paths).await;
+    // Narrow the list to one kind of entry with `?kind=`.
+    let kind = query_param(req, "kind").and_then(|kind| KINDS.iter().find(|k| **k == kind));
+    if let Some(kind) = kind {
+        paths.retain(|p| entry_kind(config, p) == *kind);
+    }
+    if *path != config.root_dir {
+        paths.insert(0, path.join(".."));
+    }
+    let html = match query_param(req, "view") {
+        Some("gallery") => {
+            let page = query_param(req, "page")
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(1);
+            make_gallery_body(req, config, &paths, page)?
+        }
+        _ => make_dir_list_body(
+            &ctx.url_path,
+            config,
+            &paths,
+            &totals,
+            Links::Absolute,
+            kind.copied(),
+            checks.as_ref(),
+        )?,
+    };
+    let resp = super::html_str_to_response(html, StatusCode::OK)?;
+    Ok(resp)
+}
+
+/// Render the listing of a directory as a page to save as its `index.html`,
+/// for `--generate-index`.
+///
+/// This is the same page as the live listing, but with relative links, so it
+/// works wherever the tree is published, and without the gallery view, which
+/// needs the server.
+pub async fn dir_index_page(config: &Config, path: &Path) -> Result<String> {
+    let root_dir = &config.root_dir;
+    let (mut paths, totals) = dir_list_paths(config, path).await?;
+    if path != root_dir.as_path() {
+        paths.insert(0, path.join(".."));
+    }
+    let rel = path
+        .strip_prefix(root_dir)
+        .map_err(Error::StripPrefixInDirList)?;
+    let url_path = match encode_url_path(rel) {
+        Some(url) if !url.is_empty() => format!("/{url}/"),
+        _ => "/".to_string(),
+    };
+    make_dir_list_body(
+        &url_path,
+        config,
+        &paths,
+        &totals,
+        Links::Relative,
+        None,
+        None,
+    )
+}
+
+/// The entries of a directory to list, sorted, and their totals. Entries
+/// matching `--hide`, and files that aren't servable, are left out.
+async fn dir_list_paths(config: &Config, path: &Path) -> Result<(Vec<PathBuf>, DirTotals)> {
+    if let Some(vfs) = &config.vfs {
+        let entries = vfs.list(path).ok_or_else(crate::vfs::not_found)?;
+        return Ok(vfs_list_paths(config, entries));
+    }
+
     let mut dents = tokio::fs::read_dir(path).await?;
     let mut paths: Vec<PathBuf> = Vec::new();
-    while let Ok(e) = dents.next_entry().await {
-        if let Some(e) = e {
-            paths.push(e.path());
+    let mut totals = DirTotals::default();
+    while let Some(e) = dents.next_entry().await? {
+        let p = e.path();
+        let rel = p.strip_prefix(&config.root_dir).unwrap_or(&p);
+        if config.hide_patterns.is_hidden(rel) {
+            continue;
         }
+        match tokio::fs::metadata(&p).await {
+            Ok(meta) if meta.is_dir() => totals.dirs += 1,
+            // FIFOs, sockets and device files are never served.
+            Ok(meta) if !meta.is_file() => continue,
+            Ok(meta) if config.is_servable(&p) => {
+                totals.files += 1;
+                totals.bytes += meta.len();
+            }
+            Err(_) if config.is_servable(&p) => totals.unreadable += 1,
+            _ => continue,
+        }
+        paths.push(p);
     }
     paths.sort();
-    let paths = Some(up_dir).into_iter().chain(paths);
-    let paths: Vec<_> = paths.collect();
-    let html = make_dir_list_body(root_dir, &paths)?;
-    let resp = super::html_str_to_response(html, StatusCode::OK)?;
-    Ok(resp)
+    Ok((paths, totals))
 }
 
-fn make_dir_list_body(root_dir: &Path, paths: &[PathBuf]) -> Result<String> {
-    let mut buf = String::new();
+/// Like `dir_list_paths`, for the entries of a directory in the tree served
+/// in place of the root directory.
+fn vfs_list_paths(
+    config: &Config,
+    entries: Vec<(PathBuf, Option<u64>)>,
+) -> (Vec<PathBuf>, DirTotals) {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let mut totals = DirTotals::default();
+    for (p, len) in entries {
+        let rel = p.strip_prefix(&config.root_dir).unwrap_or(&p);
+        if config.hide_patterns.is_hidden(rel) {
+            continue;
+        }
+        match len {
+            None => totals.dirs += 1,
+            Some(len) if config.is_servable(&p) => {
+                totals.files += 1;
+                totals.bytes += len;
+            }
+            Some(_) => continue,
+        }
+        paths.push(p);
+    }
+    paths.sort();
+    (paths, totals)
+}
+
+/// The deepest `DirWalk` goes below the directory it starts from.
+const MAX_WALK_DEPTH: usize = 64;
+
+/// A depth-first walk of a directory tree on disk, for features that go
+/// through a whole tree.
+///
+/// The caller chooses which subdirectories to descend into. Directories are
+/// recognized by device and inode, so one reached again by another path,
+/// through a symlink like `a -> .` or a bind mount, isn't visited twice, and
+/// the walk ends. Nor does it go more than `MAX_WALK_DEPTH` levels down.
+pub struct DirWalk {
+    /// Directories to visit, with their depth.
+    pending: Vec<(PathBuf, usize)>,
+    /// The depth of the directory last returned by `next_dir`.
+    depth: usize,
+    visited: HashSet<DirId>,
+}
+
+/// An entry of a directory in a `DirWalk`.
+pub struct WalkEntry {
+    pub path: PathBuf,
+    /// Whether it's a directory, or a symlink to one.
+    pub is_dir: bool,
+    pub is_symlink: bool,
+}
+
+#[cfg(unix)]
+pub type DirId = (u64, u64);
+
+/// Without inodes, the canonical path stands in for one.
+#[cfg(not(unix))]
+pub type DirId = PathBuf;
+
+impl DirWalk {
+    pub fn new(root: PathBuf) -> DirWalk {
+        DirWalk {
+            pending: vec![(root, 0)],
+            depth: 0,
+            visited: HashSet::new(),
+        }
+    }
+
+    /// The next directory to visit, or `None` when the walk is done.
+    pub async fn next_dir(&mut self) -> Result<Option<PathBuf>> {
+        while let Some((dir, depth)) = self.pending.pop() {
+            if !self.visited.insert(dir_id(&dir).await?) {
+                warn!("not visiting {} again by another path", dir.display());
+                continue;
+            }
+            self.depth = depth;
+            return Ok(Some(dir));
+        }
+        Ok(None)
+    }
+
+    /// The entries of a directory.
+    pub async fn entries(dir: &Path) -> Result<Vec<WalkEntry>> {
+        let mut entries = Vec::new();
+        let mut dents = tokio::fs::read_dir(dir).await?;
+        while let Some(e) = dents.next_entry().await? {
+            let path = e.path();
+            let is_symlink = e.file_type().await?.is_symlink();
+            // A broken symlink isn't a directory.
+            let is_dir = tokio::fs::metadata(&path)
+                .await
+                .is_ok_and(|meta| meta.is_dir());
+            entries.push(WalkEntry {
+                path,
+                is_dir,
+                is_symlink,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Visit a subdirectory of the directory last returned by `next_dir`,
+    /// unless that's too deep.
+    pub fn descend(&mut self, dir: PathBuf) {
+        if self.depth >= MAX_WALK_DEPTH {
+            warn!(
+                "not descending into {}, more than {} levels deep",
+                dir.display(),
+                MAX_WALK_DEPTH
+            );
+            return;
+        }
+        self.pending.push((dir, self.depth + 1));
+    }
+}
+
+#[cfg(unix)]
+pub async fn dir_id(dir: &Path) -> io::Result<DirId> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = tokio::fs::metadata(dir).await?;
+    Ok((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+pub async fn dir_id(dir: &Path) -> io::Result<DirId> {
+    tokio::fs::canonicalize(dir).await
+}
+
+/// Counts of a directory's listed entries, not including the contents of
+/// subdirectories, for the footer of the listing.
+#[derive(Default)]
+struct DirTotals {
+    files: u64,
+    dirs: u64,
+    /// The total size of the files.
+    bytes: u64,
+    /// Entries whose metadata couldn't be read, like broken symlinks.
+    unreadable: u64,
+}
 
-    writeln!(buf, "<div>").map_err(Error::WriteInDirList)?;
+impl fmt::Display for DirTotals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plural =
+            |n: u64, one: &'static str, many: &'static str| if n == 1 { one } else { many };
+        write!(
+            f,
+            "{} {}, {} {}, {} total",
+            self.files,
+            plural(self.files, "file", "files"),
+            self.dirs,
+            plural(self.dirs, "directory", "directories"),
+            human_size(self.bytes)
+        )?;
+        if self.unreadable > 0 {
+            write!(f, ", {} unreadable", self.unreadable)?;
+        }
+        Ok(())
+    }
+}
+
+/// Format a size in bytes with binary units, like "128.4 MiB".
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// How generated pages link to other paths.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Links {
+    /// Absolute URL paths, for pages the server responds with.
+    Absolute,
+    /// Paths relative to the page's directory, for pages saved to files.
+    Relative,
+}
+
+// https://url.spec.whatwg.org/#fragment-percent-encode-set
+const FRAGMENT_SET: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'<').add(b'>').add(b'`');
+/// The characters to %-encode in the path of links we generate.
+const PATH_SET: &AsciiSet = &FRAGMENT_SET
+    .add(b'#')
+    .add(b'?')
+    .add(b'{')
+    .add(b'}')
+    .add(b'\'')
+    .add(b'%');
+
+/// A link to an ancestor of the requested path.
+#[derive(Serialize)]
+pub struct Crumb {
+    /// The decoded name of the directory or file, or "/" for the root.
+    pub name: String,
+    pub url: String,
+}
+
+/// Make a trail of links to each ancestor directory of a URL path, starting
+/// at the root, for the top of generated pages.
+///
+/// Relative links are for directory pages, whose URL path ends in "/".
+fn breadcrumbs(url_path: &str, links: Links) -> Vec<Crumb> {
+    let segments: Vec<_> = url_path.split('/').filter(|s| !s.is_empty()).collect();
+    // The way up from the directory to the ancestor with `n` segments.
+    let up = |n: usize| match segments.len() - n {
+        0 => "./".to_string(),
+        levels => "../".repeat(levels),
+    };
+    let mut href = match links {
+        Links::Absolute => String::from("/"),
+        Links::Relative => up(0),
+    };
+    let mut crumbs = vec![Crumb {
+        name: "/".to_string(),
+        url: href.clone(),
+    }];
+    for (i, segment) in segments.iter().enumerate() {
+        let name = percent_decode_str(segment).decode_utf8_lossy();
+        if links == Links::Relative {
+            href = up(i + 1);
+        } else {
+            href.extend(utf8_percent_encode(&name, PATH_SET));
+            // Only the last component can be a file.
+            if i + 1 < segments.len() || url_path.ends_with('/') {
+                href.push('/');
+            }
+        }
+        crumbs.push(Crumb {
+            name: name.into_owned(),
+            url: href.clone(),
+        });
+    }
+    crumbs
+}
+
+/// Show breadcrumbs as a line of links, or nothing if there are none.
+pub fn breadcrumbs_html(crumbs: &[Crumb]) -> String {
+    let Some((root, rest)) = crumbs.split_first() else {
+        return String::new();
+    };
+    let mut buf = format!(
+        "<nav class='breadcrumbs'><a href='{}'>{}</a>",
+        root.url,
+        handlebars::html_escape(&root.name)
+    );
+    for crumb in rest {
+        buf.push_str(&format!(
+            " <a href='{}'>{}</a> /",
+            crumb.url,
+            handlebars::html_escape(&crumb.name)
+        ));
+    }
+    buf.push_str("</nav>");
+    buf
+}
+
+/// The most suggestions shown on a 404 page.
+const MAX_SUGGESTIONS: usize = 5;
+
+/// Don't look for suggestions in directories with more entries than this.
+const MAX_SUGGESTION_DIR_SIZE: usize = 5000;
+
+/// Make a 404 page suggesting entries of the missing file's directory with
+/// similar names, if there are any.
+async fn maybe_suggest_similar(
+    req: &Request<Incoming>,
+    config: &Config,
+    path: &Path,
+) -> Result<Option<Response<BoxBody<Bytes, Error>>>> {
+    let (Some(parent), Some(wanted)) = (path.parent(), path.file_name()) else {
+        return Ok(None);
+    };
+    let wanted = wanted.to_string_lossy().to_lowercase();
+
+    let mut candidates: Vec<(PathBuf, bool)> = Vec::new();
+    match &config.vfs {
+        Some(vfs) => {
+            let Some(entries) = vfs.list(parent) else {
+                return Ok(None);
+            };
+            candidates.extend(entries.into_iter().map(|(p, len)| (p, len.is_none())));
+        }
+        None => {
+            let Ok(mut dents) = tokio::fs::read_dir(parent).await else {
+                return Ok(None);
+            };
+            while let Ok(Some(e)) = dents.next_entry().await {
+                candidates.push((e.path(), false));
+                if candidates.len() > MAX_SUGGESTION_DIR_SIZE {
+                    break;
+                }
+            }
+        }
+    }
+    if candidates.len() > MAX_SUGGESTION_DIR_SIZE {
+        debug!("too many entries to suggest from in {}", parent.display());
+        return Ok(None);
+    }
+
+    let mut scored = Vec::new();
+    for (candidate, is_dir) in candidates {
+        let rel = candidate
+            .strip_prefix(&config.root_dir)
+            .unwrap_or(&candidate);
+        let is_dir = is_dir || (config.vfs.is_none() && candidate.is_dir());
+        if config.hide_patterns.is_hidden(rel) || !(is_dir || config.is_servable(&candidate)) {
+            continue;
+        }
+        let Some(name) = candidate.file_name() else {
+            continue;
+        };
+        let name = name.to_string_lossy().to_lowercase();
+        if let Some(score) = suggestion_score(&wanted, &name) {
+            scored.push((score, candidate));
+        }
+    }
+    if scored.is_empty() {
+        return Ok(None);
+    }
+    scored.sort();
+    let paths: Vec<PathBuf> = scored
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, p)| p)
+        .collect();
+    let links = dir_entry_links(config, &paths, Links::Absolute)?;
+
+    let mut body = String::new();
+    writeln!(body, "<p>Did you mean:</p>").map_err(Error::WriteInDirList)?;
+    write_dir_list(&mut body, &links)?;
+
+    let status = StatusCode::NOT_FOUND;
+    let cfg = HtmlCfg {
+        title: format!("{status}"),
+        kind: PageKind::Error,
+        stylesheet: crate::assets::stylesheet_url(config),
+        path: super::error_page_path(config, req.uri())
+            .unwrap_or_default()
+            .to_string(),
+        body,
+        ..HtmlCfg::default()
+    };
+    let html = super::render_html(&cfg)?;
+    Ok(Some(super::html_str_to_response(html, status)?))
+}
+
+/// How close the name of a directory entry is to a name that wasn't found,
+/// lower being closer, or `None` if it isn't worth suggesting. Both names
+/// are lowercase.
+fn suggestion_score(wanted: &str, candidate: &str) -> Option<usize> {
+    // Differs only by case.
+    if candidate == wanted {
+        return Some(0);
+    }
+    // A missing or extra extension.
+    let short = wanted.len().min(candidate.len());
+    if short >= 3 && (candidate.starts_with(wanted) || wanted.starts_with(candidate)) {
+        return Some(1);
+    }
+    // A typo.
+    let max_distance = (wanted.chars().count() / 4).clamp(1, 3);
+    let distance = edit_distance(wanted, candidate);
+    (distance <= max_distance).then_some(distance + 1)
+}
+
+/// The Levenshtein distance between two strings, in chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != cb);
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+/// %-encode a relative path for use in a URL.
+///
+/// Unix file names are bytes, so names that aren't UTF-8 are encoded byte by
+/// byte, and `local_path_for_url_path` decodes them back the same way.
+#[cfg(unix)]
+pub fn encode_url_path(path: &Path) -> Option<String> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(percent_encode(path.as_os_str().as_bytes(), PATH_SET).to_string())
+}
+
+/// %-encode a relative path for use in a URL.
+#[cfg(not(unix))]
+pub fn encode_url_path(path: &Path) -> Option<String> {
+    path.to_str()
+        .map(|path| utf8_percent_encode(path, PATH_SET).to_string())
+}
+
+/// An entry of a directory listing.
+struct DirEntryLink {
+    /// The file name, for display.
+    name: String,
+    /// The URL of the entry, %-encoded.
+    url: String,
+    /// Where the entry links to, if it's a symlink.
+    link_target: Option<String>,
+    /// What sort of entry it is, one of `KINDS`.
+    kind: &'static str,
+    /// Whether the file matches a checksums file in the directory: "✓",
+    /// "✗", or "—" if it couldn't be checked.
+    check: Option<&'static str>,
+}
+
+/// The largest checksums file read.
+const MAX_SUMS_FILE_SIZE: u64 = 1024 * 1024;
+
+/// A directory's files checked against the checksums files in it.
+struct SumsChecks {
+    /// The names of the checksums files.
+    sums_files: Vec<String>,
+    /// The mark for each file named in a checksums file, by name.
+    marks: HashMap<String, &'static str>,
+    /// The files named in a checksums file that aren't in the directory.
+    missing: Vec<String>,
+}
+
+/// Whether a file is a list of SHA-256 checksums, as written by `sha256sum`.
+fn is_sums_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|name| name == "SHA256SUMS" || name.to_ascii_lowercase().ends_with(".sha256"))
+}
+
+/// Check the files of a directory against the `SHA256SUMS` or `*.sha256`
+/// files among them, if there are any.
+///
+/// Files are hashed as `--digests` hashes them: cached, only a few at once,
+/// and not over `--max-digest-size`. Only names in the directory itself are
+/// checked, not paths into subdirectories.
+async fn verify_sums(config: &Config, paths: &[PathBuf]) -> Option<SumsChecks> {
+    let sums_paths: Vec<&PathBuf> = paths.iter().filter(|p| is_sums_file(p)).collect();
+    if sums_paths.is_empty() {
+        return None;
+    }
+
+    let mut expected: Vec<(String, String)> = Vec::new();
+    let mut sums_files = Vec::new();
+    for sums_path in sums_paths {
+        let name = sums_path.file_name()?.to_string_lossy().into_owned();
+        match file_meta(config, sums_path).await {
+            Ok((len, _)) if len <= MAX_SUMS_FILE_SIZE => {}
+            _ => {
+                warn!("not reading checksums file {}", sums_path.display());
+                continue;
+            }
+        }
+        let Ok(text) = read_file(config, sums_path).await else {
+            continue;
+        };
+        for line in String::from_utf8_lossy(&text).lines() {
+            // "<hex digest> <name>", with a "*" before the name in binary
+            // mode.
+            let Some((digest, name)) = line.split_once(' ') else {
+                continue;
+            };
+            let name = name.trim_start_matches([' ', '*']).trim_start_matches("./");
+            if digest.len() == 64 && !name.is_empty() && !name.contains('/') {
+                expected.push((name.to_string(), digest.to_ascii_lowercase()));
+            }
+        }
+        sums_files.push(name);
+    }
+
+    let by_name: HashMap<String, &PathBuf> = paths
+        .iter()
+        .filter_map(|p| Some((p.file_name()?.to_str()?.to_string(), p)))
+        .collect();
+    let mut missing = Vec::new();
+    let mut checks = Vec::new();
+    for (name, digest) in expected {
+        match by_name.get(&name) {
+            Some(path) => checks.push(async move {
+                let mark = match crate::digest::file_digest(config, path).await {
+                    Ok((_, actual)) => {
+                        let actual: String = actual.iter().map(|b| format!("{b:02x}")).collect();
+                        if actual == digest {
+                            "✓"
+                        } else {
+                            "✗"
+                        }
+                    }
+                    Err(e) => {
+                        debug!("can't check {}: {}", path.display(), e);
+                        "—"
+                    }
+                };
+                (name, mark)
+            }),
+            None => missing.push(name),
+        }
+    }
+    let marks = futures::future::join_all(checks)
+        .await
+        .into_iter()
+        .collect();
+    missing.sort();
+    missing.dedup();
+    Some(SumsChecks {
+        sums_files,
+        marks,
+        missing,
+    })
+}
+
+/// The kinds of entry a listing can be narrowed to with `?kind=`.
+static KINDS: [&str; 6] = ["directory", "image", "video", "audio", "text", "other"];
+
+/// The kind of a listed entry, going by its MIME type as it would be served.
+fn entry_kind(config: &Config, path: &Path) -> &'static str {
+    let is_dir = match &config.vfs {
+        Some(vfs) => vfs.is_dir(path),
+        None => path.is_dir(),
+    };
+    if is_dir {
+        return "directory";
+    }
+    let mime = super::file_path_mime(path);
+    let (type_, subtype) = (mime.type_(), mime.subtype());
+    if type_ == mime::IMAGE {
+        "image"
+    } else if type_ == mime::VIDEO {
+        "video"
+    } else if type_ == mime::AUDIO {
+        "audio"
+    } else if type_ == mime::TEXT
+        || (type_ == mime::APPLICATION
+            && [mime::JSON, mime::JAVASCRIPT, mime::XML].contains(&subtype))
+    {
+        "text"
+    } else {
+        "other"
+    }
+}
+
+/// Make the links for a directory listing, skipping entries that can't be
+/// linked to.
+///
+/// Relative links are relative to the directory containing the entries, and
+/// link to directories with a trailing "/" so no redirect is needed.
+fn dir_entry_links(config: &Config, paths: &[PathBuf], links: Links) -> Result<Vec<DirEntryLink>> {
+    let mut entries = Vec::with_capacity(paths.len());
 
     let dot_dot = OsStr::new("..");
 
     for path in paths {
         let full_url = path
-            .strip_prefix(root_dir)
+            .strip_prefix(&config.root_dir)
             .map_err(Error::StripPrefixInDirList)?;
+        let kind = entry_kind(config, path);
         let maybe_dot_dot = || {
             if path.ends_with("..") {
                 Some(dot_dot)
@@ -217,36 +1842,388 @@ fn make_dir_list_body(root_dir: &Path, paths: &[PathBuf]) -> Result<String> {
             }
         };
         if let Some(file_name) = path.file_name().or_else(maybe_dot_dot) {
-            if let Some(file_name) = file_name.to_str() {
-                if let Some(full_url) = full_url.to_str() {
-                    // %-encode filenames
-                    // https://url.spec.whatwg.org/#fragment-percent-encode-set
-                    const FRAGMENT_SET: &AsciiSet =
-                        &CONTROLS.add(b' ').add(b'"').add(b'<').add(b'>').add(b'`');
-                    const PATH_SET: &AsciiSet =
-                        &FRAGMENT_SET.add(b'#').add(b'?').add(b'{').add(b'}');
-                    let full_url = utf8_percent_encode(full_url, PATH_SET);
-
-                    // TODO: Make this a relative URL
-                    writeln!(buf, "<div><a href='/{full_url}'>{file_name}</a></div>")
-                        .map_err(Error::WriteInDirList)?;
-                } else {
-                    warn!("non-unicode url: {}", full_url.to_string_lossy());
-                }
+            let url = match links {
+                Links::Absolute => encode_url_path(full_url).map(|url| format!("/{url}")),
+                Links::Relative => encode_url_path(Path::new(file_name)).map(|url| {
+                    let slash = if kind == "directory" { "/" } else { "" };
+                    format!("{url}{slash}")
+                }),
+            };
+            if let Some(url) = url {
+                let name = match file_name.to_str() {
+                    Some(name) => name.to_string(),
+                    None => format!("{} (not UTF-8)", file_name.to_string_lossy()),
+                };
+
+                entries.push(DirEntryLink {
+                    name,
+                    url,
+                    link_target: link_target(path),
+                    kind,
+                    check: None,
+                });
             } else {
-                warn!("non-unicode path: {}", file_name.to_string_lossy());
+                warn!("non-unicode path: {}", full_url.to_string_lossy());
             }
         } else {
             warn!("path without file name: {}", path.display());
         }
     }
 
+    Ok(entries)
+}
+
+/// What to show as the target of a symlink in a listing.
+///
+/// Absolute targets would reveal where things are on the server, so only
+/// relative ones are shown.
+fn link_target(path: &Path) -> Option<String> {
+    if path.ends_with("..") || !path.symlink_metadata().ok()?.is_symlink() {
+        return None;
+    }
+    match std::fs::read_link(path) {
+        Ok(target) if target.is_relative() => Some(target.to_string_lossy().into_owned()),
+        _ => Some("(link)".to_string()),
+    }
+}
+
+fn make_dir_list_body(
+    url_path: &str,
+    config: &Config,
+    paths: &[PathBuf],
+    totals: &DirTotals,
+    links: Links,
+    kind: Option<&str>,
+    checks: Option<&SumsChecks>,
+) -> Result<String> {
+    let mut entries = dir_entry_links(config, paths, links)?;
+    if let Some(checks) = checks {
+        for entry in &mut entries {
+            entry.check = checks.marks.get(&entry.name).copied();
+        }
+    }
+
+    let mut buf = String::new();
+
+    if links == Links::Absolute {
+        writeln!(buf, "<p><a href='?view=gallery'>gallery view</a></p>")
+            .map_err(Error::WriteInDirList)?;
+        write_kind_filters(&mut buf, kind)?;
+    }
+
+    // Hidden until the script runs, so without JS there's just the listing.
+    writeln!(
+        buf,
+        "<p id='dir-filter' hidden><input type='search' placeholder='filter' \
+         aria-label='Filter entries'> <span></span></p>"
+    )
+    .map_err(Error::WriteInDirList)?;
+
+    write_dir_list(&mut buf, &entries)?;
+
+    if let Some(checks) = checks.filter(|c| !c.missing.is_empty()) {
+        let missing: Vec<String> = checks
+            .missing
+            .iter()
+            .map(|name| handlebars::html_escape(name))
+            .collect();
+        writeln!(
+            buf,
+            "<p class='dir-sums-missing'>✗ missing, but listed in {}: {}</p>",
+            handlebars::html_escape(&checks.sums_files.join(", ")),
+            missing.join(", ")
+        )
+        .map_err(Error::WriteInDirList)?;
+    }
+
+    writeln!(buf, "<p class='dir-totals'>{totals}</p>").map_err(Error::WriteInDirList)?;
+
+    buf.push_str(DIR_FILTER_SCRIPT);
+
+    // Generated index pages are published without the server, and its
+    // assets.
+    let stylesheet = match links {
+        Links::Absolute => crate::assets::stylesheet_url(config),
+        Links::Relative => String::new(),
+    };
+    let cfg = HtmlCfg {
+        kind: PageKind::Listing,
+        stylesheet,
+        crumbs: breadcrumbs(url_path, links),
+        url_path: url_path.to_string(),
+        body: buf,
+        ..HtmlCfg::default()
+    };
+
+    super::render_html(&cfg)
+}
+
+/// Narrows the directory listing to the entries containing the filter text,
+/// showing how many match.
+static DIR_FILTER_SCRIPT: &str = r#"<script>
+  (function () {
+    var filter = document.getElementById("dir-filter");
+    var input = filter.querySelector("input");
+    var count = filter.querySelector("span");
+    var rows = document.querySelectorAll(".dir-list > div");
+    input.addEventListener("input", function () {
+      var needle = input.value.toLowerCase();
+      var shown = 0;
+      rows.forEach(function (row) {
+        var match = row.textContent.toLowerCase().indexOf(needle) !== -1;
+        row.hidden = !match;
+        if (match) shown++;
+      });
+      count.textContent = needle ? shown + " of " + rows.length : "";
+    });
+    filter.hidden = false;
+  })();
+</script>
+"#;
+
+/// Write the links that narrow a listing to one kind of entry, with the one
+/// in use, or "all", not linked.
+fn write_kind_filters(buf: &mut String, kind: Option<&str>) -> Result<()> {
+    let link = |name: &str, href: &str, current: bool| {
+        if current {
+            format!("<strong>{name}</strong>")
+        } else {
+            format!("<a href='{href}'>{name}</a>")
+        }
+    };
+    let mut links = vec![link("all", "?", kind.is_none())];
+    for k in KINDS {
+        links.push(link(k, &format!("?kind={k}"), kind == Some(k)));
+    }
+    writeln!(buf, "<p class='dir-kinds'>kind: {}</p>", links.join(" · "))
+        .map_err(Error::WriteInDirList)
+}
+
+/// Write directory listing entries as a list of links.
+fn write_dir_list(buf: &mut String, links: &[DirEntryLink]) -> Result<()> {
+    writeln!(buf, "<div class='dir-list'>").map_err(Error::WriteInDirList)?;
+
+    for link in links {
+        let target = match &link.link_target {
+            Some(target) => format!(
+                " <span class='link-target'>→ {}</span>",
+                handlebars::html_escape(target)
+            ),
+            None => String::new(),
+        };
+        let check = match link.check {
+            Some(mark) => format!(" <span class='checksum'>{mark}</span>"),
+            None => String::new(),
+        };
+        writeln!(
+            buf,
+            "<div><a href='{}'>{}</a>{} <span class='entry-kind'>{}</span>{}</div>",
+            link.url,
+            handlebars::html_escape(&link.name),
+            target,
+            link.kind,
+            check
+        )
+        .map_err(Error::WriteInDirList)?;
+    }
+
+    writeln!(buf, "</div>").map_err(Error::WriteInDirList)?;
+
+    Ok(())
+}
+
+/// The most images shown on one page of the gallery view.
+const GALLERY_PAGE_SIZE: usize = 200;
+
+#[rustfmt::skip]
+static IMAGE_EXTENSIONS: &[&str] = &[
+    "avif",
+    "gif",
+    "jpeg",
+    "jpg",
+    "png",
+    "svg",
+    "webp",
+];
+
+/// List a directory's images as a grid of lazily-loaded images, a page at a
+/// time, followed by the other entries as a list.
+fn make_gallery_body(
+    req: &Request<Incoming>,
+    config: &Config,
+    paths: &[PathBuf],
+    page: usize,
+) -> Result<String> {
+    let links = dir_entry_links(config, paths, Links::Absolute)?;
+    let (images, others): (Vec<_>, Vec<_>) = links.into_iter().partition(|link| {
+        link.name
+            .rsplit_once('.')
+            .is_some_and(|(_, ext)| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+    });
+
+    let pages = images.len().div_ceil(GALLERY_PAGE_SIZE).max(1);
+    let page = page.clamp(1, pages);
+
+    let mut buf = String::new();
+
+    writeln!(buf, "<p><a href='?'>list view</a></p>").map_err(Error::WriteInDirList)?;
+
+    writeln!(buf, "<div class='gallery'>").map_err(Error::WriteInDirList)?;
+    for image in images
+        .iter()
+        .skip((page - 1) * GALLERY_PAGE_SIZE)
+        .take(GALLERY_PAGE_SIZE)
+    {
+        let name = handlebars::html_escape(&image.name);
+        writeln!(
+            buf,
+            "<a href='{url}'><img src='{url}' alt='{name}' title='{name}' loading='lazy'></a>",
+            url = image.url,
+        )
+        .map_err(Error::WriteInDirList)?;
+    }
     writeln!(buf, "</div>").map_err(Error::WriteInDirList)?;
 
+    if pages > 1 {
+        write!(buf, "<p>").map_err(Error::WriteInDirList)?;
+        if page > 1 {
+            let prev = page - 1;
+            write!(buf, "<a href='?view=gallery&amp;page={prev}'>previous</a> ")
+                .map_err(Error::WriteInDirList)?;
+        }
+        write!(buf, "page {page} of {pages}").map_err(Error::WriteInDirList)?;
+        if page < pages {
+            let next = page + 1;
+            write!(buf, " <a href='?view=gallery&amp;page={next}'>next</a>")
+                .map_err(Error::WriteInDirList)?;
+        }
+        writeln!(buf, "</p>").map_err(Error::WriteInDirList)?;
+    }
+
+    write_dir_list(&mut buf, &others)?;
+
     let cfg = HtmlCfg {
-        title: String::new(),
+        kind: PageKind::Listing,
+        stylesheet: crate::assets::stylesheet_url(config),
+        head: GALLERY_STYLE.to_string(),
+        crumbs: breadcrumbs(req.uri().path(), Links::Absolute),
+        url_path: req.uri().path().to_string(),
         body: buf,
+        ..HtmlCfg::default()
     };
 
     super::render_html(&cfg)
 }
+
+static GALLERY_STYLE: &str = r#"<style type="text/css">
+      .gallery {
+        display: grid;
+        grid-template-columns: repeat(auto-fill, minmax(150px, 1fr));
+        gap: 1ch;
+        margin-bottom: 2em;
+      }
+      .gallery img {
+        width: 100%;
+        height: 150px;
+        object-fit: cover;
+      }
+    </style>"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Fixture};
+
+    const ALL: [Extension; 5] = [
+        Extension::Markdown,
+        Extension::JsonView,
+        Extension::SourceView,
+        Extension::TextTypes,
+        Extension::Listing,
+    ];
+
+    #[test]
+    fn extensions_apply_to_get_and_head_only() {
+        for ext in ALL {
+            assert!(ext.applies_to(&Method::GET), "{ext:?}");
+            assert!(ext.applies_to(&Method::HEAD), "{ext:?}");
+            for method in [Method::PUT, Method::POST, Method::DELETE, Method::PATCH] {
+                assert!(!ext.applies_to(&method), "{ext:?} {method}");
+            }
+        }
+    }
+
+    /// A HEAD response has the headers of the GET response, and no body.
+    async fn assert_head_like_get(addr: std::net::SocketAddr, path: &str) {
+        let get = testing::get(addr, path).await;
+        let head = testing::request(addr, Method::HEAD, path, &[]).await;
+        assert_eq!(get.status, StatusCode::OK, "{path}");
+        assert_eq!(head.status, StatusCode::OK, "{path}");
+        assert!(!get.body.is_empty(), "{path}");
+        assert!(head.body.is_empty(), "{path}");
+        for name in [header::CONTENT_TYPE, header::CONTENT_LENGTH, header::ETAG] {
+            assert_eq!(
+                head.headers.get(&name),
+                get.headers.get(&name),
+                "{path} {name}"
+            );
+        }
+        let content_type = head
+            .headers
+            .get(header::CONTENT_TYPE)
+            .expect("a content type");
+        assert!(
+            content_type.to_str().unwrap().starts_with("text/html"),
+            "{path}"
+        );
+    }
+
+    #[tokio::test]
+    async fn head_markdown_and_listing() {
+        let fixture = Fixture::new(&[("page.md", "# Hello\n"), ("dir/a.txt", "a")]);
+        let addr = testing::start(fixture.config(&["-x"])).await;
+        assert_head_like_get(addr, "/page.md").await;
+        assert_head_like_get(addr, "/dir/").await;
+    }
+
+    #[tokio::test]
+    async fn write_methods_are_refused() {
+        let fixture = Fixture::new(&[("page.md", "# Hello\n"), ("dir/a.txt", "a")]);
+        let addr = testing::start(fixture.config(&["-x"])).await;
+        for path in ["/page.md", "/dir/"] {
+            for method in [Method::PUT, Method::POST, Method::DELETE] {
+                let reply = testing::request(addr, method.clone(), path, &[]).await;
+                assert_eq!(
+                    reply.status,
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "{method} {path}"
+                );
+                assert_eq!(reply.headers[header::ALLOW], "GET, HEAD, OPTIONS");
+            }
+        }
+        assert!(fixture.root().join("page.md").is_file());
+    }
+
+    #[tokio::test]
+    async fn root_listing_has_no_parent_link() {
+        let fixture = Fixture::new(&[("dir/a.txt", "a")]);
+        let addr = testing::start(fixture.config(&["-x"])).await;
+
+        let root = testing::get(addr, "/").await;
+        assert_eq!(root.status, StatusCode::OK);
+        assert!(root.text().contains("href='/dir'"), "{}", root.text());
+        assert!(!root.text().contains("href='/..'"), "{}", root.text());
+
+        let dir = testing::get(addr, "/dir/").await;
+        assert!(dir.text().contains("href='/dir/..'"), "{}", dir.text());
+        for view in ["/?view=gallery", "/?kind=directory"] {
+            let reply = testing::get(addr, view).await;
+            assert_eq!(reply.status, StatusCode::OK, "{view}");
+            let text = reply.text();
+            assert!(
+                !text.contains("'/..'") && !text.contains("\"/..\""),
+                "{view}"
+            );
+        }
+    }
+}
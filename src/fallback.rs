@@ -0,0 +1,120 @@
+//! Finding the file a client meant when no file has exactly the path it
+//! asked for, as with `--normalize-unicode` and `--case-insensitive`.
+//!
+//! Each directory's names are kept, grouped by their folded form, until the
+//! directory's modification time changes.
+
+use crate::ext::encode_url_path;
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::SystemTime;
+use tracing::warn;
+use unicode_normalization::UnicodeNormalization;
+
+/// The most directories to keep names for, before starting over.
+const MAX_CACHED_DIRS: usize = 1024;
+
+/// A directory's names by folded form, as of its modification time.
+type DirNames = (SystemTime, HashMap<String, Vec<String>>);
+
+static NAMES: LazyLock<Mutex<HashMap<(PathBuf, Fold), DirNames>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// How names are compared when looking for the file a client meant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Fold {
+    /// Compare names ignoring case.
+    pub case: bool,
+    /// Compare names in Unicode normalization form C.
+    pub unicode: bool,
+}
+
+impl Fold {
+    /// The form of a name that matching names share.
+    fn key(self, name: &str) -> String {
+        let name: String = if self.unicode {
+            name.nfc().collect()
+        } else {
+            name.to_string()
+        };
+        if self.case {
+            name.to_lowercase()
+        } else {
+            name
+        }
+    }
+}
+
+/// Find the URL path of an existing file or directory under the root that
+/// matches a URL path segment by segment, comparing names as `fold` says.
+///
+/// Segments that exist as requested are kept. Returns `None` if the path
+/// exists as requested, or if any segment matches no name, or more than one.
+pub fn find_url_path(root_dir: &Path, url_path: &str, fold: Fold) -> Option<String> {
+    let mut path = root_dir.to_owned();
+    let mut found = String::new();
+    let mut changed = false;
+
+    for segment in url_path.split('/').filter(|s| !s.is_empty()) {
+        let requested = percent_decode_str(segment).decode_utf8().ok()?;
+        // An encoded "/" isn't a name, and serving it is refused anyway.
+        if requested.contains('/') {
+            return None;
+        }
+
+        let name = if path.join(&*requested).symlink_metadata().is_ok() {
+            requested.into_owned()
+        } else {
+            changed = true;
+            find_name(&path, &requested, fold)?
+        };
+
+        found.push('/');
+        found.push_str(&encode_url_path(Path::new(&name))?);
+        path.push(name);
+    }
+
+    if !changed {
+        return None;
+    }
+    if url_path.ends_with('/') {
+        found.push('/');
+    }
+    Some(found)
+}
+
+/// Find the one name in a directory matching a requested name.
+fn find_name(dir: &Path, requested: &str, fold: Fold) -> Option<String> {
+    let modified = std::fs::metadata(dir).ok()?.modified().ok()?;
+    let key = (dir.to_owned(), fold);
+
+    let mut cache = NAMES.lock().unwrap_or_else(PoisonError::into_inner);
+    if !matches!(cache.get(&key), Some((cached, _)) if *cached == modified) {
+        let mut names: HashMap<String, Vec<String>> = HashMap::new();
+        for entry in std::fs::read_dir(dir).ok()?.flatten() {
+            if let Ok(name) = entry.file_name().into_string() {
+                names.entry(fold.key(&name)).or_default().push(name);
+            }
+        }
+        if cache.len() >= MAX_CACHED_DIRS {
+            cache.clear();
+        }
+        cache.insert(key.clone(), (modified, names));
+    }
+
+    let (_, names) = cache.get(&key)?;
+    match names.get(&fold.key(requested))?.as_slice() {
+        [name] => Some(name.clone()),
+        matches => {
+            warn!(
+                "{:?} is ambiguous in {}, matching {:?}",
+                requested,
+                dir.display(),
+                matches
+            );
+            None
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Custom response headers from a Netlify-style `_headers` file.
+//!
+//! A line that isn't indented is a URL path, which may contain `*` to match
+//! anything. The indented `Name: Value` lines after it are the headers to add
+//! to responses for matching paths. Blank lines and lines starting with `#`
+//! are ignored.
+
+use crate::error::{Error, Result};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use std::fmt;
+use std::path::Path;
+
+/// The rules, shortest path pattern first.
+#[derive(Clone, Debug, Default)]
+pub struct HeaderRules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    pattern: String,
+    headers: HeaderMap,
+}
+
+impl HeaderRules {
+    /// Read and parse a headers file.
+    pub fn load(path: &Path) -> Result<HeaderRules> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("can't read {}: {e}", path.display())))?;
+        HeaderRules::parse(&text, path)
+    }
+
+    /// Parse the text of a headers file, naming `path` and the line number in
+    /// errors.
+    fn parse(text: &str, path: &Path) -> Result<HeaderRules> {
+        let mut rules: Vec<Rule> = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let err = |msg: &str| Error::Config(format!("{}:{}: {msg}", path.display(), i + 1));
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+
+            if !line.starts_with(char::is_whitespace) {
+                if !trimmed.starts_with('/') {
+                    return Err(err("expected a path starting with /"));
+                }
+                rules.push(Rule {
+                    pattern: trimmed.to_string(),
+                    headers: HeaderMap::new(),
+                });
+                continue;
+            }
+
+            let Some(rule) = rules.last_mut() else {
+                return Err(err("header before any path"));
+            };
+            let Some((name, value)) = trimmed.split_once(':') else {
+                return Err(err("expected Name: Value"));
+            };
+            let name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| err("invalid header name"))?;
+            let value =
+                HeaderValue::from_str(value.trim()).map_err(|_| err("invalid header value"))?;
+            rule.headers.append(name, value);
+        }
+
+        // Applying longer patterns later lets them override shorter ones.
+        rules.sort_by_key(|rule| rule.pattern.len());
+        Ok(HeaderRules { rules })
+    }
+
+    /// Add the headers for a request path to a response's headers.
+    ///
+    /// Where several patterns set the same header, the longest wins.
+    pub fn apply(&self, path: &str, headers: &mut HeaderMap) {
+        for rule in self
+            .rules
+            .iter()
+            .filter(|rule| matches(&rule.pattern, path))
+        {
+            for name in rule.headers.keys() {
+                headers.remove(name);
+                for value in rule.headers.get_all(name) {
+                    headers.append(name.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for HeaderRules {
+    /// The rules in the headers file format, in the order they're applied.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for rule in &self.rules {
+            writeln!(f, "{}", rule.pattern)?;
+            for (name, value) in &rule.headers {
+                writeln!(
+                    f,
+                    "  {}: {}",
+                    name,
+                    String::from_utf8_lossy(value.as_bytes())
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a path matches a pattern, where `*` matches any run of characters.
+fn matches(pattern: &str, path: &str) -> bool {
+    let mut parts: Vec<&str> = pattern.split('*').collect();
+    let last = parts.pop().unwrap_or_default();
+    if parts.is_empty() {
+        return path == last;
+    }
+
+    let Some(mut rest) = path.strip_prefix(parts[0]) else {
+        return false;
+    };
+    for part in &parts[1..] {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
@@ -0,0 +1,65 @@
+//! `--allow` and `--deny`: which client addresses may connect at all,
+//! decided as soon as a connection is accepted.
+//!
+//! The most specific network containing the address decides, and when an
+//! `--allow` and a `--deny` network are as specific, the `--deny` does. An
+//! address in no network is allowed, unless there's any `--allow`, which
+//! makes the allowed networks the only ones. IPv4 addresses mapped into IPv6
+//! count as IPv4.
+//!
+//! This is about the connection's peer, so with `--trust-proxy` it's the
+//! proxy's address that's checked, not the forwarded one.
+
+use crate::config::Config;
+use crate::proxy::Cidr;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long after a rejected address is logged it isn't logged again, so a
+/// client retrying in a loop doesn't flood the log.
+const REJECTION_LOG_WINDOW: Duration = Duration::from_secs(60);
+
+/// When each recently rejected address was last logged.
+static LOGGED_REJECTIONS: LazyLock<Mutex<HashMap<IpAddr, Instant>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Whether a client address may connect.
+pub fn is_allowed(config: &Config, ip: IpAddr) -> bool {
+    if config.allow.is_empty() && config.deny.is_empty() {
+        return true;
+    }
+
+    let most_specific = |rules: &[Cidr]| {
+        rules
+            .iter()
+            .filter(|cidr| cidr.contains(ip))
+            .map(Cidr::prefix_len)
+            .max()
+    };
+    match (most_specific(&config.allow), most_specific(&config.deny)) {
+        (Some(allow), Some(deny)) => allow > deny,
+        (Some(_), None) => true,
+        (None, Some(_)) => false,
+        (None, None) => config.allow.is_empty(),
+    }
+}
+
+/// Log that an address was rejected, unless it was recently.
+pub fn log_rejection(ip: IpAddr) {
+    let now = Instant::now();
+    let mut logged = LOGGED_REJECTIONS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if logged
+        .get(&ip)
+        .is_some_and(|at| now.duration_since(*at) < REJECTION_LOG_WINDOW)
+    {
+        return;
+    }
+    logged.retain(|_, at| now.duration_since(*at) < REJECTION_LOG_WINDOW);
+    logged.insert(ip, now);
+    warn!("rejected connection from {}", ip);
+}
@@ -0,0 +1,68 @@
+//! Tracking requests on a connection, to close persistent connections that
+//! sit idle for `--keepalive-timeout`.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The requests on one connection.
+pub struct Activity {
+    /// Requests started so far.
+    requests: AtomicU64,
+    /// Requests being responded to.
+    active: AtomicUsize,
+    /// When the connection was last busy.
+    last: Mutex<Instant>,
+}
+
+/// A request in progress, which keeps its connection busy until dropped.
+pub struct Request {
+    activity: Arc<Activity>,
+}
+
+impl Activity {
+    pub fn new() -> Arc<Activity> {
+        Arc::new(Activity {
+            requests: AtomicU64::new(0),
+            active: AtomicUsize::new(0),
+            last: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Note a request starting, returning it and whether an earlier request
+    /// used the connection too.
+    pub fn start(self: &Arc<Activity>) -> (Request, bool) {
+        let reused = self.requests.fetch_add(1, Ordering::Relaxed) > 0;
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let request = Request {
+            activity: self.clone(),
+        };
+        (request, reused)
+    }
+
+    /// Wait until the connection has had no requests in progress for
+    /// `timeout`.
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let last = *self.last.lock().unwrap_or_else(PoisonError::into_inner);
+            let busy = self.active.load(Ordering::Relaxed) > 0;
+            if !busy && last.elapsed() >= timeout {
+                return;
+            }
+            let from = if busy { Instant::now() } else { last };
+            tokio::time::sleep_until(from + timeout).await;
+        }
+    }
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        *self
+            .activity
+            .last
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Instant::now();
+        self.activity.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
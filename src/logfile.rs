@@ -0,0 +1,206 @@
+//! Log output to stderr, a file, or both, with size-based rotation of the
+//! file.
+//!
+//! The logger is set up before the configuration is read, so that problems
+//! reading it can be logged, and so it always writes through `LogWriter`.
+//! Until `open` is called that only goes to stderr.
+//!
+//! Colors are stripped from stderr when `console` says they're off, and
+//! from the file always.
+//!
+//! With `--status-line`, the last line on stderr is a status line that's
+//! redrawn in place. Log lines are written over it, and it's drawn again
+//! below them.
+
+use crate::console;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, PoisonError};
+
+/// Where log lines go. Logging is synchronous, so a plain mutex keeps lines
+/// from concurrent requests whole, and rotation from racing with writes.
+static OUTPUT: LazyLock<Mutex<Output>> = LazyLock::new(|| {
+    Mutex::new(Output {
+        to_stderr: true,
+        file: None,
+        recent_errors: VecDeque::new(),
+        status: None,
+    })
+});
+
+/// The most warnings and errors kept for `recent_errors`.
+const MAX_RECENT_ERRORS: usize = 100;
+
+struct Output {
+    to_stderr: bool,
+    file: Option<RotatingFile>,
+    /// The latest warning and error lines, oldest first.
+    recent_errors: VecDeque<String>,
+    /// The status line shown below the log on stderr.
+    status: Option<String>,
+}
+
+/// Return to the start of the line and erase it.
+const CLEAR_LINE: &str = "\r\x1b[2K";
+
+/// The writer for the logger.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut output = OUTPUT.lock().unwrap_or_else(PoisonError::into_inner);
+        let line = strip_ansi(buf);
+        if output.to_stderr {
+            let mut stderr = io::stderr().lock();
+            if output.status.is_some() {
+                stderr.write_all(CLEAR_LINE.as_bytes())?;
+            }
+            stderr.write_all(if console::is_on() { buf } else { &line })?;
+            if let Some(status) = &output.status {
+                stderr.write_all(status.as_bytes())?;
+            }
+        }
+        let level = line.trim_ascii_start();
+        if level.starts_with(b"ERROR") || level.starts_with(b"WARN") {
+            if output.recent_errors.len() == MAX_RECENT_ERRORS {
+                output.recent_errors.pop_front();
+            }
+            let text = String::from_utf8_lossy(&line).trim_end().to_string();
+            output.recent_errors.push_back(text);
+        }
+        if let Some(file) = &mut output.file {
+            file.write_line(&line)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut output = OUTPUT.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(file) = &mut output.file {
+            file.file.flush()?;
+        }
+        io::stderr().flush()
+    }
+}
+
+/// Start logging to a file, and to stderr too if `to_stderr` is set.
+///
+/// With `rotate_size`, the file is rotated before it grows past that many
+/// bytes, keeping `keep` old files as `<path>.1` (the newest) to
+/// `<path>.<keep>`.
+pub fn open(path: &Path, to_stderr: bool, rotate_size: Option<u64>, keep: usize) -> io::Result<()> {
+    let file = RotatingFile::open(path.to_owned(), rotate_size, keep)?;
+    let mut output = OUTPUT.lock().unwrap_or_else(PoisonError::into_inner);
+    output.to_stderr = to_stderr;
+    output.file = Some(file);
+    Ok(())
+}
+
+/// Show a status line below the log on stderr, replacing the last one.
+pub fn set_status(status: &str) {
+    let mut output = OUTPUT.lock().unwrap_or_else(PoisonError::into_inner);
+    if !output.to_stderr {
+        return;
+    }
+    let mut stderr = io::stderr().lock();
+    let _ = write!(stderr, "{CLEAR_LINE}{status}");
+    let _ = stderr.flush();
+    output.status = Some(status.to_string());
+}
+
+/// Erase the status line, so that nothing more is drawn over it.
+pub fn clear_status() {
+    let mut output = OUTPUT.lock().unwrap_or_else(PoisonError::into_inner);
+    if output.status.take().is_some() {
+        let _ = write!(io::stderr(), "{CLEAR_LINE}");
+    }
+}
+
+/// The latest warnings and errors logged, oldest first.
+pub fn recent_errors() -> Vec<String> {
+    let output = OUTPUT.lock().unwrap_or_else(PoisonError::into_inner);
+    output.recent_errors.iter().cloned().collect()
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    rotate_size: Option<u64>,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, rotate_size: Option<u64>, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            rotate_size,
+            keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let len = line.len() as u64;
+        if let Some(max) = self.rotate_size {
+            if self.size > 0 && self.size + len > max {
+                self.rotate()?;
+            }
+        }
+        self.file.write_all(line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shift the old files along, dropping the oldest, and start a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{n}"));
+            PathBuf::from(path)
+        };
+
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = numbered(n);
+                if from.exists() {
+                    fs::rename(&from, numbered(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Remove the terminal color codes the logger adds, which don't belong in a
+/// file.
+fn strip_ansi(buf: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len());
+    let mut bytes = buf.iter().copied().peekable();
+    while let Some(b) = bytes.next() {
+        if b == 0x1b && bytes.peek() == Some(&b'[') {
+            // Skip to the end of the control sequence.
+            for b in bytes.by_ref().skip(1) {
+                if (0x40..=0x7e).contains(&b) {
+                    break;
+                }
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
@@ -1,45 +1,113 @@
 //! A simple HTTP server, for learning and local development.
 
-use clap::Parser;
-use env_logger::{Builder, Env};
+use arc_swap::ArcSwap;
+use conditional::{Outcome, Validators};
+use config::{ColorChoice, Config, LogTo, SharedConfig, TrailingSlash};
 use error::{Error, Result};
-use futures::TryStreamExt;
-use handlebars::Handlebars;
+use fallback::Fold;
+use futures::future::BoxFuture;
+use futures::{future, stream, FutureExt, TryStreamExt};
+use http::uri::{Authority, PathAndQuery};
 use http::{StatusCode, Uri};
 use http_body_util::{combinators::BoxBody, BodyExt};
-use http_body_util::{Empty, StreamBody};
+use http_body_util::{Empty, Full, StreamBody};
 use hyper::body::{Bytes, Frame, Incoming};
-use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::Method;
 use hyper::{Request, Response};
 use hyper_util::rt::TokioIo;
-use log::{debug, error, info, trace, warn};
 use percent_encoding::percent_decode_str;
+use range::Ranges;
+use reason::Reason;
 use serde::Serialize;
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::error::Error as StdError;
-use std::io;
-use std::net::SocketAddr;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{self, IsTerminal, SeekFrom};
+use std::net::{IpAddr, SocketAddr};
+use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite};
 use tokio::net::TcpListener;
 use tokio::signal;
 use tokio_util::io::ReaderStream;
-
+use tracing::level_filters::LevelFilter;
+use tracing::{
+    debug, enabled, error, field, info, info_span, trace, warn, Instrument, Level, Span,
+};
+use tracing_subscriber::EnvFilter;
+use vfs::Vfs;
+use vhost::VHost;
+
+mod accesslog;
+mod acme;
+mod admin;
+mod archive;
+mod assets;
+mod autoexit;
+mod conditional;
+mod config;
+mod console;
+mod digest;
+#[cfg(feature = "embed")]
+mod embed;
 mod error;
+mod errorpages;
 // Developer extensions. These are contained in their own module so that the
 // principle HTTP server behavior is not obscured.
 mod ext;
+mod fallback;
+mod headers;
+mod ipfilter;
+mod keepalive;
+mod logfile;
+mod maintenance;
+mod policy;
+mod proxy;
+mod range;
+mod ratelimit;
+mod reason;
+mod redirects;
+mod rewrites;
+mod snapshot;
+mod statcache;
+mod stats;
+mod templates;
+#[cfg(test)]
+mod testing;
+mod vfs;
+mod vhost;
+mod webdav;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     // Set up error handling immediately
     tokio::select! {
-        _ = signal::ctrl_c() => {}
-        o = run() => {
-            if let Err(e) = o {
+        _ = signal::ctrl_c() => {
+            logfile::clear_status();
+            stats::log_summary();
+            ExitCode::SUCCESS
+        }
+        o = run() => match o {
+            Ok(Some(reason)) => {
+                reason.log();
+                logfile::clear_status();
+                stats::log_summary();
+                reason.exit_code()
+            }
+            Ok(None) => ExitCode::SUCCESS,
+            Err(e) => {
                 log_error_chain(&e);
+                ExitCode::FAILURE
             }
         }
     }
@@ -47,76 +115,568 @@ async fn main() {
 
 /// Basic error reporting, including the "cause chain". This is used both by the
 /// top-level error reporting and to report internal server errors.
-fn log_error_chain(mut e: &dyn StdError) {
-    error!("error: {}", e);
-    while let Some(source) = e.source() {
-        error!("caused by: {}", source);
-        e = source;
+///
+/// The error is one event, with the chain as a field, so it stays in one
+/// piece under the request it happened in.
+fn log_error_chain(e: &dyn StdError) {
+    let mut causes = Vec::new();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        causes.push(cause.to_string());
+        source = cause.source();
+    }
+    if causes.is_empty() {
+        error!("error: {}", e);
+    } else {
+        let caused_by = causes.join(": ");
+        error!(caused_by = %caused_by, "error: {}", e);
+    }
+}
+
+/// How long after an error is logged the same error is only warned about.
+const REPEATED_ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+/// When each recent error was last logged in full, by its message.
+static LOGGED_ERRORS: LazyLock<Mutex<HashMap<String, Instant>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Log an error chain, unless the same error was logged recently, in which
+/// case just warn with its message. This keeps an outage that fails every
+/// request from flooding the log.
+fn log_error_chain_once(e: &dyn StdError) {
+    let message = e.to_string();
+    let now = Instant::now();
+    let mut logged = LOGGED_ERRORS.lock().unwrap_or_else(PoisonError::into_inner);
+    match logged.get(&message) {
+        Some(at) if now.duration_since(*at) < REPEATED_ERROR_WINDOW => {
+            warn!("error again: {}", message);
+        }
+        _ => {
+            logged.retain(|_, at| now.duration_since(*at) < REPEATED_ERROR_WINDOW);
+            logged.insert(message, now);
+            log_error_chain(e);
+        }
     }
 }
 
-/// The configuration object, parsed from command line options.
-#[derive(Clone, Parser)]
-#[command(about = "A basic HTTP file server")]
-pub struct Config {
-    /// The IP:PORT combination.
-    #[arg(
-        name = "ADDR",
-        short = 'a',
-        long = "addr",
-        default_value = "127.0.0.1:4000"
-    )]
-    addr: SocketAddr,
+/// What a listener's connections are for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Service {
+    /// Serving files.
+    Files,
+    /// The admin endpoint.
+    Admin,
+    /// Refusing a client `--allow` and `--deny` keep out, with
+    /// `--deny-with-403`.
+    Forbidden,
+}
 
-    /// The root directory for serving files.
-    #[structopt(name = "ROOT", default_value = ".")]
-    root_dir: PathBuf,
+/// The address of a connected client, as shown in the access log.
+#[derive(Clone, Debug)]
+enum Peer {
+    Tcp(SocketAddr),
+    /// A Unix domain socket client, with its credentials if the platform
+    /// provides them.
+    Unix(Option<String>),
+    /// A client behind a trusted proxy, from `X-Forwarded-For`.
+    Forwarded(IpAddr),
+}
 
-    /// Enable developer extensions.
-    #[structopt(short = 'x')]
-    use_extensions: bool,
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{addr}"),
+            Peer::Unix(Some(cred)) => write!(f, "uds:{cred}"),
+            Peer::Unix(None) => write!(f, "uds"),
+            Peer::Forwarded(ip) => write!(f, "{ip}"),
+        }
+    }
 }
 
-async fn run() -> Result<()> {
+/// Run the server until it fails or stops on its own, returning why it
+/// stopped if it did.
+async fn run() -> Result<Option<autoexit::Reason>> {
     // Initialize logging, and log the "info" level for this crate only, unless
-    // the environment contains `RUST_LOG`.
-    let env = Env::new().default_filter_or("basic_http_server=info");
-    Builder::from_env(env)
-        .format_target(false)
-        .format_timestamp(None)
+    // the environment contains `RUST_LOG`. Events are shown in the spans they
+    // happen in, the connection and the request, and records from crates
+    // that use `log` are turned into events, so they are too. Output goes
+    // through our own writer so that `--log-file` can redirect it later, and
+    // it strips the colors when `--color` says not to use them.
+    console::set(ColorChoice::Auto);
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("basic_http_server=info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .without_time()
+        .with_ansi(true)
+        .with_writer(|| logfile::LogWriter)
         .init();
 
-    // Create the configuration from the command line arguments. It
-    // includes the IP address and port to listen on and the path to use
-    // as the HTTP server's root directory.
-    let config = Config::parse();
+    // Create the configuration from the command line arguments and the
+    // optional configuration file. It includes the IP address and port to
+    // listen on and the path to use as the HTTP server's root directory.
+    let config = Config::load()?;
+    console::set(config.color);
+    templates::install(config.templates.clone());
+
+    if let Some(path) = &config.log_file {
+        let to_stderr = config.log_to == LogTo::Both;
+        logfile::open(path, to_stderr, config.log_rotate_size, config.log_keep)?;
+    }
+
+    if config.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(None);
+    }
+
+    if config.print_text_types {
+        print!("{}", config.text_types);
+        return Ok(None);
+    }
+
+    if config.print_routes {
+        print!("{}", routes_table(&config));
+        return Ok(None);
+    }
+
+    if let Some(path) = &config.print_mime {
+        print!("{}", mime_decision(&config, path));
+        return Ok(None);
+    }
+
+    if let Some(out_dir) = &config.generate_index {
+        return snapshot::generate_index(&config, out_dir)
+            .await
+            .map(|()| None);
+    }
 
     // Display the configuration to be helpful
     info!("basic-http-server {}", env!("CARGO_PKG_VERSION"));
-    info!("addr: http://{}", config.addr);
-    info!("root dir: {}", config.root_dir.display());
+    if let Some(addr) = config.tcp_addr() {
+        info!("addr: {}", console::highlight(&format!("http://{addr}")));
+    }
+    if let Some(uds) = &config.uds {
+        info!("unix socket: {}", uds.display());
+    }
+    match &config.vfs {
+        Some(vfs) => info!("root: {}", vfs.describe()),
+        None => info!("root dir: {}", config.root_dir.display()),
+    }
+    for vhost in &config.vhost {
+        info!("vhost: {}", vhost);
+    }
     info!("extensions: {}", config.use_extensions);
+    if config.no_cache {
+        info!("caching: disabled");
+    } else if let Some(secs) = config.cache {
+        info!("caching: {} seconds", secs);
+    }
+
+    let tcp_addr = config.tcp_addr();
+    let bind_first = config.bind_first;
+    let workers = config.workers;
+    let uds = config.uds.clone();
+    let uds_mode = config.uds_mode;
+    let watched_files = config.watched_files();
+    let stats_interval = config.stats_interval;
+    let status_line = config.status_line && show_status_line(&config);
+    let admin_addr = config.admin_addr;
+    let exit_on_root_removed = config.exit_on_root_removed;
+    let idle_timeout = config.idle_timeout;
+    let root_dir = config.root_dir.clone();
+
+    // The admin endpoint can change anything, so it's local only by default.
+    if let Some(addr) = admin_addr {
+        if !addr.ip().to_canonical().is_loopback() && !config.admin_allow_remote {
+            return Err(Error::Config(format!(
+                "--admin-addr {addr} is not a loopback address; \
+                 use --admin-allow-remote to allow it"
+            )));
+        }
+    }
+
+    stats::start();
+    maintenance::set(config.maintenance);
+
+    // The configuration can be swapped out at runtime. Every request takes a
+    // snapshot of it when it arrives.
+    let config: SharedConfig = Arc::new(ArcSwap::from_pointee(config));
+
+    // Bind every requested listener and run an accept loop for each, along
+    // with the configuration reloaders. These run until an error occurs, or
+    // until `main` drops this future on ctrl-c.
+    let mut tasks: Vec<BoxFuture<'static, Result<()>>> = Vec::new();
+
+    if let Some(addr) = tcp_addr {
+        for addr in Config::resolve_tcp_addr(&addr, bind_first).await? {
+            let listeners = bind_tcp(addr, workers).await?;
+            info!(
+                "listening on {} with {} accept loops",
+                listeners[0].local_addr()?,
+                listeners.len()
+            );
+            for listener in listeners {
+                tasks.push(Box::pin(accept_tcp(
+                    listener,
+                    config.clone(),
+                    Service::Files,
+                )));
+            }
+        }
+    }
+
+    if let Some(addr) = admin_addr {
+        let listener = TcpListener::bind(addr).await?;
+        info!("admin endpoint on http://{}", listener.local_addr()?);
+        tasks.push(Box::pin(accept_tcp(
+            listener,
+            config.clone(),
+            Service::Admin,
+        )));
+    }
+
+    if let Some(path) = &uds {
+        tasks.push(bind_uds(path, uds_mode, config.clone())?);
+    }
+
+    #[cfg(unix)]
+    tasks.push(Box::pin(config::reload_on_sighup(config.clone())));
+
+    #[cfg(unix)]
+    tasks.push(Box::pin(maintenance::toggle_on_sigusr1()));
+
+    if !watched_files.is_empty() {
+        tasks.push(Box::pin(config::reload_on_change(
+            config.clone(),
+            watched_files,
+        )));
+    }
+
+    if let Some(secs) = stats_interval {
+        tasks.push(Box::pin(stats::log_periodically(secs)));
+    }
+
+    if status_line {
+        tasks.push(Box::pin(stats::show_status_line()));
+    }
+
+    // Stop without being asked with `--exit-on-root-removed` or
+    // `--idle-timeout`.
+    let mut exits: Vec<BoxFuture<'static, Result<autoexit::Reason>>> = Vec::new();
+    if exit_on_root_removed {
+        exits.push(Box::pin(autoexit::root_removed(root_dir)));
+    }
+    if let Some(secs) = idle_timeout {
+        let timeout = Duration::from_secs(secs);
+        exits.push(Box::pin(async move { Ok(autoexit::idle(timeout).await) }));
+    }
+    let exit = async move {
+        if exits.is_empty() {
+            return future::pending().await;
+        }
+        future::select_all(exits).await.0
+    };
+
+    tokio::select! {
+        result = future::try_join_all(tasks) => result.map(|_| None),
+        reason = exit => reason.map(Some),
+    }
+}
 
-    // Create a Hyper Server, binding to an address, and use
-    // our service builder.
-    let listener = TcpListener::bind(&config.addr).await?;
+/// Whether `--status-line` can be shown: it's redrawn in place, so only on a
+/// terminal, and it would be lost among more detailed logging.
+fn show_status_line(config: &Config) -> bool {
+    let to_stderr = config.log_file.is_none() || config.log_to == LogTo::Both;
+    let reason = if !to_stderr || !io::stderr().is_terminal() {
+        "stderr is not a terminal"
+    } else if LevelFilter::current() > LevelFilter::INFO {
+        "logging is more verbose than info"
+    } else {
+        return true;
+    };
+    info!("status line: off, as {}", reason);
+    false
+}
+
+/// Bind TCP listeners to an address, one per worker.
+///
+/// More than one worker needs `SO_REUSEPORT`, so that the listeners can share
+/// the address and the kernel spreads new connections among them.
+async fn bind_tcp(addr: SocketAddr, workers: usize) -> Result<Vec<TcpListener>> {
+    if workers <= 1 {
+        return Ok(vec![TcpListener::bind(addr).await?]);
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    {
+        (0..workers).map(|_| bind_tcp_reuseport(addr)).collect()
+    }
+
+    #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+    {
+        warn!("--workers needs SO_REUSEPORT, which this platform lacks; using one listener");
+        Ok(vec![TcpListener::bind(addr).await?])
+    }
+}
+
+/// Bind a TCP listener that can share its address with other listeners.
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn bind_tcp_reuseport(addr: SocketAddr) -> Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Accept TCP connections forever, serving each on its own task.
+async fn accept_tcp(listener: TcpListener, config: SharedConfig, service: Service) -> Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        // Keep out clients `--allow` and `--deny` refuse, by closing the
+        // connection or answering 403.
+        let allowed = service != Service::Files || ipfilter::is_allowed(&config.load(), addr.ip());
+        let service = if allowed {
+            service
+        } else {
+            ipfilter::log_rejection(addr.ip());
+            if !config.load().deny_with_403 {
+                continue;
+            }
+            Service::Forbidden
+        };
+        // Don't hold back small responses waiting for more to send.
+        if !config.load().no_nodelay {
+            if let Err(e) = stream.set_nodelay(true) {
+                warn!("failed to set TCP_NODELAY: {}", e);
+            }
+        }
+        spawn_connection(config.clone(), stream, Peer::Tcp(addr), service);
+    }
+}
+
+/// Bind a Unix domain socket, returning its accept loop.
+///
+/// A socket file left behind by a previous run is removed first, but any
+/// other kind of file at that path is an error. The socket file is removed
+/// again when the accept loop is dropped.
+#[cfg(unix)]
+fn bind_uds(
+    path: &Path,
+    mode: Option<u32>,
+    config: SharedConfig,
+) -> Result<BoxFuture<'static, Result<()>>> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use tokio::net::UnixListener;
+
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(Error::UdsPathNotSocket);
+        }
+        debug!("removing stale socket {}", path.display());
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let guard = UdsGuard(path.to_owned());
+
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
 
+    Ok(Box::pin(accept_uds(listener, guard, config)))
+}
+
+/// Accept Unix domain socket connections forever, serving each on its own
+/// task. The guard is held for as long as we're accepting connections.
+#[cfg(unix)]
+async fn accept_uds(
+    listener: tokio::net::UnixListener,
+    _guard: UdsGuard,
+    config: SharedConfig,
+) -> Result<()> {
     loop {
         let (stream, _) = listener.accept().await?;
+        let cred = stream.peer_cred().ok().map(|c| match c.pid() {
+            Some(pid) => format!("uid={},pid={}", c.uid(), pid),
+            None => format!("uid={}", c.uid()),
+        });
+        spawn_connection(config.clone(), stream, Peer::Unix(cred), Service::Files);
+    }
+}
+
+#[cfg(not(unix))]
+fn bind_uds(
+    _path: &Path,
+    _mode: Option<u32>,
+    _config: SharedConfig,
+) -> Result<BoxFuture<'static, Result<()>>> {
+    Err(Error::UdsUnsupported)
+}
+
+/// Removes the Unix domain socket file on drop, which is how it gets cleaned
+/// up on graceful shutdown.
+#[cfg(unix)]
+struct UdsGuard(PathBuf);
+
+#[cfg(unix)]
+impl Drop for UdsGuard {
+    fn drop(&mut self) {
+        debug!("removing socket {}", self.0.display());
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("failed to remove socket {}: {}", self.0.display(), e);
+        }
+    }
+}
 
-        let io = TokioIo::new(stream);
+/// Serve HTTP on an accepted connection, on a new task.
+fn spawn_connection<S>(config: SharedConfig, stream: S, peer: Peer, service: Service)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = TokioIo::new(stream);
+
+    // Explicit limits on the request head, so that hyper responds to
+    // oversized requests with a proper error rather than buffering them.
+    //
+    // hyper answers `Expect: 100-continue` by itself, and only when the body
+    // is first read; see `unsupported_reason`.
+    let mut builder = http1::Builder::new();
+    let idle_timeout;
+    {
+        let config = config.load();
+        builder
+            .max_buf_size(config.max_header_size)
+            .max_headers(config.max_headers)
+            .keep_alive(!config.no_keepalive);
+        idle_timeout = config.keepalive_timeout.map(Duration::from_secs);
+    }
 
-        let config = config.clone();
-        let service = service_fn(move |req| {
+    let activity = keepalive::Activity::new();
+    let conn_peer = peer.clone();
+    let service = service_fn({
+        let activity = activity.clone();
+        move |req: Request<Incoming>| {
             let config = config.clone();
-            async move { Ok::<_, Error>(serve(config, req).await) }
-        });
+            let peer = peer.clone();
+            let (request, reused) = activity.start();
+            let (method, uri) = (req.method().clone(), req.uri().clone());
+            // The status and duration are recorded once there's a response.
+            let span = info_span!(
+                "request",
+                method = %method,
+                path = %truncate_for_log(uri.path()),
+                status = field::Empty,
+                duration = field::Empty,
+            );
+            async move {
+                // A panic making the response is answered with a 500 rather
+                // than dropping the connection.
+                let resp = async move {
+                    match service {
+                        Service::Files => serve(config.load_full(), peer, reused, req).await,
+                        Service::Admin => admin::serve(&config, req).await,
+                        Service::Forbidden => transform_error(
+                            make_error_response_for_reason(Reason::ClientDenied, None),
+                            None,
+                        ),
+                    }
+                };
+                let resp = match AssertUnwindSafe(resp).catch_unwind().await {
+                    Ok(resp) => resp,
+                    Err(panic) => panic_response(&method, &uri, &*panic),
+                };
+                drop(request);
+                Ok::<_, Error>(resp)
+            }
+            .instrument(span)
+        }
+    });
+
+    let conn = async move {
+        // Only file serving is counted in the statistics.
+        let _connection = (service == Service::Files).then(stats::connection);
+        let conn = builder.serve_connection(io, service);
+        // hyper keeps idle connections open forever, so close them here.
+        let result = match idle_timeout {
+            Some(timeout) => {
+                tokio::pin!(conn);
+                tokio::select! {
+                    result = conn.as_mut() => result,
+                    () = activity.idle(timeout) => {
+                        debug!("closing connection idle for {:?}", timeout);
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                }
+            }
+            None => conn.await,
+        };
+        match result {
+            Err(err) if is_client_abort(&err) => {
+                debug!("client closed the connection: {}", err);
+            }
+            Err(err) => error!("Error serving connection: {:?}", err),
+            Ok(()) => {}
+        }
+    };
 
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                error!("Error serving connection: {:?}", err);
+    // A panic after the response has started, like while sending its body,
+    // can only close the connection, but it's still logged.
+    let span = info_span!("conn", peer = %conn_peer);
+    tokio::task::spawn(
+        async move {
+            if let Err(panic) = AssertUnwindSafe(conn).catch_unwind().await {
+                error!(
+                    "panic serving connection from {}: {}{}",
+                    conn_peer,
+                    panic_message(&*panic),
+                    BACKTRACE_HINT
+                );
+                stats::record_panic();
             }
-        });
+        }
+        .instrument(span),
+    );
+}
+
+/// How to see where a panic happened, as the panic hook prints the
+/// backtrace separately.
+const BACKTRACE_HINT: &str = " (run with RUST_BACKTRACE=1 for a backtrace)";
+
+/// Log a panic while making a response, and answer with a 500 instead.
+fn panic_response(
+    method: &Method,
+    uri: &Uri,
+    panic: &(dyn Any + Send),
+) -> Response<BoxBody<Bytes, Error>> {
+    error!(
+        "panic serving {} {}: {}{}",
+        method,
+        truncate_for_log(&uri.to_string()),
+        panic_message(panic),
+        BACKTRACE_HINT
+    );
+    stats::record_panic();
+    transform_error(
+        make_error_response_from_code(StatusCode::INTERNAL_SERVER_ERROR, None),
+        None,
+    )
+}
+
+/// The message a panic was raised with, if it has one.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "(no message)"
     }
 }
 
@@ -124,219 +684,1691 @@ async fn run() -> Result<()> {
 ///
 /// Errors are turned into an appropriate HTTP error response, and never
 /// propagated upward for hyper to deal with.
-async fn serve(config: Config, req: Request<Incoming>) -> Response<BoxBody<Bytes, Error>> {
+async fn serve(
+    config: Arc<Config>,
+    peer: Peer,
+    reused: bool,
+    mut req: Request<Incoming>,
+) -> Response<BoxBody<Bytes, Error>> {
+    let start = Instant::now();
+
+    // Normalize the path first, so that everything after this, including the
+    // access log, sees the canonical path.
+    let normalized =
+        origin_form_request(&config, &mut req).and_then(|()| normalize_request_uri(&mut req));
+
+    // Find the real client, and let extensions see it too.
+    let peer = client_peer(&config, peer, req.headers());
+    req.extensions_mut().insert(peer.clone());
+
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let error_path = error_page_path(&config, &uri);
+    let dump_level = header_dump_level(&config);
+    let req_headers = dump_level.map(|_| req.headers().clone());
+    let accept = req.headers().get(header::ACCEPT).cloned();
+    let accept_language = req.headers().get(header::ACCEPT_LANGUAGE).cloned();
+    let has_body = request_has_body(req.headers());
+
+    // Rewrite the path to serve, keeping the requested one for the log.
+    let prepared = normalized.and_then(|()| rewrite_request_uri(&config, &mut req));
+
+    // Serve the requested file, unless the client is over the rate limit.
+    let resp = if let Some(retry_after) = check_rate_limit(&config, &peer) {
+        let secs = retry_after.as_secs_f64().ceil() as u64;
+        let headers = HeaderMap::from_iter([(header::RETRY_AFTER, HeaderValue::from(secs))]);
+        make_error_response_from_code_and_headers(
+            StatusCode::TOO_MANY_REQUESTS,
+            headers,
+            error_path,
+        )
+    } else {
+        match (prepared, vhost_config(&config, &req)) {
+            (Ok(()), Some((config, vhost))) => {
+                serve_or_error(config, req, peer.clone(), start, vhost).await
+            }
+            (Ok(()), None) => {
+                make_error_response_from_code(StatusCode::MISDIRECTED_REQUEST, error_path)
+            }
+            (Err(e), _) => Err(e),
+        }
+    };
+
+    // Transform internal errors to error responses.
+    let mut resp = transform_error(resp, error_path);
+
+    // Give API clients errors as JSON, and show everyone else error pages in
+    // their language, if there are translations.
+    errorpages::negotiate(
+        &config,
+        &method,
+        accept.as_ref(),
+        accept_language.as_ref(),
+        error_path,
+        &mut resp,
+    );
+
+    // Add the headers every response gets. Nothing changes the response
+    // after this.
+    finalize_response(&config, uri.path(), has_body, &mut resp);
+
+    if let (Some(level), Some(req_headers)) = (dump_level, &req_headers) {
+        dump_headers(level, &method, &uri, req_headers, &resp);
+    }
+
+    // hyper leaves the body off HEAD responses, and those that can't have
+    // one.
+    let no_body = method == Method::HEAD
+        || resp.status().is_informational()
+        || matches!(
+            resp.status(),
+            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+        );
+    let expected = if no_body {
+        Some(0)
+    } else {
+        resp.headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse().ok())
+    };
+    let bytes = expected.unwrap_or(0);
+    stats::record_response(resp.status().as_u16(), bytes);
+
+    let span = Span::current();
+    span.record("status", resp.status().as_u16());
+    span.record("duration", field::debug(start.elapsed()));
+    if config.status_line {
+        stats::record_last_request(
+            method.as_str(),
+            &truncate_for_log(uri.path()),
+            resp.status().as_u16(),
+        );
+    }
+
+    // The access log, noting requests on connections kept alive, written
+    // once the body is sent or the client leaves.
+    let request = format!("{} {}", method, truncate_for_log(&uri.to_string()));
+    let line = console::access_line(
+        &peer,
+        &method,
+        &truncate_for_log(&uri.to_string()),
+        resp.status(),
+        reused,
+    );
+    resp.map(|body| accesslog::Logged::wrap(body, line, request, expected))
+}
+
+/// Whether a connection failed because the client went away, as when a
+/// download is cancelled, rather than because of anything wrong here.
+fn is_client_abort(err: &hyper::Error) -> bool {
+    if err.is_incomplete_message() || err.is_canceled() || err.is_body_write_aborted() {
+        return true;
+    }
+    let mut source = err.source();
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            return matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::UnexpectedEof
+            );
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Add the headers that every response gets, whatever made it.
+fn finalize_response(
+    config: &Config,
+    path: &str,
+    has_body: bool,
+    resp: &mut Response<BoxBody<Bytes, Error>>,
+) {
+    // Add headers from the headers file for the requested path.
+    config.header_rules.apply(path, resp.headers_mut());
+
+    // Then apply the most specific policies for it.
+    let policies = policy::effective(&config.policy, path);
+    if !policies.is_empty() {
+        let shown: Vec<String> = policies.iter().map(|p| p.to_string()).collect();
+        debug!(
+            "policies for {}: {}",
+            truncate_for_log(path),
+            shown.join(", ")
+        );
+        let is_success = resp.status().is_success();
+        policy::apply(&policies, is_success, !config.no_cache, resp.headers_mut());
+    }
+
+    // With `--no-keepalive`, every connection closes after one response.
+    // Request bodies are never read, and could be any size, so close the
+    // connection rather than have hyper drain the body to read the next
+    // request.
+    if has_body || config.no_keepalive {
+        resp.headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+
+    if let Some(server) = &config.server_header {
+        resp.headers_mut().insert(header::SERVER, server.clone());
+    }
+
+    if config.deny_robots {
+        resp.headers_mut().insert(
+            HeaderName::from_static("x-robots-tag"),
+            HeaderValue::from_static("noindex, nofollow"),
+        );
+    }
+}
+
+/// The level to log headers at: info with `--dump-headers`, or else trace,
+/// if that's being logged.
+fn header_dump_level(config: &Config) -> Option<Level> {
+    // tracing levels must be constant at each call site.
+    if config.dump_headers {
+        enabled!(Level::INFO).then_some(Level::INFO)
+    } else {
+        enabled!(Level::TRACE).then_some(Level::TRACE)
+    }
+}
+
+/// Log the request's headers, and the status and headers of its response as
+/// they are sent. Credentials in the request are never logged.
+fn dump_headers(
+    level: Level,
+    method: &Method,
+    uri: &Uri,
+    req_headers: &HeaderMap,
+    resp: &Response<BoxBody<Bytes, Error>>,
+) {
+    let redacted = [
+        header::AUTHORIZATION,
+        header::PROXY_AUTHORIZATION,
+        header::COOKIE,
+    ];
+    let mut dump = format!(
+        "headers for {} {}",
+        method,
+        truncate_for_log(&uri.to_string())
+    );
+    for (name, value) in req_headers {
+        let value = if redacted.contains(name) {
+            Cow::Borrowed("(redacted)")
+        } else {
+            String::from_utf8_lossy(value.as_bytes())
+        };
+        dump.push_str(&format!("\n> {name}: {value}"));
+    }
+    dump.push_str(&format!("\n< {}", resp.status()));
+    for (name, value) in resp.headers() {
+        let value = String::from_utf8_lossy(value.as_bytes());
+        dump.push_str(&format!("\n< {name}: {value}"));
+    }
+    if level == Level::INFO {
+        info!("{}", dump);
+    } else {
+        trace!("{}", dump);
+    }
+}
+
+/// Whether a request has a body, going by its headers.
+fn request_has_body(headers: &HeaderMap) -> bool {
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+    headers.contains_key(header::TRANSFER_ENCODING) || content_length.is_some_and(|len| len > 0)
+}
+
+/// Check the client against `--rate-limit`, returning how long it should
+/// wait if it's over.
+///
+/// Local clients aren't limited without `--rate-limit-local`.
+fn check_rate_limit(config: &Config, peer: &Peer) -> Option<Duration> {
+    let rate = config.rate_limit?;
+    let ip = match peer {
+        Peer::Tcp(addr) => addr.ip(),
+        Peer::Forwarded(ip) => *ip,
+        Peer::Unix(_) => return None,
+    };
+    if ip.to_canonical().is_loopback() && !config.rate_limit_local {
+        return None;
+    }
+    let retry_after = ratelimit::check(ip, rate)?;
+    debug!("rate limiting {}", ip);
+    Some(retry_after)
+}
+
+/// The configuration to serve a request with, and the `--vhost` it matched:
+/// with `--vhost`, the root directory is the one for the requested host.
+///
+/// Returns `None` if no host matches and `--vhost-strict` is set.
+fn vhost_config(
+    config: &Arc<Config>,
+    req: &Request<Incoming>,
+) -> Option<(Arc<Config>, Option<VHost>)> {
+    if config.vhost.is_empty() {
+        return Some((config.clone(), None));
+    }
+
+    let host = vhost::request_host(req.uri(), req.headers());
+    match host
+        .as_deref()
+        .and_then(|host| vhost::position(&config.vhost, host))
+    {
+        Some(i) => Some((
+            config.vhost_configs[i].clone(),
+            Some(config.vhost[i].clone()),
+        )),
+        None if config.vhost_strict => {
+            debug!("no --vhost for host {:?}", host);
+            None
+        }
+        None => Some((config.clone(), None)),
+    }
+}
+
+/// The client of a request: the connected peer, or with `--trust-proxy`,
+/// whoever a trusted proxy forwarded the request for.
+///
+/// `X-Forwarded-For` from anyone but a trusted proxy is ignored, so clients
+/// can't spoof their address.
+fn client_peer(config: &Config, peer: Peer, headers: &HeaderMap) -> Peer {
+    if !config.trust_proxy {
+        return peer;
+    }
+
+    let is_trusted = |ip: IpAddr| {
+        if config.trusted_proxy.is_empty() {
+            ip.to_canonical().is_loopback()
+        } else {
+            config.trusted_proxy.iter().any(|net| net.contains(ip))
+        }
+    };
+    let peer_is_trusted = match &peer {
+        Peer::Tcp(addr) => is_trusted(addr.ip()),
+        // Only local processes can connect to the socket.
+        Peer::Unix(_) => true,
+        Peer::Forwarded(_) => false,
+    };
+    if !peer_is_trusted {
+        return peer;
+    }
+
+    match proxy::forwarded_for(headers, is_trusted) {
+        Some(ip) => Peer::Forwarded(ip),
+        None => peer,
+    }
+}
+
+/// Handle all types of requests, but don't deal with transforming internal
+/// errors to HTTP error responses.
+async fn serve_or_error(
+    config: Arc<Config>,
+    mut req: Request<Incoming>,
+    peer: Peer,
+    start: Instant,
+    vhost: Option<VHost>,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    // Health checks are answered whatever else is going on.
+    if req.uri().path() == HEALTH_PATH {
+        return health_response();
+    }
+
+    // In maintenance mode, nothing else is.
+    if maintenance::is_on() {
+        return maintenance_response(&config, error_page_path(&config, req.uri()));
+    }
+
+    // Tell clients probing with OPTIONS which methods there are. This
+    // includes "OPTIONS *", which isn't about any path.
+    if req.method() == Method::OPTIONS {
+        return options_response(&config);
+    }
+
+    // This server only supports the GET and HEAD methods, and PROPFIND with
+    // `--webdav`. Return an appropriate response otherwise.
+    if let Some(resp) = handle_unsupported_request(&req, &config) {
+        return resp;
+    }
+
+    // Refuse URIs longer than anyone could reasonably need.
+    let uri_len = req.uri().path_and_query().map_or(0, |pq| pq.as_str().len());
+    if uri_len > config.max_uri_length {
+        warn!("URI too long: {} bytes", uri_len);
+        let limit = config.max_uri_length;
+        return make_error_response_for_reason(Reason::UriTooLong { limit }, None);
+    }
+
+    // The built-in assets hide any files under their prefix.
+    if let Some(resp) = assets::serve(&config, req.uri().path()) {
+        return resp;
+    }
+
+    // ACME challenges come from `--acme-dir`, not the root.
+    if let Some(resp) = acme::serve(&config, req.method(), req.uri().path()).await {
+        return resp;
+    }
+
+    // Look for the file the client meant, if the path doesn't exist.
+    find_fallback_path(&config, &mut req)?;
+
+    // Map the URL to a local path and the file to serve once, for
+    // everything after this.
+    let path = local_path_for_request(req.uri(), &config.root_dir)?;
+    let ctx = RequestContext {
+        peer,
+        start,
+        file: local_path_with_maybe_index(&config, &path),
+        path,
+        url_path: req.uri().path().to_string(),
+        vhost,
+    };
+
+    // Pretend hidden paths don't exist.
+    if config.hide_strict {
+        let rel = ctx.path.strip_prefix(&config.root_dir).unwrap_or(&ctx.path);
+        if config.hide_patterns.is_hidden(rel) {
+            debug!("hidden path: {}", rel.display());
+            let path = error_page_path(&config, req.uri());
+            return make_error_response_from_code(StatusCode::NOT_FOUND, path);
+        }
+    }
+
+    // Describe the file or directory for WebDAV clients.
+    if webdav::is_propfind(&req) {
+        return webdav::propfind(&req, &config, &ctx.path).await;
+    }
+
+    // Keep crawlers out with `--deny-robots`, unless there's a real
+    // robots.txt to serve.
+    if config.deny_robots && ctx.url_path == ROBOTS_PATH && !is_file(&config, &ctx.path) {
+        return robots_response();
+    }
+
     // Serve the requested file.
-    let resp = serve_or_error(config, req).await;
+    let resp = serve_file(&req, &config, &ctx).await;
+
+    // Give developer extensions an opportunity to post-process the request/response pair.
+    let uri = req.uri().clone();
+    let method = req.method().clone();
+    let mut resp = ext::serve(config.clone(), &method, req, &ctx, resp).await;
+
+    if let Ok(resp) = &mut resp {
+        set_cache_control(&config, &uri, resp);
+    }
+
+    debug!(
+        "served {} to {} from {} in {:?}",
+        truncate_for_log(&ctx.url_path),
+        ctx.peer,
+        ctx.vhost
+            .as_ref()
+            .map_or(config.root_dir.display().to_string(), |v| v.to_string()),
+        ctx.start.elapsed()
+    );
+
+    resp
+}
+
+/// What's known about a request once it's been checked, worked out once for
+/// everything that serves it, extensions included.
+///
+/// Anything computed while serving that later steps want can go in the
+/// request's extensions, as the client's `Peer` does.
+pub(crate) struct RequestContext {
+    /// The client, as shown in the access log.
+    peer: Peer,
+    /// When the request arrived.
+    start: Instant,
+    /// The local path the URL maps to, before any index file is added.
+    path: PathBuf,
+    /// The file served for the URL: `path`, or the index file of the
+    /// directory it names.
+    file: PathBuf,
+    /// The normalized URL path, after rewrites and fallbacks.
+    url_path: String,
+    /// The `--vhost` the request's host matched, if any.
+    vhost: Option<VHost>,
+}
+
+/// The URL path of health checks.
+const HEALTH_PATH: &str = "/__health";
+
+/// Report whether the server is up, or down for maintenance.
+fn health_response() -> Result<Response<BoxBody<Bytes, Error>>> {
+    let (status, body) = if maintenance::is_on() {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "{\"status\":\"maintenance\"}\n",
+        )
+    } else {
+        (StatusCode::OK, "{\"status\":\"ok\"}\n")
+    };
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .header(header::CONTENT_LENGTH, body.len())
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(
+            Full::new(Bytes::from_static(body.as_bytes()))
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .map_err(Error::from)
+}
+
+/// The URL path of the robots exclusion file.
+const ROBOTS_PATH: &str = "/robots.txt";
+
+/// A robots.txt disallowing everything, for `--deny-robots`.
+fn robots_response() -> Result<Response<BoxBody<Bytes, Error>>> {
+    let body = "User-agent: *\nDisallow: /\n";
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, mime::TEXT_PLAIN_UTF_8.as_ref())
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(
+            Full::new(Bytes::from_static(body.as_bytes()))
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .map_err(Error::from)
+}
+
+/// Respond 503 to a request in maintenance mode.
+fn maintenance_response(
+    config: &Config,
+    path: Option<&str>,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    let status = StatusCode::SERVICE_UNAVAILABLE;
+    let body = render_html(&HtmlCfg {
+        title: format!("{}", status),
+        kind: PageKind::Error,
+        path: path.unwrap_or_default().to_string(),
+        body: "<p>The server is down for maintenance. Try again soon.</p>".to_string(),
+        ..HtmlCfg::default()
+    })?;
+    let retry_after = HeaderValue::from(config.maintenance_retry_after);
+    let headers = HeaderMap::from_iter([(header::RETRY_AFTER, retry_after)]);
+    html_str_to_response_with_headers(body, status, headers)
+}
+
+/// Add `Cache-Control` to a response as configured by `--cache`,
+/// `--immutable` or `--no-cache`.
+///
+/// `--no-cache` applies to every response, while the others only let clients
+/// cache successful ones.
+/// A `--policy` for the requested path overrides this, when responses are
+/// finalized.
+fn set_cache_control(config: &Config, uri: &Uri, resp: &mut Response<BoxBody<Bytes, Error>>) {
+    let value = if config.no_cache {
+        HeaderValue::from_static("no-store")
+    } else if resp.status().is_success() && config.immutable_patterns.is_match(uri.path()) {
+        debug!("immutable path: {}", truncate_for_log(uri.path()));
+        HeaderValue::from_static("public, max-age=31536000, immutable")
+    } else if let Some(secs) = config.cache.filter(|_| resp.status().is_success()) {
+        HeaderValue::from_str(&format!("max-age={secs}")).expect("max-age is valid header")
+    } else {
+        return;
+    };
+    resp.headers_mut().insert(header::CACHE_CONTROL, value);
+}
+
+/// Serve static files from a root directory.
+async fn serve_file(
+    req: &Request<Incoming>,
+    config: &Config,
+    ctx: &RequestContext,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    // First, try to do a redirect. If that doesn't happen, then find the path
+    // to the static file we want to serve - which may be `index.html` for
+    // directories - and send a response containing that file.
+    if let Some(redir_resp) = try_rule_redirect(req, config)? {
+        return Ok(redir_resp);
+    }
+
+    let maybe_redir_resp = try_dir_redirect(req, config, &ctx.path)?;
+
+    if let Some(redir_resp) = maybe_redir_resp {
+        return Ok(redir_resp);
+    }
+
+    // Platforms disagree about opening a path through a file, so don't try.
+    if config.vfs.is_none() && is_under_file(&ctx.path, &config.root_dir).await {
+        debug!("path goes through a file: {}", ctx.path.display());
+        return Err(Error::from(io::Error::from(io::ErrorKind::NotFound)));
+    }
+
+    let path = ctx.file.clone();
+
+    if !config.is_servable(&path) {
+        debug!("not an allowed file type: {}", path.display());
+        return Err(Error::from(io::Error::from(io::ErrorKind::NotFound)));
+    }
+
+    // Answer `?hash=sha256` with the file's digest rather than the file.
+    if config.digests && digest::hash_query(req).is_some() {
+        return digest::query_response(req, config, &path).await;
+    }
+
+    let mut resp = match respond_with_file(path.clone(), config, req.method(), req.headers()).await
+    {
+        // Browsers ask for a favicon on every page, so have one for them.
+        Err(Error::Io(e))
+            if e.kind() == io::ErrorKind::NotFound
+                && req.uri().path() == "/favicon.ico"
+                && !config.no_default_favicon =>
+        {
+            debug!("serving the default favicon");
+            return default_favicon_response();
+        }
+        resp => resp,
+    };
+
+    if config.digests && digest::wants_repr_digest(req.headers()) {
+        if let Ok(resp) = &mut resp {
+            digest::add_repr_digest(config, &path, resp).await;
+        }
+    }
+
+    resp
+}
+
+/// The icon served for `/favicon.ico` when the root directory doesn't have
+/// one.
+static DEFAULT_FAVICON: &[u8] = include_bytes!("favicon.ico");
+
+/// Make a response with the default favicon.
+fn default_favicon_response() -> Result<Response<BoxBody<Bytes, Error>>> {
+    let body = Full::new(Bytes::from_static(DEFAULT_FAVICON))
+        .map_err(|never| match never {})
+        .boxed();
+    let resp = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_LENGTH, DEFAULT_FAVICON.len())
+        .header(header::CONTENT_TYPE, "image/x-icon")
+        .header(header::CACHE_CONTROL, "public, max-age=604800")
+        .body(body)?;
+    Ok(resp)
+}
+
+/// Try to redirect with the rules of the redirects file.
+fn try_rule_redirect(
+    req: &Request<Incoming>,
+    config: &Config,
+) -> Result<Option<Response<BoxBody<Bytes, Error>>>> {
+    let Some((status, mut new_loc)) = config.redirect_rules.find(req.uri().path()) else {
+        return Ok(None);
+    };
+
+    if let Some(query) = req.uri().query() {
+        if !new_loc.contains('?') {
+            new_loc.push('?');
+            new_loc.push_str(query);
+        }
+    }
+
+    info!(
+        "redirecting {} to {} by rule",
+        truncate_for_log(&req.uri().to_string()),
+        truncate_for_log(&new_loc)
+    );
+    Response::builder()
+        .status(status)
+        .header(header::LOCATION, new_loc)
+        .body(
+            Empty::<Bytes>::new()
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .map(Some)
+        .map_err(Error::from)
+}
+
+/// Try to do a redirect that normalizes the trailing "/" of a URL.
+///
+/// By default, if we get a URL without trailing "/" that can be mapped to a
+/// directory, then return a redirect to the path with the trailing "/".
+///
+/// Without this we couldn't correctly return the contents of `index.html` for a
+/// directory - for the purpose of building absolute URLs from relative URLs,
+/// agents appear to only treat paths with trailing "/" as directories, so we
+/// have to redirect to the proper directory URL first.
+///
+/// In other words, if we returned the contents of `index.html` for URL `docs`
+/// then all the relative links in that file would be broken, but that is not
+/// the case for URL `docs/`.
+///
+/// This seems to match the behavior of other static web servers.
+///
+/// The `--trailing-slash` option can instead strip the trailing "/" from URLs
+/// that map to regular files, or turn these redirects off entirely. The
+/// status code is a 302 unless configured with `--redirect-code`.
+fn try_dir_redirect(
+    req: &Request<Incoming>,
+    config: &Config,
+    path: &Path,
+) -> Result<Option<Response<BoxBody<Bytes, Error>>>> {
+    let uri_path = req.uri().path();
+
+    let mut new_loc = if let Some(dir_url) = canonical_index_url(config, uri_path, path) {
+        dir_url
+    } else {
+        match config.trailing_slash {
+            TrailingSlash::Keep => return Ok(None),
+            TrailingSlash::Add => {
+                if uri_path.ends_with('/') {
+                    return Ok(None);
+                }
+
+                debug!("path does not end with /");
+
+                if !is_dir(config, path) {
+                    return Ok(None);
+                }
+
+                format!("{uri_path}/")
+            }
+            TrailingSlash::Strip => {
+                let trimmed = uri_path.trim_end_matches('/');
+                if trimmed.is_empty() || trimmed.len() == uri_path.len() {
+                    return Ok(None);
+                }
+
+                debug!("path ends with /");
+
+                let path = local_path_for_url_path(trimmed, &config.root_dir)?;
+
+                if !is_file(config, &path) {
+                    return Ok(None);
+                }
+
+                trimmed.to_string()
+            }
+        }
+    };
+
+    if let Some(query) = req.uri().query() {
+        new_loc.push('?');
+        new_loc.push_str(query);
+    }
+
+    info!(
+        "redirecting {} to {}",
+        truncate_for_log(&req.uri().to_string()),
+        truncate_for_log(&new_loc)
+    );
+    Response::builder()
+        .status(config.redirect_status())
+        .header(header::LOCATION, new_loc)
+        .body(
+            Empty::<Bytes>::new()
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .map(Some)
+        .map_err(Error::from)
+}
+
+/// With `--canonical-index`, the directory URL to redirect a request for
+/// its index file to, like "/docs/" for "/docs/index.html".
+///
+/// Only an `--index` file that the directory URL would be served is
+/// redirected. Markdown index files aren't, so their source can still be
+/// fetched.
+/// That URL ends in "/", and no trailing slash policy redirects a directory
+/// away from one, so the two redirects can't loop.
+fn canonical_index_url(config: &Config, uri_path: &str, path: &Path) -> Option<String> {
+    if !config.canonical_index {
+        return None;
+    }
+    let (dir_url, name) = uri_path.rsplit_once('/')?;
+    if !config.index_names().contains(&name) || !is_file(config, path) {
+        return None;
+    }
+    let dir_url = format!("{dir_url}/");
+    let dir = path.parent()?;
+    (local_path_with_maybe_index(config, dir) == path).then_some(dir_url)
+}
+
+/// Construct a 200 response with the file as the body, streaming it to avoid
+/// loading it fully into memory.
+///
+/// If the I/O here fails then an error future will be returned, and `serve`
+/// will convert it into the appropriate HTTP error response.
+///
+/// A `Range` header gets just those parts of the file, as a 206 response.
+///
+/// The body is cut off at the length the file had when it was opened, so it
+/// matches the `Content-Length` even if the file grows meanwhile. With
+/// `--no-content-length` there's no length to match, and the file is sent
+/// until it ends.
+///
+/// Requests for more than `--max-file-size` bytes are refused with
+/// `Error::FileTooLarge`, before the file is opened: the whole file, or just
+/// the ranges asked for.
+///
+/// The request's preconditions are only evaluated once the file is found,
+/// so they never turn a 404 into anything else.
+async fn respond_with_file(
+    path: PathBuf,
+    config: &Config,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    let mime_type = file_path_mime(&path);
+
+    if let Some(vfs) = &config.vfs {
+        return respond_with_vfs_file(&**vfs, &path, config, method, headers, &mime_type).await;
+    }
+
+    // Only regular files are opened, since opening a FIFO waits for
+    // something to write to it, and sockets and device files aren't files to
+    // serve. The cached size was just looked up to find the file, so it saves
+    // asking again.
+    let cached = uses_stat_cache(config)
+        .then(|| statcache::stat(&path))
+        .flatten()
+        .filter(|stat| stat.is_file);
+    let len = match cached {
+        Some(stat) => stat.len,
+        None => {
+            let meta = tokio::fs::metadata(&path).await?;
+            if !meta.is_file() {
+                return special_file_response(config, &path);
+            }
+            meta.len()
+        }
+    };
+
+    let outcome = file_preconditions(config, method, headers);
+    if let Some(resp) = conditional::response(&outcome, &Validators::default()) {
+        return resp;
+    }
+    let ranges = match outcome {
+        Outcome::SendWhole => Ranges::Full,
+        _ => range::parse(headers, len),
+    };
+
+    if config
+        .max_file_size
+        .is_some_and(|max| ranges.byte_count(len) > max)
+    {
+        return Err(Error::FileTooLarge);
+    }
+
+    let file = File::open(&path).await?;
+
+    match ranges {
+        Ranges::Full => {}
+        Ranges::Unsatisfiable => {
+            let content_range = HeaderValue::from_str(&format!("bytes */{len}"))
+                .expect("content range is valid header");
+            let headers = HeaderMap::from_iter([(header::CONTENT_RANGE, content_range)]);
+            return make_error_response_from_code_and_headers(
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                headers,
+                None,
+            );
+        }
+        Ranges::Partial(ranges) => {
+            return match ranges[..] {
+                [(start, end)] => {
+                    respond_with_range(file, config, &mime_type, len, start, end).await
+                }
+                _ => respond_with_ranges(&path, config, &mime_type, len, &ranges).await,
+            };
+        }
+    }
+
+    let file = file.take(if config.no_content_length {
+        u64::MAX
+    } else {
+        len
+    });
+    let reader_stream = ReaderStream::with_capacity(file, config.buffer_size);
+    let stream_body = StreamBody::new(reader_stream.map_ok(Frame::data).map_err(Error::Io));
+    let boxed_body = stream_body.boxed();
+
+    let mut resp = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, mime_type.as_ref())
+        .header(header::ACCEPT_RANGES, "bytes")
+        .body(boxed_body)?;
+
+    if !config.no_content_length {
+        resp.headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+    }
+
+    Ok(resp)
+}
+
+/// Refuse a request for something that isn't a regular file, like a FIFO,
+/// socket or device file: 404 as if it weren't there, or 403 with
+/// `--forbid-special-files`.
+fn special_file_response(config: &Config, path: &Path) -> Result<Response<BoxBody<Bytes, Error>>> {
+    debug!("not a regular file: {}", path.display());
+    if config.forbid_special_files {
+        make_error_response_for_reason(Reason::SpecialFile, None)
+    } else {
+        Err(Error::from(io::Error::from(io::ErrorKind::NotFound)))
+    }
+}
+
+/// Respond with a file from the tree served in place of the root directory.
+/// These files are always sent whole, ignoring any `Range` header.
+async fn respond_with_vfs_file(
+    vfs: &dyn Vfs,
+    path: &Path,
+    config: &Config,
+    method: &Method,
+    headers: &HeaderMap,
+    mime_type: &mime::Mime,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    let (len, _) = vfs.file_meta(path).ok_or_else(vfs::not_found)?;
+
+    if config.max_file_size.is_some_and(|max| len > max) {
+        return Err(Error::FileTooLarge);
+    }
+
+    let outcome = file_preconditions(config, method, headers);
+    if let Some(resp) = conditional::response(&outcome, &Validators::default()) {
+        return resp;
+    }
+
+    let (len, body) = vfs.read(path, config.buffer_size).await?;
+
+    let resp = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_LENGTH, len)
+        .header(header::CONTENT_TYPE, mime_type.as_ref())
+        .body(body)?;
+
+    Ok(resp)
+}
+
+/// Evaluate the preconditions of a request for a file, which `--no-cache`
+/// ignores.
+///
+/// Files are sent without validators, so only "*" meets `If-Match` or
+/// `If-None-Match`, and `If-Range` never matches.
+fn file_preconditions(config: &Config, method: &Method, headers: &HeaderMap) -> Outcome {
+    if config.no_cache {
+        return Outcome::Send;
+    }
+    conditional::evaluate(method, headers, &Validators::default())
+}
+
+/// Respond with one inclusive byte range of a file.
+async fn respond_with_range(
+    mut file: File,
+    config: &Config,
+    mime_type: &mime::Mime,
+    len: u64,
+    start: u64,
+    end: u64,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    file.seek(SeekFrom::Start(start)).await?;
+    let part = file.take(end - start + 1);
+
+    let reader_stream = ReaderStream::with_capacity(part, config.buffer_size);
+    let stream_body = StreamBody::new(reader_stream.map_ok(Frame::data).map_err(Error::Io));
+
+    let resp = Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(header::CONTENT_LENGTH, end - start + 1)
+        .header(header::CONTENT_TYPE, mime_type.as_ref())
+        .header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"))
+        .header(header::ACCEPT_RANGES, "bytes")
+        .body(stream_body.boxed())?;
+
+    Ok(resp)
+}
+
+/// Respond with several inclusive byte ranges of a file, as a
+/// `multipart/byteranges` body.
+///
+/// Each part reads from its own handle to the file, opened and seeked up
+/// front, and the length of the whole body is worked out exactly.
+async fn respond_with_ranges(
+    path: &Path,
+    config: &Config,
+    mime_type: &mime::Mime,
+    len: u64,
+    ranges: &[(u64, u64)],
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    use futures::StreamExt;
+
+    let boundary = range::boundary();
+    let mut content_length = 0;
+    let mut parts = Vec::with_capacity(ranges.len());
+
+    for &(start, end) in ranges {
+        let head = format!(
+            "\r\n--{boundary}\r\nContent-Type: {mime_type}\r\n\
+             Content-Range: bytes {start}-{end}/{len}\r\n\r\n"
+        );
+        content_length += head.len() as u64 + (end - start + 1);
+
+        let mut file = File::open(path).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let data = ReaderStream::with_capacity(file.take(end - start + 1), config.buffer_size);
+        parts.push(stream::once(future::ready(Ok(Bytes::from(head)))).chain(data));
+    }
+
+    let tail = format!("\r\n--{boundary}--\r\n");
+    content_length += tail.len() as u64;
+
+    let body = stream::iter(parts)
+        .flatten()
+        .chain(stream::once(future::ready(Ok(Bytes::from(tail)))));
+    let stream_body = StreamBody::new(body.map_ok(Frame::data).map_err(Error::Io));
+
+    let resp = Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(header::CONTENT_LENGTH, content_length)
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={boundary}"),
+        )
+        .header(header::ACCEPT_RANGES, "bytes")
+        .body(BodyExt::boxed(stream_body))?;
+
+    Ok(resp)
+}
+
+/// Get a MIME type based on the file extension.
+///
+/// `MIME_OVERRIDES` comes first, then `mime_guess`. If the extension is
+/// unknown then return "application/octet-stream".
+fn file_path_mime(file_path: &Path) -> mime::Mime {
+    mime_override(file_path)
+        .unwrap_or_else(|| mime_guess::from_path(file_path).first_or_octet_stream())
+}
+
+/// The MIME type from `MIME_OVERRIDES` for a file's extension, if any.
+fn mime_override(file_path: &Path) -> Option<mime::Mime> {
+    let ext = file_path.extension()?.to_str()?.to_ascii_lowercase();
+    MIME_OVERRIDES
+        .iter()
+        .find(|(e, _)| *e == ext)
+        .and_then(|(_, mime)| mime.parse().ok())
+}
+
+/// For `--print-mime`, explain step by step how a file's Content-Type is
+/// chosen, as `serve_file` and the extensions would choose it.
+fn mime_decision(config: &Config, path: &Path) -> String {
+    let mut out = format!("path: {}\n", path.display());
+    if !config.is_servable(path) {
+        out.push_str("served: no, by --allow-ext or --deny-ext\n");
+        return out;
+    }
+    let overridden = mime_override(path);
+    let guessed = mime_guess::from_path(path).first();
+    let show = |mime: &Option<mime::Mime>| {
+        mime.as_ref()
+            .map_or("none".to_string(), |mime| mime.to_string())
+    };
+    out.push_str(&format!("built-in override: {}\n", show(&overridden)));
+    out.push_str(&format!("mime_guess: {}\n", show(&guessed)));
+    let mut content_type = file_path_mime(path).to_string();
+
+    let name = path.to_string_lossy();
+    let is_md = path.extension().is_some_and(|ext| ext == "md");
+    if !config.use_extensions {
+        out.push_str("extensions: off\n");
+    } else if is_md {
+        out.push_str("markdown: text/html for browsers, rendered\n");
+        content_type = "text/markdown; charset=utf-8".to_string();
+    } else if config.text_types.matches_path(&name) {
+        out.push_str("text type: yes, by the text types (see --print-text-types)\n");
+        content_type = mime::TEXT_PLAIN.to_string();
+    } else {
+        out.push_str("text type: no\n");
+    }
+    out.push_str(&format!("content type: {content_type}\n"));
+    out
+}
+
+/// For `--print-routes`, the rules that decide how a request is served, in
+/// the order `serve` and `serve_or_error` check them.
+fn routes_table(config: &Config) -> String {
+    let mut out = String::new();
+    let mut section = |title: &str, lines: Vec<String>| {
+        out.push_str(title);
+        out.push_str(":\n");
+        if lines.is_empty() {
+            out.push_str("    (none)\n");
+        }
+        for line in lines {
+            out.push_str(&format!("    {line}\n"));
+        }
+    };
+    let on_off = |on: bool| (if on { "on" } else { "off" }).to_string();
+    let lines = |text: String| text.lines().map(str::to_string).collect::<Vec<_>>();
+
+    section(
+        "rewrites",
+        config.rewrite.iter().map(|r| r.to_string()).collect(),
+    );
+    section(
+        "vhosts",
+        config.vhost.iter().map(|v| v.to_string()).collect(),
+    );
+    let clients = config
+        .allow
+        .iter()
+        .map(|cidr| format!("allow {cidr}"))
+        .chain(config.deny.iter().map(|cidr| format!("deny {cidr}")))
+        .collect();
+    section("clients", clients);
+    section("health check", vec![HEALTH_PATH.to_string()]);
+    section("maintenance", vec![on_off(config.maintenance)]);
+    let assets = format!("{}/", config.assets_prefix.trim_end_matches('/'));
+    section("assets", vec![assets]);
+    section(
+        "acme challenges",
+        config
+            .acme_dir
+            .iter()
+            .map(|dir| format!("{} -> {}", acme::CHALLENGE_PREFIX, dir.display()))
+            .collect(),
+    );
+    section(
+        "fallbacks",
+        vec![
+            format!("case-insensitive: {}", on_off(config.case_insensitive)),
+            format!("normalize unicode: {}", on_off(config.normalize_unicode)),
+        ],
+    );
+    let hidden = config.hide.iter().map(|glob| {
+        let strict = if config.hide_strict {
+            ""
+        } else {
+            " (listings only)"
+        };
+        format!("{glob}{strict}")
+    });
+    section("hidden", hidden.collect());
+    section("webdav", vec![on_off(config.webdav)]);
+    let robots = config
+        .deny_robots
+        .then(|| format!("{ROBOTS_PATH} (unless there's a file)"));
+    section("robots", robots.into_iter().collect());
+    section("redirects", lines(config.redirect_rules.to_string()));
+    section(
+        "trailing slash",
+        vec![format!("{:?}", config.trailing_slash).to_lowercase()],
+    );
+    section("index", vec![config.index_candidates().join(", ")]);
+    let mut exts = Vec::new();
+    if !config.allow_ext.is_empty() {
+        exts.push(format!("allow: {}", config.allow_ext.join(", ")));
+        exts.push(format!(
+            "allow no extension: {}",
+            on_off(config.allow_noext)
+        ));
+    }
+    if !config.deny_ext.is_empty() {
+        exts.push(format!("deny: {}", config.deny_ext.join(", ")));
+    }
+    section("extensions served", exts);
+    section("digests", vec![on_off(config.digests)]);
+    section("developer extensions", vec![on_off(config.use_extensions)]);
+    let listings = if config.use_extensions {
+        "html"
+    } else if config.list_dirs {
+        "plain text, or html for clients that prefer it"
+    } else {
+        "off"
+    };
+    section("directory listings", vec![listings.to_string()]);
+    section("immutable", config.immutable.clone());
+    section("headers", lines(config.header_rules.to_string()));
+    section("policies", policy::describe(&config.policy));
+    out
+}
+
+/// MIME types for extensions that browsers are strict about, and that some
+/// versions of `mime_guess` get wrong or don't know.
+///
+/// Modules must be JavaScript, `WebAssembly.instantiateStreaming` needs
+/// "application/wasm", and source maps are JSON.
+#[rustfmt::skip]
+static MIME_OVERRIDES: &[(&str, &str)] = &[
+    ("avif", "image/avif"),
+    ("cjs", "text/javascript"),
+    ("js", "text/javascript"),
+    ("map", "application/json"),
+    ("mjs", "text/javascript"),
+    ("wasm", "application/wasm"),
+    ("webmanifest", "application/manifest+json"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+];
+
+/// Convert the local path for a directory to its index file, the first of
+/// `Config::index_candidates` that's a servable file.
+///
+/// Without any, it's the first `--index` name, which isn't found, so the
+/// directory is listed or it's a 404. Everything that asks what a directory
+/// is served as asks this.
+fn local_path_with_maybe_index(config: &Config, path: &Path) -> PathBuf {
+    if !is_dir(config, path) {
+        trace!("trying path as from URL");
+        return path.to_owned();
+    }
+
+    let candidates = config.index_candidates();
+    let index = candidates
+        .iter()
+        .map(|name| path.join(name))
+        .find(|p| is_file(config, p) && config.is_servable(p))
+        .unwrap_or_else(|| path.join(candidates[0]));
+    debug!("trying {} for directory URL", index.display());
+    index
+}
+
+/// Whether a local path on disk can't be served because it goes through a
+/// file, as "/file.txt/extra" does, or names a file as a directory, as
+/// "/file.txt/" does.
+///
+/// Opening these fails with `NotADirectory` on some platforms, and succeeds
+/// or fails differently on others. The checks don't block the runtime, as
+/// they stat every ancestor up to the root.
+async fn is_under_file(path: &Path, root_dir: &Path) -> bool {
+    let trailing_slash = path
+        .as_os_str()
+        .as_encoded_bytes()
+        .ends_with(std::path::MAIN_SEPARATOR_STR.as_bytes());
+    let resolved: PathBuf = path.components().collect();
+    if let Ok(meta) = tokio::fs::metadata(&resolved).await {
+        return trailing_slash && meta.is_file();
+    }
+    let ancestors = resolved
+        .ancestors()
+        .skip(1)
+        .take_while(|a| *a != root_dir && a.starts_with(root_dir));
+    for ancestor in ancestors {
+        if tokio::fs::metadata(ancestor)
+            .await
+            .is_ok_and(|m| m.is_file())
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// Whether a local path is a directory, in the tree served in place of the
+/// root directory if there is one.
+fn is_dir(config: &Config, path: &Path) -> bool {
+    match &config.vfs {
+        Some(vfs) => vfs.is_dir(path),
+        None if uses_stat_cache(config) => statcache::stat(path).is_some_and(|s| s.is_dir),
+        None => path.is_dir(),
+    }
+}
+
+/// Whether a local path is a file, in the tree served in place of the root
+/// directory if there is one.
+fn is_file(config: &Config, path: &Path) -> bool {
+    match &config.vfs {
+        Some(vfs) => vfs.is_file(path),
+        None if uses_stat_cache(config) => statcache::stat(path).is_some_and(|s| s.is_file),
+        None => path.is_file(),
+    }
+}
+
+/// Whether to ask `statcache` about paths on disk: with `--stat-cache`, but
+/// not with `--no-cache`, where changes should show at once.
+fn uses_stat_cache(config: &Config) -> bool {
+    config.stat_cache && !config.no_cache
+}
+
+/// Map the request's URI to a local path
+fn local_path_for_request(uri: &Uri, root_dir: &Path) -> Result<PathBuf> {
+    debug!("raw URI: {}", truncate_for_log(&uri.to_string()));
+
+    local_path_for_url_path(uri.path(), root_dir)
+}
+
+/// Decode a %-encoded URL path segment to a file name.
+///
+/// Unix file names are bytes, so a segment that doesn't decode to UTF-8 can
+/// still name a file.
+#[cfg(unix)]
+fn decode_path_segment(segment: &str) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(OsString::from_vec(percent_decode_str(segment).collect()))
+}
+
+/// Decode a %-encoded URL path segment to a file name.
+#[cfg(not(unix))]
+fn decode_path_segment(segment: &str) -> Option<OsString> {
+    let segment = percent_decode_str(segment).decode_utf8().ok()?;
+    Some(OsString::from(segment.into_owned()))
+}
+
+/// Whether a decoded path segment names an ordinary file in a directory on
+/// Windows.
+///
+/// A backslash separates paths there as "/" does, a colon makes
+/// drive-relative paths and alternate data streams, and the device names
+/// open devices in any directory, with any extension. Pushing anything else
+/// onto a path adds exactly one normal component, rather than replacing the
+/// path with one from another drive or the root.
+#[cfg(windows)]
+fn is_plain_windows_name(segment: &std::ffi::OsStr) -> bool {
+    const DEVICES: [&str; 6] = ["con", "prn", "aux", "nul", "conin$", "conout$"];
+
+    let Some(name) = segment.to_str() else {
+        return false;
+    };
+    if name.contains(['\\', ':']) {
+        return false;
+    }
+    let stem = name.split('.').next().unwrap_or_default();
+    let stem = stem.trim_end_matches(' ').to_ascii_lowercase();
+    let numbered = |prefix: &str| {
+        stem.strip_prefix(prefix).is_some_and(|n| {
+            (n.len() == 1 && n.as_bytes()[0].is_ascii_digit())
+                || matches!(n, "\u{b9}" | "\u{b2}" | "\u{b3}")
+        })
+    };
+    if DEVICES.contains(&stem.as_str()) || numbered("com") || numbered("lpt") {
+        return false;
+    }
 
-    // Transform internal errors to error responses.
-    transform_error(resp)
+    let mut components = Path::new(segment).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(_)), None)
+    )
 }
 
-/// Handle all types of requests, but don't deal with transforming internal
-/// errors to HTTP error responses.
-async fn serve_or_error(
-    config: Config,
-    req: Request<Incoming>,
-) -> Result<Response<BoxBody<Bytes, Error>>> {
-    // This server only supports the GET method. Return an appropriate
-    // response otherwise.
-    if let Some(resp) = handle_unsupported_request(&req) {
-        return resp;
-    }
+/// Map the path component of a URL to a local path
+fn local_path_for_url_path(request_path: &str, root_dir: &Path) -> Result<PathBuf> {
+    debug!("raw URI to path: {}", truncate_for_log(request_path));
 
-    // Serve the requested file.
-    let resp = serve_file(&req, &config.root_dir).await;
+    // Trim off the url parameters starting with '?'
+    let end = request_path.find('?').unwrap_or(request_path.len());
+    let request_path = &request_path[0..end];
 
-    // Give developer extensions an opportunity to post-process the request/response pair.
-    ext::serve(config, req, resp).await
-}
+    let Some(relative_path) = request_path.strip_prefix('/') else {
+        warn!("found non-absolute path {}", truncate_for_log(request_path));
+        return Err(Error::UriNotAbsolute);
+    };
 
-/// Serve static files from a root directory.
-async fn serve_file(
-    req: &Request<Incoming>,
-    root_dir: &Path,
-) -> Result<Response<BoxBody<Bytes, Error>>> {
-    // First, try to do a redirect. If that doesn't happen, then find the path
-    // to the static file we want to serve - which may be `index.html` for
-    // directories - and send a response containing that file.
-    let maybe_redir_resp = try_dir_redirect(req, root_dir)?;
+    // Append the requested path to the root directory one segment at a time,
+    // converting %-encoding to actual values. Decoding each segment on its own
+    // means an encoded "/" can't sneak in an extra path component: it would
+    // have to be part of a file name, which it can't be, so it's a 404.
+    let mut path = root_dir.to_owned();
+    for segment in relative_path.split('/') {
+        if has_control_chars(segment) {
+            warn!(
+                "control character in path: {}",
+                truncate_for_log(request_path)
+            );
+            return Err(Error::UriControlChar);
+        }
+        let Some(segment) = decode_path_segment(segment) else {
+            error!("non utf-8 URL: {}", truncate_for_log(request_path));
+            return Err(Error::UriNotUtf8);
+        };
+
+        match segment {
+            s if s.is_empty() || s == "." => {}
+            s if s == ".." => {
+                warn!("found unnormalized path {}", truncate_for_log(request_path));
+                return Err(Error::UriEscapesRoot);
+            }
+            s if s.as_encoded_bytes().contains(&b'/') => {
+                debug!("found encoded / in path {}", truncate_for_log(request_path));
+                return Err(Error::UriEncodedSlash);
+            }
+            #[cfg(windows)]
+            s if !is_plain_windows_name(&s) => {
+                warn!(
+                    "found unsafe Windows name in path {}",
+                    truncate_for_log(request_path)
+                );
+                return Err(Error::UriUnsafeName);
+            }
+            s => path.push(s),
+        }
+    }
 
-    if let Some(redir_resp) = maybe_redir_resp {
-        return Ok(redir_resp);
+    // Keep the trailing "/", so that a file can't be opened as if it were a
+    // directory.
+    if relative_path.ends_with('/') && path != root_dir {
+        path.as_mut_os_string().push(std::path::MAIN_SEPARATOR_STR);
     }
 
-    let path = local_path_with_maybe_index(req.uri(), root_dir)?;
+    debug!(
+        "URL · path : {} · {}",
+        truncate_for_log(request_path),
+        path.display()
+    );
 
-    respond_with_file(path).await
+    Ok(path)
 }
 
-/// Try to do a 302 redirect for directories.
-///
-/// If we get a URL without trailing "/" that can be mapped to a directory, then
-/// return a 302 redirect to the path with the trailing "/".
-///
-/// Without this we couldn't correctly return the contents of `index.html` for a
-/// directory - for the purpose of building absolute URLs from relative URLs,
-/// agents appear to only treat paths with trailing "/" as directories, so we
-/// have to redirect to the proper directory URL first.
-///
-/// In other words, if we returned the contents of `index.html` for URL `docs`
-/// then all the relative links in that file would be broken, but that is not
-/// the case for URL `docs/`.
+/// Turn a request with an absolute URI, as sent to proxies, like
+/// "GET http://localhost:4000/index.html", into the usual form with just the
+/// path and query.
 ///
-/// This seems to match the behavior of other static web servers.
-fn try_dir_redirect(
-    req: &Request<Incoming>,
-    root_dir: &Path,
-) -> Result<Option<Response<BoxBody<Bytes, Error>>>> {
-    if req.uri().path().ends_with('/') {
-        return Ok(None);
-    }
-
-    debug!("path does not end with /");
+/// The URI's host stands in place of the `Host` header, as the HTTP spec
+/// says, so vhost matching sees it. With `--vhost-strict`, a `Host` header
+/// naming another host is an error instead.
+fn origin_form_request(config: &Config, req: &mut Request<Incoming>) -> Result<()> {
+    let Some(authority) = req.uri().authority().cloned() else {
+        return Ok(());
+    };
 
-    let path = local_path_for_request(req.uri(), root_dir)?;
+    let host = req.headers().get(header::HOST);
+    let matches = host
+        .and_then(|host| host.to_str().ok()?.parse::<Authority>().ok())
+        .is_some_and(|host| {
+            host.host().eq_ignore_ascii_case(authority.host())
+                && host.port_u16() == authority.port_u16()
+        });
+    if host.is_some() && !matches {
+        if config.vhost_strict {
+            warn!(
+                "request for {} has Host header {:?}",
+                truncate_for_log(&req.uri().to_string()),
+                host
+            );
+            return Err(Error::UriHostMismatch);
+        }
+        debug!("replacing Host header {:?} with {}", host, authority);
+    }
+    // Leave out any user info.
+    let host = authority.as_str().rsplit('@').next().unwrap_or_default();
+    let host = HeaderValue::from_str(host).expect("authority is valid header");
+    req.headers_mut().insert(header::HOST, host);
+
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| PathAndQuery::from_static("/"));
+    *req.uri_mut() = Uri::from(path_and_query);
+
+    Ok(())
+}
 
-    if !path.is_dir() {
-        return Ok(None);
+/// Replace the request's URI path with its normalized form.
+///
+/// Paths that aren't absolute, like the "*" of `OPTIONS *`, are left alone.
+fn normalize_request_uri(req: &mut Request<Incoming>) -> Result<()> {
+    let path = req.uri().path();
+    if !path.starts_with('/') {
+        return Ok(());
     }
 
-    let mut new_loc = req.uri().path().to_string();
-    new_loc.push('/');
-    if let Some(query) = req.uri().query() {
-        new_loc.push('?');
-        new_loc.push_str(query);
+    let normalized = normalize_url_path(path)?;
+    if normalized == path {
+        return Ok(());
     }
 
-    info!("redirecting {} to {}", req.uri(), new_loc);
-    Response::builder()
-        .status(StatusCode::FOUND)
-        .header(header::LOCATION, new_loc)
-        .body(
-            Empty::<Bytes>::new()
-                .map_err(|never| match never {})
-                .boxed(),
-        )
-        .map(Some)
-        .map_err(Error::from)
+    debug!(
+        "normalized {} to {}",
+        truncate_for_log(path),
+        truncate_for_log(&normalized)
+    );
+
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{normalized}?{query}"),
+        None => normalized,
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse()?);
+    *req.uri_mut() = Uri::from_parts(parts)?;
+
+    Ok(())
 }
 
-/// Construct a 200 response with the file as the body, streaming it to avoid
-/// loading it fully into memory.
+/// Apply the `--rewrite` rules to the request path, keeping the query.
 ///
-/// If the I/O here fails then an error future will be returned, and `serve`
-/// will convert it into the appropriate HTTP error response.
-async fn respond_with_file(path: PathBuf) -> Result<Response<BoxBody<Bytes, Error>>> {
-    let mime_type = file_path_mime(&path);
+/// Rewriting comes first, so redirect rules and file lookup see the rewritten
+/// path.
+fn rewrite_request_uri(config: &Config, req: &mut Request<Incoming>) -> Result<()> {
+    let path = req.uri().path();
+    let Some(rewritten) = rewrites::rewrite(&config.rewrite, path) else {
+        return Ok(());
+    };
 
-    let file = File::open(path).await?;
+    debug!(
+        "rewrote {} to {}",
+        truncate_for_log(path),
+        truncate_for_log(&rewritten)
+    );
 
-    let meta = file.metadata().await?;
-    let len = meta.len();
+    set_request_path(req, rewritten)?;
 
-    let reader_stream = ReaderStream::new(file);
-    let stream_body = StreamBody::new(reader_stream.map_ok(Frame::data).map_err(Error::Io));
-    let boxed_body = stream_body.boxed();
+    // The rewritten path could have "." or ".." segments.
+    normalize_request_uri(req)
+}
 
-    let resp = Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_LENGTH, len)
-        .header(header::CONTENT_TYPE, mime_type.as_ref())
-        .body(boxed_body)?;
+/// With `--normalize-unicode` or `--case-insensitive`, point a request for a
+/// path that doesn't exist at one that does, whose names only differ in
+/// Unicode normalization or case.
+fn find_fallback_path(config: &Config, req: &mut Request<Incoming>) -> Result<()> {
+    let fold = Fold {
+        case: config.case_insensitive,
+        unicode: config.normalize_unicode,
+    };
+    if !fold.case && !fold.unicode {
+        return Ok(());
+    }
+    let path = local_path_for_request(req.uri(), &config.root_dir)?;
+    if path.exists() {
+        return Ok(());
+    }
 
-    Ok(resp)
+    let Some(found) = fallback::find_url_path(&config.root_dir, req.uri().path(), fold) else {
+        return Ok(());
+    };
+    debug!(
+        "found {} for {}",
+        truncate_for_log(&found),
+        truncate_for_log(req.uri().path())
+    );
+    set_request_path(req, found)
 }
 
-/// Get a MIME type based on the file extension.
-///
-/// If the extension is unknown then return "application/octet-stream".
-fn file_path_mime(file_path: &Path) -> mime::Mime {
-    mime_guess::from_path(file_path).first_or_octet_stream()
+/// Replace the path of a request's URI, keeping the query.
+fn set_request_path(req: &mut Request<Incoming>, path: String) -> Result<()> {
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path,
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse()?);
+    *req.uri_mut() = Uri::from_parts(parts)?;
+    Ok(())
 }
 
-/// Find the local path for a request URI, converting directories to the
-/// `index.html` file.
-fn local_path_with_maybe_index(uri: &Uri, root_dir: &Path) -> Result<PathBuf> {
-    local_path_for_request(uri, root_dir).map(|mut p: PathBuf| {
-        if p.is_dir() {
-            p.push("index.html");
-            debug!("trying {} for directory URL", p.display());
-        } else {
-            trace!("trying path as from URL");
+/// Normalize an absolute URL path, collapsing repeated "/" and resolving "."
+/// and ".." segments.
+///
+/// Segments stay %-encoded, though "." and ".." are recognized in encoded
+/// form too, so that an encoded "/" remains part of its segment rather than
+/// becoming a separator. A ".." that would climb above the root is an error,
+/// as is a control character like an encoded NUL, which no file name should
+/// have. Paths that end with a directory, including "." and "..", end with
+/// "/".
+fn normalize_url_path(path: &str) -> Result<String> {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut ends_with_dir = false;
+
+    for segment in path.split('/') {
+        if has_control_chars(segment) {
+            warn!("control character in path: {}", truncate_for_log(path));
+            return Err(Error::UriControlChar);
         }
-        p
-    })
-}
-
-/// Map the request's URI to a local path
-fn local_path_for_request(uri: &Uri, root_dir: &Path) -> Result<PathBuf> {
-    debug!("raw URI: {}", uri);
+        let decoded = percent_decode_str(segment).decode_utf8_lossy();
+        ends_with_dir = true;
+        match decoded.as_ref() {
+            "" | "." => {}
+            ".." => {
+                if segments.pop().is_none() {
+                    warn!("path escapes the root: {}", truncate_for_log(path));
+                    return Err(Error::UriEscapesRoot);
+                }
+            }
+            _ => {
+                segments.push(segment);
+                ends_with_dir = false;
+            }
+        }
+    }
 
-    let request_path = uri.path();
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if segments.is_empty() || ends_with_dir {
+        normalized.push('/');
+    }
 
-    debug!("raw URI to path: {}", request_path);
+    Ok(normalized)
+}
 
-    // Trim off the url parameters starting with '?'
-    let end = request_path.find('?').unwrap_or(request_path.len());
-    let request_path = &request_path[0..end];
+/// Whether a %-encoded URL path segment decodes to anything with an ASCII
+/// control character, NUL to US or DEL.
+fn has_control_chars(segment: &str) -> bool {
+    percent_decode_str(segment).any(|b| b.is_ascii_control())
+}
 
-    // Convert %-encoding to actual values
-    let decoded = percent_decode_str(request_path);
-    let Ok(request_path) = decoded.decode_utf8() else {
-        error!("non utf-8 URL: {}", request_path);
-        return Err(Error::UriNotUtf8);
-    };
+/// The longest URI, in bytes, that is written to the log in full.
+const MAX_LOGGED_URI_LEN: usize = 256;
 
-    // Append the requested path to the root directory
-    let mut path = root_dir.to_owned();
-    if let Some(request_path) = request_path.strip_prefix('/') {
-        path.push(request_path);
-    } else {
-        warn!("found non-absolute path {}", request_path);
-        return Err(Error::UriNotAbsolute);
+/// Shorten a URI for logging, so that absurdly long ones don't flood the log.
+fn truncate_for_log(s: &str) -> Cow<'_, str> {
+    if s.len() <= MAX_LOGGED_URI_LEN {
+        return Cow::Borrowed(s);
     }
 
-    debug!("URL · path : {} · {}", uri, path.display());
-
-    Ok(path)
+    let mut end = MAX_LOGGED_URI_LEN;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    Cow::Owned(format!("{}... ({} bytes)", &s[..end], s.len()))
 }
 
 /// Create an error response if the request contains unsupported methods,
 /// headers, etc.
 fn handle_unsupported_request(
     req: &Request<Incoming>,
+    config: &Config,
 ) -> Option<Result<Response<BoxBody<Bytes, Error>>>> {
-    get_unsupported_request_message(req)
-        .map(|unsup| make_error_response_from_code_and_headers(unsup.code, unsup.headers))
+    let reason = unsupported_reason(req, config)?;
+    let path = error_page_path(config, req.uri());
+    Some(make_error_response_for_reason(reason, path))
 }
 
-/// Description of an unsupported request.
-struct Unsupported {
-    code: StatusCode,
-    headers: HeaderMap,
+/// The methods this server supports, for `Allow` headers.
+fn allowed_methods(config: &Config) -> HeaderValue {
+    HeaderValue::from_static(allowed_method_names(config))
+}
+
+/// The methods this server supports, as listed in `Allow` headers.
+fn allowed_method_names(config: &Config) -> &'static str {
+    if config.webdav {
+        "GET, HEAD, OPTIONS, PROPFIND"
+    } else {
+        "GET, HEAD, OPTIONS"
+    }
 }
 
-/// Create messages for unsupported requests.
-fn get_unsupported_request_message(req: &Request<Incoming>) -> Option<Unsupported> {
+/// Respond to an OPTIONS request with the allowed methods.
+///
+/// With `--webdav`, this also says which WebDAV class is supported, which
+/// clients check before anything else.
+fn options_response(config: &Config) -> Result<Response<BoxBody<Bytes, Error>>> {
+    let mut builder = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ALLOW, allowed_methods(config));
+    if config.webdav {
+        builder = builder.header("dav", "1");
+    }
+    builder
+        .body(
+            Empty::<Bytes>::new()
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .map_err(Error::from)
+}
+
+/// Why a request is unsupported, if it is.
+fn unsupported_reason(req: &Request<Incoming>, config: &Config) -> Option<Reason> {
+    let not_allowed = || Reason::MethodNotAllowed {
+        method: req.method().clone(),
+        allowed: allowed_method_names(config),
+    };
+
+    // Answered before the URI is looked at, since it isn't a path: CONNECT
+    // has a host and port.
+    // https://tools.ietf.org/html/rfc7231#section-6.6.2
+    if req.method() == Method::CONNECT {
+        return Some(Reason::NotAProxy);
+    }
+
+    // Echoing requests back can expose credentials to scripts.
+    if req.method() == Method::TRACE {
+        return Some(not_allowed());
+    }
+
+    // HEAD is served exactly like GET, and hyper drops the body, so the
+    // headers always match.
     // https://tools.ietf.org/html/rfc7231#section-6.5.5
-    if req.method() != Method::GET {
-        return Some(Unsupported {
-            code: StatusCode::METHOD_NOT_ALLOWED,
-            headers: HeaderMap::from_iter([(header::ALLOW, HeaderValue::from_static("GET"))]),
-        });
+    let get_or_head = req.method() == Method::GET || req.method() == Method::HEAD;
+    if !get_or_head && !(config.webdav && webdav::is_propfind(req)) {
+        return Some(not_allowed());
+    }
+
+    // hyper only sends `100 Continue` once the body is read, and no request
+    // body is ever read, so clients expecting one get the final response
+    // right away instead. Any other expectation can't be met.
+    // https://tools.ietf.org/html/rfc7231#section-5.1.1
+    let expect = req.headers().get(header::EXPECT);
+    if expect.is_some_and(|v| !v.as_bytes().eq_ignore_ascii_case(b"100-continue")) {
+        return Some(Reason::UnsupportedExpectation);
     }
 
     None
 }
 
-/// Turn any errors into an HTTP error response.
+/// The URL path to show on error pages for a request, unless
+/// `--quiet-errors` is set.
+///
+/// This is the URL path, never the local path, so error pages don't reveal
+/// where the root directory is.
+fn error_page_path<'a>(config: &Config, uri: &'a Uri) -> Option<&'a str> {
+    (!config.quiet_errors).then(|| uri.path())
+}
+
+/// Turn any errors into an HTTP error response, showing `path` on the error
+/// page if given.
 fn transform_error(
     resp: Result<Response<BoxBody<Bytes, Error>>>,
+    path: Option<&str>,
 ) -> Response<BoxBody<Bytes, Error>> {
     match resp {
         Ok(r) => r,
         Err(e) => {
-            let resp = make_error_response(e);
+            let resp = make_error_response(e, path);
             match resp {
                 Ok(r) => r,
                 Err(e) => {
@@ -354,45 +2386,136 @@ fn transform_error(
 }
 
 /// Convert an error to an HTTP error response future, with correct response code.
-fn make_error_response(e: Error) -> Result<Response<BoxBody<Bytes, Error>>> {
+fn make_error_response(e: Error, path: Option<&str>) -> Result<Response<BoxBody<Bytes, Error>>> {
     let resp = match e {
-        Error::Io(e) => make_io_error_response(e)?,
-        e => make_internal_server_error_response(e)?,
+        Error::Io(e) => make_io_error_response(e, path)?,
+        Error::UriControlChar => make_error_response_from_code(StatusCode::BAD_REQUEST, path)?,
+        Error::UriEscapesRoot => make_error_response_from_code(StatusCode::BAD_REQUEST, path)?,
+        Error::UriHostMismatch => make_error_response_from_code(StatusCode::BAD_REQUEST, path)?,
+        Error::UriEncodedSlash => make_error_response_from_code(StatusCode::NOT_FOUND, path)?,
+        #[cfg(windows)]
+        Error::UriUnsafeName => make_error_response_from_code(StatusCode::NOT_FOUND, path)?,
+        Error::FileTooLarge => make_error_response_for_reason(Reason::FileTooLarge, path)?,
+        e => make_internal_server_error_response(e, path)?,
     };
     Ok(resp)
 }
 
+/// Make an error response explaining why a request was refused, carrying
+/// the reason for `errorpages`.
+fn make_error_response_for_reason(
+    reason: Reason,
+    path: Option<&str>,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    let status = reason.status();
+    let body = render_html(&HtmlCfg {
+        title: format!("{status}"),
+        kind: PageKind::Error,
+        path: path.unwrap_or_default().to_string(),
+        body: format!("<p>{}</p>", handlebars::html_escape(&reason.message())),
+        ..HtmlCfg::default()
+    })?;
+    let mut resp = html_str_to_response_with_headers(body, status, reason.headers())?;
+    resp.extensions_mut().insert(reason);
+    Ok(resp)
+}
+
 /// Convert an error into a 500 internal server error, and log it.
-fn make_internal_server_error_response(err: Error) -> Result<Response<BoxBody<Bytes, Error>>> {
-    log_error_chain(&err);
-    let resp = make_error_response_from_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+fn make_internal_server_error_response(
+    err: Error,
+    path: Option<&str>,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    log_error_chain_once(&err);
+    let resp = make_error_response_from_code(StatusCode::INTERNAL_SERVER_ERROR, path)?;
     Ok(resp)
 }
 
-/// Handle the one special IO error (file not found) by returning a 404, otherwise
-/// return a 500.
-fn make_io_error_response(error: io::Error) -> Result<Response<BoxBody<Bytes, Error>>> {
+/// How long clients are told to wait when the root directory is unavailable.
+const UNAVAILABLE_RETRY_AFTER: u64 = 5;
+
+/// Whether an I/O error means the filesystem the root is on is unavailable
+/// for now, like a network mount that has dropped, rather than anything
+/// being wrong with the request or the server.
+fn is_unavailable_error(error: &io::Error) -> bool {
+    // EIO has the same number on every Unix.
+    #[cfg(unix)]
+    const EIO: i32 = 5;
+
+    match error.kind() {
+        io::ErrorKind::TimedOut
+        | io::ErrorKind::StaleNetworkFileHandle
+        | io::ErrorKind::NotConnected => true,
+        #[cfg(unix)]
+        _ if error.raw_os_error() == Some(EIO) => true,
+        _ => false,
+    }
+}
+
+/// Respond 503 when the root directory is unavailable for now, telling the
+/// client to try again soon and not to keep the response.
+fn make_unavailable_response(
+    error: io::Error,
+    path: Option<&str>,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    log_error_chain_once(&error);
+    let status = StatusCode::SERVICE_UNAVAILABLE;
+    let body = render_html(&HtmlCfg {
+        title: format!("{}", status),
+        kind: PageKind::Error,
+        path: path.unwrap_or_default().to_string(),
+        body: "<p>The files are temporarily unavailable. Try again soon.</p>".to_string(),
+        ..HtmlCfg::default()
+    })?;
+    let headers = HeaderMap::from_iter([
+        (
+            header::RETRY_AFTER,
+            HeaderValue::from(UNAVAILABLE_RETRY_AFTER),
+        ),
+        (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+    ]);
+    html_str_to_response_with_headers(body, status, headers)
+}
+
+/// Handle the IO errors that are the client's problem - missing files and
+/// files the server isn't allowed to read - with a 404 or 403, and a root
+/// that's unavailable for now with a 503, otherwise return a 500.
+fn make_io_error_response(
+    error: io::Error,
+    path: Option<&str>,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
     let resp = match error.kind() {
-        io::ErrorKind::NotFound => {
+        // A path like "/file.txt/extra" is not-a-directory.
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => {
             debug!("{}", error);
-            make_error_response_from_code(StatusCode::NOT_FOUND)?
+            make_error_response_from_code(StatusCode::NOT_FOUND, path)?
         }
-        _ => make_internal_server_error_response(Error::Io(error))?,
+        io::ErrorKind::PermissionDenied => {
+            debug!("{}", error);
+            make_error_response_for_reason(Reason::PermissionDenied, path)?
+        }
+        _ if is_unavailable_error(&error) => make_unavailable_response(error, path)?,
+        _ => make_internal_server_error_response(Error::Io(error), path)?,
     };
     Ok(resp)
 }
 
-/// Make an error response given an HTTP status code.
-fn make_error_response_from_code(status: StatusCode) -> Result<Response<BoxBody<Bytes, Error>>> {
-    make_error_response_from_code_and_headers(status, HeaderMap::new())
+/// Make an error response given an HTTP status code, and the requested path
+/// to show.
+fn make_error_response_from_code(
+    status: StatusCode,
+    path: Option<&str>,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    make_error_response_from_code_and_headers(status, HeaderMap::new(), path)
 }
 
-/// Make an error response given an HTTP status code and response headers.
+/// Make an error response given an HTTP status code, response headers, and
+/// the requested path to show.
 fn make_error_response_from_code_and_headers(
     status: StatusCode,
     headers: HeaderMap,
+    path: Option<&str>,
 ) -> Result<Response<BoxBody<Bytes, Error>>> {
-    let body = render_error_html(status)?;
+    let body = render_error_html(status, path)?;
     let resp = html_str_to_response_with_headers(body, status, headers)?;
     Ok(resp)
 }
@@ -406,6 +2529,10 @@ fn html_str_to_response(
 }
 
 /// Make an HTTP response from a HTML string and response headers.
+///
+/// Generated pages are always rendered in full before responding, however
+/// large, so they have an exact `Content-Length`, and a HEAD request gets
+/// the same headers as a GET without rendering any differently.
 fn html_str_to_response_with_headers(
     body: String,
     status: StatusCode,
@@ -425,30 +2552,580 @@ fn html_str_to_response_with_headers(
         .map_err(Error::from)
 }
 
-/// A handlebars HTML template.
-static HTML_TEMPLATE: &str = include_str!("template.html");
-
 /// The data for the handlebars HTML template. Handlebars will use serde to get
 /// the data out of the struct and mapped onto the template.
-#[derive(Serialize)]
+#[derive(Default, Serialize)]
 struct HtmlCfg {
     title: String,
+    /// What sort of page this is, for templates that show them differently.
+    kind: PageKind,
+    /// The URL of a stylesheet for the page, if it has one.
+    stylesheet: String,
+    /// Extra elements for the page's `<head>`, like scripts and stylesheets.
+    head: String,
+    /// The ancestors of the requested path, shown above the title as
+    /// `breadcrumbs`.
+    crumbs: Vec<ext::Crumb>,
+    /// The requested URL path of pages made from files and directories.
+    url_path: String,
+    /// The requested URL path, shown below the title of error pages.
+    path: String,
     body: String,
 }
 
+/// What sort of page is rendered, as `kind` in templates, so
+/// `{{#if (eq kind "error")}}` picks out error pages.
+#[derive(Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum PageKind {
+    #[default]
+    Page,
+    Listing,
+    Markdown,
+    Source,
+    Json,
+    Error,
+}
+
+/// Everything templates can use: the page's data, and what's the same for
+/// every page.
+#[derive(Serialize)]
+struct PageContext<'a> {
+    #[serde(flatten)]
+    page: &'a HtmlCfg,
+    /// `crumbs` as links.
+    breadcrumbs: String,
+    /// The server's version.
+    version: &'static str,
+    /// When the page was rendered, as an HTTP date.
+    generated: String,
+}
+
 /// Render an HTML page with handlebars, the template and the configuration data.
 fn render_html(cfg: &HtmlCfg) -> Result<String> {
-    let reg = Handlebars::new();
-    let rendered = reg
-        .render_template(HTML_TEMPLATE, &cfg)
-        .map_err(Error::TemplateRender)?;
-    Ok(rendered)
+    let context = PageContext {
+        page: cfg,
+        breadcrumbs: ext::breadcrumbs_html(&cfg.crumbs),
+        version: env!("CARGO_PKG_VERSION"),
+        generated: httpdate::fmt_http_date(SystemTime::now()),
+    };
+    templates::current().render_page(&context)
 }
 
-/// Render an HTML page from an HTTP status code
-fn render_error_html(status: StatusCode) -> Result<String> {
+/// Render an HTML page from an HTTP status code and the requested path
+fn render_error_html(status: StatusCode, path: Option<&str>) -> Result<String> {
     render_html(&HtmlCfg {
         title: format!("{status}"),
-        body: String::new(),
+        kind: PageKind::Error,
+        path: path.unwrap_or_default().to_string(),
+        ..HtmlCfg::default()
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Fixture};
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+    #[tokio::test]
+    async fn path_through_file_is_under_file() {
+        let fixture = Fixture::new(&[("file.txt", "hello")]);
+        let root = fixture.root();
+        assert!(is_under_file(&root.join("file.txt").join("extra"), root).await);
+        assert!(is_under_file(&root.join("file.txt").join("a").join("b"), root).await);
+        assert!(!is_under_file(&root.join("file.txt"), root).await);
+        assert!(!is_under_file(&root.join("missing").join("extra"), root).await);
+    }
+
+    #[tokio::test]
+    async fn file_as_directory_is_under_file() {
+        let fixture = Fixture::new(&[("file.txt", "hello"), ("dir/a.txt", "a")]);
+        let root = fixture.root();
+        let mut with_slash = root.join("file.txt").into_os_string();
+        with_slash.push(std::path::MAIN_SEPARATOR_STR);
+        assert!(is_under_file(Path::new(&with_slash), root).await);
+        assert!(!is_under_file(&root.join("dir").join(""), root).await);
+    }
+
+    #[tokio::test]
+    async fn paths_through_files_are_not_found() {
+        let fixture = Fixture::new(&[("file.txt", "hello")]);
+        let addr = testing::start(fixture.config(&[])).await;
+        for path in ["/file.txt/extra", "/file.txt/", "/file.txt/a/b"] {
+            let reply = testing::get(addr, path).await;
+            assert_eq!(reply.status, StatusCode::NOT_FOUND, "{path}");
+        }
+        let reply = testing::get(addr, "/file.txt").await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.body, "hello");
+    }
+
+    #[test]
+    fn normalize_ugly_paths() {
+        let cases = [
+            ("/", "/"),
+            ("//foo///bar/./baz", "/foo/bar/baz"),
+            ("/foo/bar/.", "/foo/bar/"),
+            ("/foo/bar/./", "/foo/bar/"),
+            ("/a/../b", "/b"),
+            ("/a/b/..", "/a/"),
+            ("/a/..", "/"),
+            ("/a/%2E%2E/b", "/b"),
+            ("/a/%2e/b", "/a/b"),
+            ("/a%2Fb/c", "/a%2Fb/c"),
+            ("/a/%2F/../b", "/a/b"),
+            ("/a%20b/", "/a%20b/"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(normalize_url_path(path).unwrap(), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn normalize_refuses_escaping_the_root() {
+        for path in ["/..", "/../a", "/a/../..", "/%2E%2E", "/%2e%2E/a", "//../"] {
+            assert!(
+                matches!(normalize_url_path(path), Err(Error::UriEscapesRoot)),
+                "{path}"
+            );
+        }
+        assert!(matches!(
+            normalize_url_path("/a%00b"),
+            Err(Error::UriControlChar)
+        ));
+    }
+
+    #[test]
+    fn local_path_keeps_encoded_slash_in_its_segment() {
+        let root = Path::new("root");
+        assert_eq!(
+            local_path_for_url_path("/foo/bar.txt", root).unwrap(),
+            root.join("foo").join("bar.txt")
+        );
+        assert_eq!(
+            local_path_for_url_path("/a%20b?x=1", root).unwrap(),
+            root.join("a b")
+        );
+        assert!(matches!(
+            local_path_for_url_path("/foo%2Fbar.txt", root),
+            Err(Error::UriEncodedSlash)
+        ));
+        assert!(matches!(
+            local_path_for_url_path("/%2E%2E/secret", root),
+            Err(Error::UriEscapesRoot)
+        ));
+        assert!(matches!(
+            local_path_for_url_path("foo", root),
+            Err(Error::UriNotAbsolute)
+        ));
+    }
+
+    #[tokio::test]
+    async fn ugly_paths_are_served_normalized() {
+        let fixture = Fixture::new(&[("foo/bar/baz.txt", "baz")]);
+        let addr = testing::start(fixture.config(&[])).await;
+        for path in ["//foo///bar/./baz.txt", "/foo/../foo/bar/baz.txt"] {
+            let reply = testing::get(addr, path).await;
+            assert_eq!(reply.status, StatusCode::OK, "{path}");
+            assert_eq!(reply.body, "baz");
+        }
+        for path in ["/..", "/%2E%2E", "/foo/../../etc"] {
+            let reply = testing::get(addr, path).await;
+            assert_eq!(reply.status, StatusCode::BAD_REQUEST, "{path}");
+        }
+        let reply = testing::get(addr, "/foo%2Fbar/baz.txt").await;
+        assert_eq!(reply.status, StatusCode::NOT_FOUND);
+    }
+
+    /// The fields spans are created with or record later, as (span name,
+    /// field name, value) in the order they come.
+    #[derive(Clone, Default)]
+    struct SpanFields(Arc<Mutex<Vec<(String, String, String)>>>);
+
+    impl SpanFields {
+        fn get(&self, span: &str, field: &str) -> Option<String> {
+            let fields = self.0.lock().unwrap();
+            fields
+                .iter()
+                .find(|(s, f, _)| s == span && f == field)
+                .map(|(_, _, value)| value.clone())
+        }
+    }
+
+    struct Collect<'a> {
+        span: &'static str,
+        fields: &'a mut Vec<(String, String, String)>,
+    }
+
+    impl tracing::field::Visit for Collect<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+            let entry = (
+                self.span.to_string(),
+                field.name().to_string(),
+                format!("{value:?}"),
+            );
+            self.fields.push(entry);
+        }
+    }
+
+    impl<S> tracing_subscriber::Layer<S> for SpanFields
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = self.0.lock().unwrap();
+            let span = attrs.metadata().name();
+            attrs.record(&mut Collect {
+                span,
+                fields: &mut fields,
+            });
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let Some(span) = ctx.span(id) else {
+                return;
+            };
+            let mut fields = self.0.lock().unwrap();
+            let span = span.name();
+            values.record(&mut Collect {
+                span,
+                fields: &mut fields,
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn spans_record_peer_status_and_duration() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        // The test runtime runs every task on this thread, so the default
+        // subscriber sees the server's spans too.
+        let fields = SpanFields::default();
+        let subscriber = tracing_subscriber::registry().with(fields.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let fixture = Fixture::new(&[("a.txt", "a")]);
+        let addr = testing::start(fixture.config(&[])).await;
+        let reply = testing::get(addr, "/a.txt?x=1").await;
+        assert_eq!(reply.status, StatusCode::OK);
+
+        let peer = fields.get("conn", "peer").expect("conn span has a peer");
+        assert!(peer.starts_with("127.0.0.1:"), "{peer}");
+        assert_eq!(fields.get("request", "method").as_deref(), Some("GET"));
+        assert_eq!(fields.get("request", "path").as_deref(), Some("/a.txt"));
+        assert_eq!(fields.get("request", "status").as_deref(), Some("200"));
+        assert!(fields.get("request", "duration").is_some());
+    }
+
+    async fn body_text(resp: Response<BoxBody<Bytes, Error>>) -> String {
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8_lossy(&body).into_owned()
+    }
+
+    #[test]
+    fn io_error_status_by_kind() {
+        let cases = [
+            (io::ErrorKind::NotFound, StatusCode::NOT_FOUND),
+            (io::ErrorKind::NotADirectory, StatusCode::NOT_FOUND),
+            (io::ErrorKind::PermissionDenied, StatusCode::FORBIDDEN),
+            (io::ErrorKind::TimedOut, StatusCode::SERVICE_UNAVAILABLE),
+            (
+                io::ErrorKind::InvalidData,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (io::ErrorKind::Other, StatusCode::INTERNAL_SERVER_ERROR),
+        ];
+        for (kind, status) in cases {
+            let resp = make_io_error_response(io::Error::from(kind), Some("/a.txt")).unwrap();
+            assert_eq!(resp.status(), status, "{kind:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn permission_denied_hides_the_local_path() {
+        let local = "/srv/private/root/a.txt";
+        let error = io::Error::new(io::ErrorKind::PermissionDenied, local);
+        let resp = make_io_error_response(error, Some("/a.txt")).unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = body_text(resp).await;
+        assert!(!body.contains("/srv/private"), "{body}");
+    }
+
+    /// Opening a FIFO blocks until something writes to it, so a request for
+    /// one must be refused without opening it. The server runs on other
+    /// threads, so the timeout fires even if it blocks one.
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fifo_is_refused_promptly() {
+        let fixture = Fixture::new(&[]);
+        let made = std::process::Command::new("mkfifo")
+            .arg(fixture.root().join("pipe"))
+            .status()
+            .expect("run mkfifo");
+        assert!(made.success());
+
+        let cases: [(&[&str], StatusCode); 2] = [
+            (&[], StatusCode::NOT_FOUND),
+            (&["--forbid-special-files"], StatusCode::FORBIDDEN),
+        ];
+        for (args, status) in cases {
+            let addr = testing::start(fixture.config(args)).await;
+            let reply = tokio::time::timeout(Duration::from_secs(5), testing::get(addr, "/pipe"))
+                .await
+                .expect("answered without waiting for a writer");
+            assert_eq!(reply.status, status, "{args:?}");
+        }
+    }
+
+    /// Names that open devices or other files on Windows, in any directory.
+    const UNSAFE_WINDOWS_NAMES: [&str; 14] = [
+        "con",
+        "CON.txt",
+        "nul ",
+        "Aux.tar.gz",
+        "prn",
+        "com1",
+        "LPT9.log",
+        "com\u{b9}",
+        "conin$",
+        "CONOUT$.txt",
+        "a\\b",
+        "c:foo",
+        "file.txt:stream",
+        "..\\secret",
+    ];
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_names_that_are_not_plain() {
+        for name in UNSAFE_WINDOWS_NAMES {
+            assert!(
+                !is_plain_windows_name(std::ffi::OsStr::new(name)),
+                "{name:?}"
+            );
+        }
+        for name in [
+            "console", "com10", "lpt", "file.txt", "aux_file", "nully", "conin",
+        ] {
+            assert!(
+                is_plain_windows_name(std::ffi::OsStr::new(name)),
+                "{name:?}"
+            );
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_device_paths_are_refused() {
+        let root = Path::new("root");
+        for path in ["/con", "/sub/NUL.txt", "/conin$", "/a%5Cb", "/c%3Afoo"] {
+            assert!(
+                matches!(
+                    local_path_for_url_path(path, root),
+                    Err(Error::UriUnsafeName)
+                ),
+                "{path}"
+            );
+        }
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn windows_device_names_are_plain_elsewhere() {
+        let root = Path::new("root");
+        for name in UNSAFE_WINDOWS_NAMES {
+            let encoded = utf8_percent_encode(name, NON_ALPHANUMERIC);
+            let path = local_path_for_url_path(&format!("/{encoded}"), root).unwrap();
+            assert_eq!(path, root.join(name));
+        }
+    }
+
+    /// On every platform, these names are only ever files that aren't there.
+    #[tokio::test]
+    async fn windows_device_names_are_not_found() {
+        let fixture = Fixture::new(&[("a.txt", "a")]);
+        let addr = testing::start(fixture.config(&[])).await;
+        for name in UNSAFE_WINDOWS_NAMES {
+            let encoded = utf8_percent_encode(name, NON_ALPHANUMERIC);
+            let reply = testing::get(addr, &format!("/{encoded}")).await;
+            assert_eq!(reply.status, StatusCode::NOT_FOUND, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn index_candidates_in_order() {
+        let fixture = Fixture::new(&[]);
+        let config = fixture.config(&["--index", "a.html", "--index", "b.html", "-x"]);
+        assert_eq!(
+            config.index_candidates(),
+            ["a.html", "b.html", "index.md", "README.md"]
+        );
+        let config = fixture.config(&["--index", "a.html"]);
+        assert_eq!(config.index_candidates(), ["a.html"]);
+        let config = fixture.config(&["-x"]);
+        assert_eq!(
+            config.index_candidates(),
+            ["index.html", "index.md", "README.md"]
+        );
+    }
+
+    #[test]
+    fn index_precedence_over_every_subset() {
+        // In order of precedence, with extensions.
+        let names = ["home.html", "start.html", "index.md", "README.md"];
+        for present in 0..(1 << names.len()) {
+            let fixture = Fixture::new(&[]);
+            fixture.mkdir("dir");
+            let dir = fixture.root().join("dir");
+            let is_present = |i: usize| present & (1 << i) != 0;
+            for (i, name) in names.iter().enumerate() {
+                if is_present(i) {
+                    fixture.write(&format!("dir/{name}"), name);
+                }
+            }
+            let first_of = |from: usize, default: &str| {
+                let found = (from..names.len()).find(|&i| is_present(i));
+                dir.join(found.map_or(default, |i| names[i]))
+            };
+
+            let index = ["--index", "home.html", "--index", "start.html"];
+            let config = fixture.config(&[&index[..], &["-x"][..]].concat());
+            assert_eq!(
+                local_path_with_maybe_index(&config, &dir),
+                first_of(0, "home.html"),
+                "{present:04b} with extensions"
+            );
+
+            // Without extensions, only the --index names are tried.
+            let config = fixture.config(&index);
+            let expected = if is_present(0) || !is_present(1) {
+                dir.join("home.html")
+            } else {
+                dir.join("start.html")
+            };
+            assert_eq!(
+                local_path_with_maybe_index(&config, &dir),
+                expected,
+                "{present:04b} without extensions"
+            );
+
+            // Without --index, there's never an index.html here.
+            let config = fixture.config(&["-x"]);
+            assert_eq!(
+                local_path_with_maybe_index(&config, &dir),
+                first_of(2, "index.html"),
+                "{present:04b} without --index"
+            );
+        }
+    }
+
+    /// A precomposed name, "café.txt", and a decomposed one, "résumé", whose
+    /// accents are combining characters.
+    fn unicode_fixture() -> Fixture {
+        Fixture::new(&[
+            ("caf\u{e9}.txt", "precomposed"),
+            ("re\u{301}sume\u{301}/a.txt", "decomposed"),
+        ])
+    }
+
+    #[tokio::test]
+    async fn normalize_unicode_finds_the_other_form() {
+        let fixture = unicode_fixture();
+        let addr = testing::start(fixture.config(&["--normalize-unicode"])).await;
+        let cases = [
+            ("/cafe%CC%81.txt", "precomposed"),
+            ("/caf%C3%A9.txt", "precomposed"),
+            ("/r%C3%A9sum%C3%A9/a.txt", "decomposed"),
+            ("/re%CC%81sume%CC%81/a.txt", "decomposed"),
+        ];
+        for (path, body) in cases {
+            let reply = testing::get(addr, path).await;
+            assert_eq!(reply.status, StatusCode::OK, "{path}");
+            assert_eq!(reply.body, body, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn without_normalize_unicode_forms_differ() {
+        let fixture = unicode_fixture();
+        let addr = testing::start(fixture.config(&[])).await;
+        assert_eq!(
+            testing::get(addr, "/caf%C3%A9.txt").await.status,
+            StatusCode::OK
+        );
+        // Some filesystems, like APFS, ignore normalization themselves.
+        if cfg!(target_os = "linux") {
+            for path in ["/cafe%CC%81.txt", "/r%C3%A9sum%C3%A9/a.txt"] {
+                let reply = testing::get(addr, path).await;
+                assert_eq!(reply.status, StatusCode::NOT_FOUND, "{path}");
+            }
+        }
+    }
+
+    /// Only where the filesystem keeps the forms apart, so the requested one
+    /// doesn't exist.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fallback_path_in_the_form_on_disk() {
+        let fixture = unicode_fixture();
+        let fold = Fold {
+            case: false,
+            unicode: true,
+        };
+        let found = fallback::find_url_path(fixture.root(), "/r%C3%A9sum%C3%A9/a.txt", fold)
+            .expect("a match");
+        let decoded = percent_decode_str(&found).decode_utf8().unwrap();
+        assert_eq!(decoded, "/re\u{301}sume\u{301}/a.txt");
+        assert_eq!(
+            fallback::find_url_path(fixture.root(), "/caf%C3%A9.txt", fold),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn hide_strict_hides_what_is_inside_hidden_directories() {
+        let fixture = Fixture::new(&[
+            ("secret/a.txt", "a"),
+            ("secret/sub/b.txt", "b"),
+            ("docs/secret/c.txt", "c"),
+            ("secretive.txt", "not hidden"),
+        ]);
+        fixture.mkdir("secret/empty");
+
+        let addr = testing::start(fixture.config(&["--hide", "secret", "--hide-strict"])).await;
+        let hidden = [
+            "/secret",
+            "/secret/",
+            "/secret/a.txt",
+            "/secret/sub/b.txt",
+            "/secret/empty/",
+            "/secret/missing.txt",
+            "/docs/secret/c.txt",
+        ];
+        for path in hidden {
+            let reply = testing::get(addr, path).await;
+            assert_eq!(reply.status, StatusCode::NOT_FOUND, "{path}");
+        }
+        assert_eq!(
+            testing::get(addr, "/secretive.txt").await.status,
+            StatusCode::OK
+        );
+
+        // Without --hide-strict, hidden paths are only left out of listings.
+        let addr = testing::start(fixture.config(&["--hide", "secret"])).await;
+        let reply = testing::get(addr, "/secret/sub/b.txt").await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.body, "b");
+    }
+}
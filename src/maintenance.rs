@@ -0,0 +1,33 @@
+//! Maintenance mode, in which every request is answered 503, switched on by
+//! `--maintenance` and toggled at runtime by SIGUSR1.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::info;
+
+static ON: AtomicBool = AtomicBool::new(false);
+
+/// Whether the server is in maintenance mode.
+pub fn is_on() -> bool {
+    ON.load(Ordering::Relaxed)
+}
+
+/// Switch maintenance mode on or off.
+pub fn set(on: bool) {
+    ON.store(on, Ordering::Relaxed);
+}
+
+/// Toggle maintenance mode on every SIGUSR1.
+#[cfg(unix)]
+pub async fn toggle_on_sigusr1() -> crate::error::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = signal(SignalKind::user_defined1())?;
+    while signals.recv().await.is_some() {
+        let on = !ON.fetch_xor(true, Ordering::Relaxed);
+        info!(
+            "received SIGUSR1, maintenance mode {}",
+            if on { "on" } else { "off" }
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,239 @@
+//! Policies for URL prefixes, from `--policy PREFIX:KEY=VALUE`, so one
+//! server can let clients cache `/assets` forever and never store
+//! `/api-mocks`.
+//!
+//! A prefix matches itself and every path under it, and "/" matches every
+//! path. Each key is set by the most specific prefix that sets it, so a
+//! policy for `/assets/vendor` overrides one for `/assets`, which overrides
+//! one for `/`, key by key. Among policies for the same prefix and key, the
+//! last one given wins.
+
+use http::header::{self, HeaderMap, HeaderValue};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// A `--policy` rule.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    /// The URL prefix, without any trailing "/" unless it's "/".
+    prefix: String,
+    key: Key,
+    /// The value, already checked to be a valid header value.
+    value: HeaderValue,
+}
+
+/// What a policy sets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Key {
+    /// The `Cache-Control` of successful responses, overriding `--cache` and
+    /// `--immutable`. `--no-cache` still wins.
+    CacheControl,
+    /// The `Access-Control-Allow-Origin` of every response, "*" or an
+    /// origin.
+    Cors,
+}
+
+impl Key {
+    const ALL: [Key; 2] = [Key::CacheControl, Key::Cors];
+
+    fn name(self) -> &'static str {
+        match self {
+            Key::CacheControl => "cache-control",
+            Key::Cors => "cors",
+        }
+    }
+}
+
+impl Policy {
+    /// Whether the prefix matches a URL path: it's the path or a directory
+    /// containing it.
+    fn matches(&self, path: &str) -> bool {
+        self.prefix == "/"
+            || path
+                .strip_prefix(&self.prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+/// The policies in effect for a URL path, one for each key that any
+/// matching policy sets.
+pub fn effective<'a>(policies: &'a [Policy], path: &str) -> Vec<&'a Policy> {
+    Key::ALL
+        .into_iter()
+        .filter_map(|key| {
+            policies
+                .iter()
+                .filter(|p| p.key == key && p.matches(path))
+                .max_by_key(|p| p.prefix.len())
+        })
+        .collect()
+}
+
+/// Add the headers the policies in effect set to a response.
+///
+/// `is_success` is whether the response is successful, and `may_cache`
+/// whether it may be cached at all, which `--no-cache` rules out.
+pub fn apply(policies: &[&Policy], is_success: bool, may_cache: bool, headers: &mut HeaderMap) {
+    for policy in policies {
+        match policy.key {
+            Key::CacheControl if is_success && may_cache => {
+                headers.insert(header::CACHE_CONTROL, policy.value.clone());
+            }
+            Key::CacheControl => {}
+            Key::Cors => {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, policy.value.clone());
+            }
+        }
+    }
+}
+
+/// The policies, one per line, as `--print-routes` shows them: the broadest
+/// prefixes first, so later lines override earlier ones.
+pub fn describe(policies: &[Policy]) -> Vec<String> {
+    let mut sorted: Vec<&Policy> = policies.iter().collect();
+    sorted.sort_by_key(|p| p.prefix.len());
+    sorted.iter().map(|p| p.to_string()).collect()
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Policy, String> {
+        let Some((prefix, setting)) = s.split_once(':') else {
+            return Err("expected PREFIX:KEY=VALUE".to_string());
+        };
+        let Some((key, value)) = setting.split_once('=') else {
+            return Err("expected PREFIX:KEY=VALUE".to_string());
+        };
+        if !prefix.starts_with('/') {
+            return Err("PREFIX must be a path starting with /".to_string());
+        }
+
+        let key = Key::ALL
+            .into_iter()
+            .find(|k| k.name() == key.trim())
+            .ok_or_else(|| {
+                let names: Vec<&str> = Key::ALL.iter().map(|k| k.name()).collect();
+                format!(
+                    "unknown key {:?}; expected one of {}",
+                    key,
+                    names.join(", ")
+                )
+            })?;
+        let value = value.trim();
+        if value.is_empty() {
+            return Err("VALUE can't be empty".to_string());
+        }
+        let value =
+            HeaderValue::from_str(value).map_err(|_| "VALUE must be a valid header value")?;
+
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => "/",
+            trimmed => trimmed,
+        };
+        Ok(Policy {
+            prefix: prefix.to_string(),
+            key,
+            value,
+        })
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = String::from_utf8_lossy(self.value.as_bytes());
+        write!(f, "{}:{}={}", self.prefix, self.key.name(), value)
+    }
+}
+
+impl Serialize for Policy {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policies(specs: &[&str]) -> Vec<Policy> {
+        specs.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    /// The headers a successful, cacheable response for `path` gets.
+    fn headers_for(policies: &[Policy], path: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        apply(&effective(policies, path), true, true, &mut headers);
+        headers
+    }
+
+    fn cache_control(policies: &[Policy], path: &str) -> Option<String> {
+        let headers = headers_for(policies, path);
+        let value = headers.get(header::CACHE_CONTROL)?;
+        Some(value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn most_specific_prefix_wins() {
+        let p = policies(&[
+            "/assets/img:cache-control=max-age=60",
+            "/assets:cache-control=max-age=31536000, immutable",
+            "/:cache-control=no-store",
+        ]);
+        let cases = [
+            ("/assets/img/logo.png", "max-age=60"),
+            ("/assets/img", "max-age=60"),
+            ("/assets/img/", "max-age=60"),
+            ("/assets/app.js", "max-age=31536000, immutable"),
+            ("/assets/imgs/a.png", "max-age=31536000, immutable"),
+            ("/assets", "max-age=31536000, immutable"),
+            ("/assetsfoo/a.js", "no-store"),
+            ("/index.html", "no-store"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(cache_control(&p, path).as_deref(), Some(expected), "{path}");
+        }
+    }
+
+    #[test]
+    fn keys_are_overridden_one_by_one() {
+        let p = policies(&[
+            "/:cors=*",
+            "/assets:cache-control=max-age=600",
+            "/assets/img:cors=https://example.com",
+        ]);
+        let headers = headers_for(&p, "/assets/img/a.png");
+        assert_eq!(headers[header::CACHE_CONTROL], "max-age=600");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        let headers = headers_for(&p, "/other");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!headers.contains_key(header::CACHE_CONTROL));
+    }
+
+    #[test]
+    fn last_policy_for_a_prefix_wins() {
+        let p = policies(&[
+            "/assets/:cache-control=max-age=1",
+            "/assets:cache-control=max-age=2",
+        ]);
+        assert_eq!(
+            cache_control(&p, "/assets/a.js").as_deref(),
+            Some("max-age=2")
+        );
+    }
+
+    #[test]
+    fn cache_control_only_for_cacheable_successes() {
+        let p = policies(&["/:cache-control=max-age=60", "/:cors=*"]);
+        for (is_success, may_cache) in [(false, true), (true, false)] {
+            let mut headers = HeaderMap::new();
+            apply(&effective(&p, "/a"), is_success, may_cache, &mut headers);
+            assert!(!headers.contains_key(header::CACHE_CONTROL));
+            assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Finding the real client of requests forwarded by a trusted reverse proxy.
+
+use http::HeaderMap;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// An IP network, like "10.0.0.0/8", or a single address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// The length of the network prefix, 32 or 128 for a single address.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Whether the network contains an address. IPv4 addresses mapped into
+    /// IPv6 count as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("{e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|e| format!("invalid prefix: {e}"))?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(format!("prefix can be at most {max}"));
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Find the client that proxies forwarded a request for, from
+/// `X-Forwarded-For`.
+///
+/// Each proxy appends the address it got the request from, so the client is
+/// the right-most address that isn't one of our trusted proxies. Anything to
+/// the left of that could have been made up by the client. Returns `None` if
+/// there's no header, or it can't be parsed.
+pub fn forwarded_for(headers: &HeaderMap, is_trusted: impl Fn(IpAddr) -> bool) -> Option<IpAddr> {
+    let mut hops = Vec::new();
+    for value in headers.get_all("x-forwarded-for") {
+        for hop in value.to_str().ok()?.split(',') {
+            hops.push(parse_hop(hop.trim())?);
+        }
+    }
+
+    let mut client = None;
+    for hop in hops.into_iter().rev() {
+        client = Some(hop);
+        if !is_trusted(hop) {
+            break;
+        }
+    }
+    client
+}
+
+/// Parse an `X-Forwarded-For` entry, which some proxies give with a port.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    /// Trusting the proxies on 10.0.0.0/8.
+    fn trusted(hop: IpAddr) -> bool {
+        "10.0.0.0/8".parse::<Cidr>().unwrap().contains(hop)
+    }
+
+    #[test]
+    fn right_most_untrusted_hop_wins() {
+        let h = headers(&["203.0.113.9, 198.51.100.7, 10.0.0.2, 10.0.0.1"]);
+        assert_eq!(forwarded_for(&h, trusted), ip("198.51.100.7"));
+        let h = headers(&["203.0.113.9, 198.51.100.7"]);
+        assert_eq!(forwarded_for(&h, trusted), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn all_hops_trusted() {
+        let h = headers(&["10.0.0.3, 10.0.0.2, 10.0.0.1"]);
+        assert_eq!(forwarded_for(&h, trusted), ip("10.0.0.3"));
+    }
+
+    #[test]
+    fn multiple_header_lines() {
+        let h = headers(&["203.0.113.9, 198.51.100.7", "10.0.0.2", "10.0.0.1"]);
+        assert_eq!(forwarded_for(&h, trusted), ip("198.51.100.7"));
+        let h = headers(&["10.0.0.2", "203.0.113.9"]);
+        assert_eq!(forwarded_for(&h, trusted), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn hops_with_ports() {
+        let h = headers(&["198.51.100.7:5123, 10.0.0.1:80"]);
+        assert_eq!(forwarded_for(&h, trusted), ip("198.51.100.7"));
+        let h = headers(&["[2001:db8::1]:443, 10.0.0.1"]);
+        assert_eq!(forwarded_for(&h, trusted), ip("2001:db8::1"));
+        let h = headers(&["2001:db8::2"]);
+        assert_eq!(forwarded_for(&h, trusted), ip("2001:db8::2"));
+    }
+
+    #[test]
+    fn unparsable_hop_is_none() {
+        for value in [
+            "unknown, 10.0.0.1",
+            "198.51.100.7, not-an-ip",
+            "198.51.100.7,,10.0.0.1",
+        ] {
+            assert_eq!(forwarded_for(&headers(&[value]), trusted), None, "{value}");
+        }
+        let h = headers(&["198.51.100.7", "_hidden"]);
+        assert_eq!(forwarded_for(&h, trusted), None);
+        assert_eq!(forwarded_for(&HeaderMap::new(), trusted), None);
+    }
+}
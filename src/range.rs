@@ -0,0 +1,119 @@
+//! Parsing `Range` request headers, for resuming downloads and seeking in
+//! media.
+
+use http::header::{self, HeaderMap};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// The most ranges served in one response, after merging overlapping ones.
+/// Requests for more get the whole file, so that a small request can't ask
+/// for a response many times the size of the file.
+const MAX_RANGES: usize = 16;
+
+/// What to send for a request, given its `Range` header.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// The whole file, as there is no usable `Range` header.
+    Full,
+    /// These inclusive byte ranges, in order and not overlapping.
+    Partial(Vec<(u64, u64)>),
+    /// None of the requested ranges are in the file.
+    Unsatisfiable,
+}
+
+impl Ranges {
+    /// How many bytes of a file this long are sent: all of them, those in
+    /// the ranges, or none.
+    pub fn byte_count(&self, file_len: u64) -> u64 {
+        match self {
+            Ranges::Full => file_len,
+            Ranges::Partial(ranges) => ranges.iter().map(|(start, end)| end - start + 1).sum(),
+            Ranges::Unsatisfiable => 0,
+        }
+    }
+}
+
+/// Work out the ranges of a file of `len` bytes to send.
+///
+/// Headers that can't be parsed are ignored, as the RFC allows. `If-Range`
+/// is left to `conditional::evaluate`.
+pub fn parse(headers: &HeaderMap, len: u64) -> Ranges {
+    let Some(value) = headers.get(header::RANGE) else {
+        return Ranges::Full;
+    };
+    let Some(specs) = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().strip_prefix("bytes="))
+    else {
+        return Ranges::Full;
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return Ranges::Full;
+        };
+        let (start, end) = match (start.trim(), end.trim()) {
+            // The last `suffix` bytes.
+            ("", suffix) => {
+                let Ok(suffix) = suffix.parse::<u64>() else {
+                    return Ranges::Full;
+                };
+                if suffix == 0 || len == 0 {
+                    continue;
+                }
+                (len.saturating_sub(suffix), len - 1)
+            }
+            // From `start` to the end.
+            (start, "") => {
+                let Ok(start) = start.parse::<u64>() else {
+                    return Ranges::Full;
+                };
+                if start >= len {
+                    continue;
+                }
+                (start, len - 1)
+            }
+            (start, end) => {
+                let (Ok(start), Ok(end)) = (start.parse::<u64>(), end.parse::<u64>()) else {
+                    return Ranges::Full;
+                };
+                if end < start {
+                    return Ranges::Full;
+                }
+                if start >= len {
+                    continue;
+                }
+                (start, end.min(len - 1))
+            }
+        };
+        ranges.push((start, end));
+    }
+
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    // Merge overlapping and adjacent ranges.
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    if merged.len() > MAX_RANGES {
+        return Ranges::Full;
+    }
+    Ranges::Partial(merged)
+}
+
+/// Make a boundary for a `multipart/byteranges` response that won't turn up
+/// in the file by chance.
+pub fn boundary() -> String {
+    let random = || RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", random(), random())
+}
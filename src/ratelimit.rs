@@ -0,0 +1,65 @@
+//! Per-client rate limiting, with a token bucket for each client address.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How often to forget clients that haven't been limited lately.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The buckets of every client seen lately.
+///
+/// These live outside the configuration so that they survive reloads.
+static BUCKETS: LazyLock<Mutex<Buckets>> = LazyLock::new(|| {
+    Mutex::new(Buckets {
+        buckets: HashMap::new(),
+        last_cleanup: Instant::now(),
+    })
+});
+
+struct Buckets {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_cleanup: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Take a token from a client's bucket, which refills at `rate` tokens per
+/// second, up to a burst of twice that.
+///
+/// Returns how long the client should wait if the bucket is empty.
+pub fn check(ip: IpAddr, rate: u32) -> Option<Duration> {
+    let now = Instant::now();
+    let rate = f64::from(rate);
+    let burst = rate * 2.0;
+    let refilled = |bucket: &Bucket| {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * rate).min(burst)
+    };
+
+    let mut state = BUCKETS.lock().unwrap_or_else(PoisonError::into_inner);
+
+    // A full bucket is the same as no bucket, so drop those.
+    if now.duration_since(state.last_cleanup) > CLEANUP_INTERVAL {
+        state.buckets.retain(|_, bucket| refilled(bucket) < burst);
+        state.last_cleanup = now;
+    }
+
+    let bucket = state.buckets.entry(ip).or_insert(Bucket {
+        tokens: burst,
+        updated: now,
+    });
+    bucket.tokens = refilled(bucket);
+    bucket.updated = now;
+
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        None
+    } else {
+        Some(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+    }
+}
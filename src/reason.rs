@@ -0,0 +1,105 @@
+//! Why a request was refused, for an explanation on its error page.
+//!
+//! A response made for a reason carries it in its extensions, so JSON errors
+//! and error templates can explain it too. Explanations never name local
+//! paths.
+
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, StatusCode};
+
+/// Why a request was refused.
+#[derive(Clone, Debug)]
+pub enum Reason {
+    /// A method the server doesn't handle, with the ones it does, like
+    /// "GET, HEAD, OPTIONS".
+    MethodNotAllowed {
+        method: Method,
+        allowed: &'static str,
+    },
+    /// `CONNECT`, which only proxies handle.
+    NotAProxy,
+    /// An `Expect` other than 100-continue.
+    UnsupportedExpectation,
+    /// A URI longer than `--max-uri-length`.
+    UriTooLong { limit: usize },
+    /// A file larger than `--max-file-size`.
+    FileTooLarge,
+    /// A file the server isn't permitted to read.
+    PermissionDenied,
+    /// Something that isn't a regular file, with `--forbid-special-files`.
+    SpecialFile,
+    /// A client `--allow` and `--deny` keep out.
+    ClientDenied,
+    /// A WebDAV `PROPFIND` of the whole tree.
+    InfiniteDepth,
+}
+
+impl Reason {
+    /// The status to respond with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Reason::MethodNotAllowed { .. } => StatusCode::METHOD_NOT_ALLOWED,
+            Reason::NotAProxy => StatusCode::NOT_IMPLEMENTED,
+            Reason::UnsupportedExpectation => StatusCode::EXPECTATION_FAILED,
+            Reason::UriTooLong { .. } => StatusCode::URI_TOO_LONG,
+            Reason::FileTooLarge
+            | Reason::PermissionDenied
+            | Reason::SpecialFile
+            | Reason::ClientDenied
+            | Reason::InfiniteDepth => StatusCode::FORBIDDEN,
+        }
+    }
+
+    /// Headers the response needs, like the `Allow` of a 405.
+    pub fn headers(&self) -> HeaderMap {
+        match self {
+            Reason::MethodNotAllowed { allowed, .. } => {
+                HeaderMap::from_iter([(header::ALLOW, HeaderValue::from_static(allowed))])
+            }
+            _ => HeaderMap::new(),
+        }
+    }
+
+    /// The explanation, as plain text.
+    pub fn message(&self) -> String {
+        match self {
+            Reason::MethodNotAllowed { method, .. } if method == Method::TRACE => {
+                "TRACE is disabled, as echoing requests back can expose credentials to \
+                 scripts."
+                    .to_string()
+            }
+            Reason::MethodNotAllowed { method, allowed } if method.as_str() == "PROPFIND" => {
+                format!("PROPFIND needs --webdav. This server only handles {allowed}.")
+            }
+            Reason::MethodNotAllowed { method, allowed } => format!(
+                "This server got a {method} request, but only handles {allowed}. It \
+                 serves files read-only, and never accepts uploads."
+            ),
+            Reason::NotAProxy => "This server is not a proxy.".to_string(),
+            Reason::UnsupportedExpectation => {
+                "Only the 100-continue expectation is supported.".to_string()
+            }
+            Reason::UriTooLong { limit } => format!(
+                "The URI is longer than the server's limit of {limit} bytes, set with \
+                 --max-uri-length."
+            ),
+            Reason::FileTooLarge => "This file is larger than the server's size limit, set \
+                                     with --max-file-size."
+                .to_string(),
+            Reason::PermissionDenied => {
+                "The server doesn't have permission to read this file.".to_string()
+            }
+            Reason::SpecialFile => {
+                "This isn't a regular file, and only regular files are served.".to_string()
+            }
+            Reason::ClientDenied => {
+                "Your address isn't allowed to use this server, as set with --allow and \
+                 --deny."
+                    .to_string()
+            }
+            Reason::InfiniteDepth => {
+                "PROPFIND needs a Depth of 0 or 1; the whole tree isn't listed at once.".to_string()
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Redirect rules from a Netlify-style `_redirects` file.
+//!
+//! Each line is `FROM TO [STATUS]`, with blank lines and lines starting with
+//! `#` ignored. FROM is a URL path, and may end in `*` to match every path
+//! under it, in which case `:splat` in TO is replaced with what the `*`
+//! matched. TO can be a path or a full URL. STATUS defaults to 301.
+
+use crate::error::{Error, Result};
+use http::{HeaderValue, StatusCode};
+use std::fmt;
+use std::path::Path;
+
+/// The redirect rules, in file order. The first matching rule wins.
+#[derive(Clone, Debug, Default)]
+pub struct Redirects {
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    /// The path to match, without any trailing `*`.
+    from: String,
+    /// Whether `from` ended in `*`, matching as a prefix.
+    splat: bool,
+    to: String,
+    status: StatusCode,
+}
+
+impl Redirects {
+    /// Read and parse a redirects file.
+    pub fn load(path: &Path) -> Result<Redirects> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("can't read {}: {e}", path.display())))?;
+        Redirects::parse(&text, path)
+    }
+
+    /// Parse the text of a redirects file, naming `path` and the line number
+    /// in errors.
+    fn parse(text: &str, path: &Path) -> Result<Redirects> {
+        let mut rules = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+
+            let err = |msg: &str| Error::Config(format!("{}:{}: {msg}", path.display(), i + 1));
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (from, to, status) = match fields[..] {
+                [] => continue,
+                [from, to] => (from, to, StatusCode::MOVED_PERMANENTLY),
+                [from, to, status] => match status {
+                    "301" | "302" | "307" | "308" => {
+                        (from, to, status.parse().expect("valid status code"))
+                    }
+                    _ => return Err(err("status must be one of 301, 302, 307 or 308")),
+                },
+                _ => return Err(err("expected FROM TO [STATUS]")),
+            };
+
+            if !from.starts_with('/') {
+                return Err(err("FROM must be a path starting with /"));
+            }
+            if HeaderValue::from_str(to).is_err() {
+                return Err(err("TO isn't a valid Location"));
+            }
+
+            let (from, splat) = match from.strip_suffix('*') {
+                Some(prefix) => (prefix, true),
+                None => (from, false),
+            };
+            rules.push(Rule {
+                from: from.to_string(),
+                splat,
+                to: to.to_string(),
+                status,
+            });
+        }
+
+        Ok(Redirects { rules })
+    }
+
+    /// Find the redirect for a request path, as the status and location.
+    pub fn find(&self, path: &str) -> Option<(StatusCode, String)> {
+        self.rules.iter().find_map(|rule| {
+            let location = if rule.splat {
+                let rest = path.strip_prefix(&rule.from)?;
+                rule.to.replace(":splat", rest)
+            } else if path.trim_end_matches('/') == rule.from.trim_end_matches('/') {
+                rule.to.clone()
+            } else {
+                return None;
+            };
+            Some((rule.status, location))
+        })
+    }
+}
+
+impl fmt::Display for Redirects {
+    /// The rules in the redirects file format, one per line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for rule in &self.rules {
+            let splat = if rule.splat { "*" } else { "" };
+            writeln!(
+                f,
+                "{}{} {} {}",
+                rule.from,
+                splat,
+                rule.to,
+                rule.status.as_u16()
+            )?;
+        }
+        Ok(())
+    }
+}
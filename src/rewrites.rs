@@ -0,0 +1,94 @@
+//! Rewrite rules, which serve one URL path from another without a redirect.
+//!
+//! A rule is `FROM=TO`. FROM is a URL path, and may end in `*` to match every
+//! path under it, in which case a `*` in TO is replaced with what it matched.
+//! Both are %-encoded, as in URLs.
+
+use http::uri::PathAndQuery;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use tracing::warn;
+
+/// The most rewrites applied to one path, in case rules rewrite each other's
+/// output forever.
+const MAX_REWRITES: usize = 10;
+
+/// A `--rewrite` rule.
+#[derive(Clone, Debug)]
+pub struct Rewrite {
+    /// The path to match, without any trailing `*`.
+    from: String,
+    /// Whether `from` ended in `*`, matching as a prefix.
+    splat: bool,
+    to: String,
+}
+
+impl Rewrite {
+    /// Apply the rule to a path, if it matches.
+    fn apply(&self, path: &str) -> Option<String> {
+        if self.splat {
+            let rest = path.strip_prefix(&self.from)?;
+            Some(self.to.replace('*', rest))
+        } else if path == self.from {
+            Some(self.to.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// Rewrite a path with the first matching rule, then rewrite the result the
+/// same way, and so on. Returns `None` if no rule matched.
+pub fn rewrite(rules: &[Rewrite], path: &str) -> Option<String> {
+    let mut rewritten: Option<String> = None;
+    for _ in 0..MAX_REWRITES {
+        let current = rewritten.as_deref().unwrap_or(path);
+        match rules.iter().find_map(|rule| rule.apply(current)) {
+            Some(next) if next != current => rewritten = Some(next),
+            _ => return rewritten,
+        }
+    }
+    warn!("stopped rewriting {} after {} rewrites", path, MAX_REWRITES);
+    rewritten
+}
+
+impl FromStr for Rewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rewrite, String> {
+        let Some((from, to)) = s.split_once('=') else {
+            return Err("expected FROM=TO".to_string());
+        };
+        if !from.starts_with('/') || !to.starts_with('/') {
+            return Err("FROM and TO must be paths starting with /".to_string());
+        }
+        let (from, splat) = match from.strip_suffix('*') {
+            Some(prefix) => (prefix, true),
+            None => (from, false),
+        };
+        // Anything the splat matched came from a valid URL path, so checking
+        // TO without it is enough.
+        if to.contains('?') || PathAndQuery::from_str(&to.replace('*', "")).is_err() {
+            return Err("TO must be a valid URL path".to_string());
+        }
+        Ok(Rewrite {
+            from: from.to_string(),
+            splat,
+            to: to.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let splat = if self.splat { "*" } else { "" };
+        write!(f, "{}{}={}", self.from, splat, self.to)
+    }
+}
+
+impl Serialize for Rewrite {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
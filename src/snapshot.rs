@@ -0,0 +1,55 @@
+//! `--generate-index`: save the directory listings as `index.html` files,
+//! for publishing a tree to a host that can't list directories itself.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::ext::{self, DirWalk};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Write the listing of each directory under the root to `index.html` in the
+/// same place under `out_dir`, then print how many were written.
+///
+/// Directories matching `--hide` aren't listed or descended into, nor are
+/// symlinks to directories or `out_dir` itself. Existing files are only
+/// overwritten with `--force`. See `ext::DirWalk` for how the walk ends.
+pub async fn generate_index(config: &Config, out_dir: &Path) -> Result<()> {
+    tokio::fs::create_dir_all(out_dir).await?;
+    let out_dir_canonical = tokio::fs::canonicalize(out_dir).await?;
+
+    let mut written = 0;
+    let mut skipped = 0;
+    let mut walk = DirWalk::new(config.root_dir.clone());
+    while let Some(dir) = walk.next_dir().await? {
+        let rel = dir
+            .strip_prefix(&config.root_dir)
+            .map_err(Error::StripPrefixInDirList)?;
+
+        let index = out_dir.join(rel).join("index.html");
+        if index.exists() && !config.force {
+            warn!("not overwriting {} without --force", index.display());
+            skipped += 1;
+        } else {
+            let html = ext::dir_index_page(config, &dir).await?;
+            tokio::fs::create_dir_all(out_dir.join(rel)).await?;
+            tokio::fs::write(&index, html).await?;
+            info!("wrote {}", index.display());
+            written += 1;
+        }
+
+        for entry in DirWalk::entries(&dir).await? {
+            let path = entry.path;
+            let rel = path.strip_prefix(&config.root_dir).unwrap_or(&path);
+            if !entry.is_dir || entry.is_symlink || config.hide_patterns.is_hidden(rel) {
+                continue;
+            }
+            if tokio::fs::canonicalize(&path).await? == out_dir_canonical {
+                continue;
+            }
+            walk.descend(path);
+        }
+    }
+
+    println!("{written} index files written, {skipped} existing files skipped");
+    Ok(())
+}
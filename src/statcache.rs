@@ -0,0 +1,63 @@
+//! `--stat-cache`: remember what's at a path on disk for a moment, so the
+//! several checks made while serving a request, and the requests for a
+//! page's assets that arrive together, don't each ask the filesystem.
+//!
+//! Entries last `TTL`, half a second. For that long after a change on disk,
+//! requests can see the old metadata: a directory just created can still
+//! be taken for a missing file, and a file that changed size is sent with its
+//! old length, cutting the response short if it shrank. With
+//! `--no-cache`, for development, the cache isn't used.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How long metadata is remembered.
+pub const TTL: Duration = Duration::from_millis(500);
+
+/// The most paths remembered. Past this, expired entries are dropped, and if
+/// that's not enough, the cache starts over.
+const MAX_ENTRIES: usize = 10_000;
+
+/// What's at a path, or `None` if there's nothing there that can be read.
+static CACHE: LazyLock<Mutex<HashMap<PathBuf, (Instant, Option<Stat>)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The metadata of a path that's remembered.
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+    pub is_dir: bool,
+    pub is_file: bool,
+    pub len: u64,
+}
+
+/// The metadata of a path, following symlinks, from the cache if it's
+/// recent enough.
+pub fn stat(path: &Path) -> Option<Stat> {
+    let now = Instant::now();
+    {
+        let cache = CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((at, stat)) = cache.get(path) {
+            if now.duration_since(*at) < TTL {
+                return *stat;
+            }
+        }
+    }
+
+    // Not holding the lock, so a slow filesystem only holds up this request.
+    let stat = std::fs::metadata(path).ok().map(|meta| Stat {
+        is_dir: meta.is_dir(),
+        is_file: meta.is_file(),
+        len: meta.len(),
+    });
+    let mut cache = CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+    if cache.len() >= MAX_ENTRIES {
+        cache.retain(|_, (at, _)| now.duration_since(*at) < TTL);
+        if cache.len() >= MAX_ENTRIES {
+            cache.clear();
+        }
+    }
+    cache.insert(path.to_owned(), (now, stat));
+    stat
+}
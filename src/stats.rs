@@ -0,0 +1,183 @@
+//! Counters for a summary of the session, logged at shutdown and optionally
+//! every `--stats-interval` seconds, or shown live with `--status-line`.
+
+use crate::ext::human_size;
+use crate::logfile;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+/// Responses by status class, 1xx to 5xx.
+static STATUS_CLASSES: [AtomicU64; 5] = [const { AtomicU64::new(0) }; 5];
+/// The sum of the `Content-Length` of responses.
+static BYTES: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static PEAK_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+/// Panics caught while serving requests or connections.
+static PANICS: AtomicU64 = AtomicU64::new(0);
+/// When a response was last made or a connection last closed, in
+/// milliseconds since the start.
+static LAST_ACTIVITY: AtomicU64 = AtomicU64::new(0);
+/// The latest request, like "GET /index.html 200", for the status line.
+static LAST_REQUEST: Mutex<String> = Mutex::new(String::new());
+
+/// How often the status line is redrawn.
+const STATUS_LINE_PERIOD: Duration = Duration::from_secs(1);
+
+/// The most characters of the latest request shown, to keep the status line
+/// on one line of the terminal.
+const MAX_LAST_REQUEST_LEN: usize = 48;
+
+/// Start the uptime clock.
+pub fn start() {
+    LazyLock::force(&START);
+}
+
+/// Count a response.
+pub fn record_response(status: u16, bytes: u64) {
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+    if let Some(class) = STATUS_CLASSES.get(usize::from(status / 100).wrapping_sub(1)) {
+        class.fetch_add(1, Ordering::Relaxed);
+    }
+    BYTES.fetch_add(bytes, Ordering::Relaxed);
+    touch();
+}
+
+/// Count a panic that was caught.
+pub fn record_panic() {
+    PANICS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Note that the server is active now.
+fn touch() {
+    let now = START.elapsed().as_millis() as u64;
+    LAST_ACTIVITY.fetch_max(now, Ordering::Relaxed);
+}
+
+/// How long there's been no response and no open connection, or `None` if a
+/// connection is open now.
+pub fn idle_for() -> Option<Duration> {
+    if CONNECTIONS.load(Ordering::Relaxed) > 0 {
+        return None;
+    }
+    let last = Duration::from_millis(LAST_ACTIVITY.load(Ordering::Relaxed));
+    Some(START.elapsed().saturating_sub(last))
+}
+
+/// Note the latest request for the status line.
+pub fn record_last_request(method: &str, path: &str, status: u16) {
+    let mut line = format!("{method} {path}");
+    if line.chars().count() > MAX_LAST_REQUEST_LEN {
+        line = line.chars().take(MAX_LAST_REQUEST_LEN - 1).collect();
+        line.push('…');
+    }
+    *LAST_REQUEST.lock().unwrap_or_else(PoisonError::into_inner) = format!("{line} {status}");
+}
+
+/// Count an open connection, until the guard is dropped.
+pub fn connection() -> ConnectionGuard {
+    let open = CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1;
+    PEAK_CONNECTIONS.fetch_max(open, Ordering::Relaxed);
+    ConnectionGuard(())
+}
+
+pub struct ConnectionGuard(());
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        touch();
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The counters at a moment, for the admin endpoint.
+#[derive(Serialize)]
+pub struct Snapshot {
+    requests: u64,
+    /// Responses by status class, like "2xx".
+    statuses: BTreeMap<String, u64>,
+    bytes: u64,
+    connections: u64,
+    peak_connections: u64,
+    panics: u64,
+    uptime_secs: u64,
+}
+
+/// Read the counters.
+pub fn snapshot() -> Snapshot {
+    let statuses = STATUS_CLASSES
+        .iter()
+        .enumerate()
+        .map(|(i, count)| (format!("{}xx", i + 1), count.load(Ordering::Relaxed)))
+        .collect();
+    Snapshot {
+        requests: REQUESTS.load(Ordering::Relaxed),
+        statuses,
+        bytes: BYTES.load(Ordering::Relaxed),
+        connections: CONNECTIONS.load(Ordering::Relaxed),
+        peak_connections: PEAK_CONNECTIONS.load(Ordering::Relaxed),
+        panics: PANICS.load(Ordering::Relaxed),
+        uptime_secs: START.elapsed().as_secs(),
+    }
+}
+
+/// Log the summary.
+pub fn log_summary() {
+    let classes: Vec<String> = STATUS_CLASSES
+        .iter()
+        .enumerate()
+        .map(|(i, count)| format!("{}xx: {}", i + 1, count.load(Ordering::Relaxed)))
+        .collect();
+    info!(
+        "{} requests ({}), {} bytes served, peak {} connections, up {}s",
+        REQUESTS.load(Ordering::Relaxed),
+        classes.join(", "),
+        BYTES.load(Ordering::Relaxed),
+        PEAK_CONNECTIONS.load(Ordering::Relaxed),
+        START.elapsed().as_secs()
+    );
+    let panics = PANICS.load(Ordering::Relaxed);
+    if panics > 0 {
+        warn!("{} panics were caught", panics);
+    }
+}
+
+/// The status line, like "↑ 3 conns | 1240 reqs | 2.1 GiB served | last: GET
+/// /slides/03.html 200".
+fn status_line() -> String {
+    let mut line = format!(
+        "↑ {} conns | {} reqs | {} served",
+        CONNECTIONS.load(Ordering::Relaxed),
+        REQUESTS.load(Ordering::Relaxed),
+        human_size(BYTES.load(Ordering::Relaxed))
+    );
+    let last = LAST_REQUEST.lock().unwrap_or_else(PoisonError::into_inner);
+    if !last.is_empty() {
+        line.push_str(&format!(" | last: {last}"));
+    }
+    line
+}
+
+/// Redraw the status line below the log every second, forever.
+pub async fn show_status_line() -> crate::error::Result<()> {
+    let mut interval = tokio::time::interval(STATUS_LINE_PERIOD);
+    loop {
+        interval.tick().await;
+        logfile::set_status(&status_line());
+    }
+}
+
+/// Log the summary every `secs` seconds, forever.
+pub async fn log_periodically(secs: u64) -> crate::error::Result<()> {
+    let period = Duration::from_secs(secs);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        log_summary();
+    }
+}
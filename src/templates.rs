@@ -0,0 +1,167 @@
+//! The handlebars templates for HTML pages, with custom ones and partials
+//! from `--template-dir`.
+//!
+//! Every `.hbs` or `.html` file in the directory is registered by its name
+//! without the extension, so `header.hbs` can be included with
+//! `{{> header}}`. One named `page` replaces the built-in page template.
+//!
+//! Error pages can be templated too, from `--error-template-dir`, with files
+//! named for a status like `404.hbs`, a class like `5xx.hbs`, or
+//! `default.hbs`. The most specific one for a status is used, and without
+//! any the built-in error page is.
+//!
+//! Error pages are made where there's no configuration at hand, so the
+//! templates in use are kept here, and replaced when the configuration is
+//! loaded or reloaded.
+
+use crate::error::{Error, Result};
+use arc_swap::ArcSwap;
+use handlebars::Handlebars;
+use hyper::StatusCode;
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, LazyLock};
+use tracing::debug;
+
+/// The name of the template every page is rendered with.
+pub const PAGE: &str = "page";
+
+/// The prefix of the names error templates are registered with, so they
+/// can't be mistaken for partials.
+const ERROR_PREFIX: &str = "error/";
+
+/// The built-in page template.
+static BUILTIN_PAGE: &str = include_str!("template.html");
+
+static CURRENT: LazyLock<ArcSwap<Templates>> =
+    LazyLock::new(|| ArcSwap::from_pointee(Templates::default()));
+
+/// The registered templates and partials.
+pub struct Templates {
+    registry: Handlebars<'static>,
+}
+
+impl Default for Templates {
+    fn default() -> Templates {
+        let mut registry = Handlebars::new();
+        registry
+            .register_template_string(PAGE, BUILTIN_PAGE)
+            .expect("built-in template is valid");
+        Templates { registry }
+    }
+}
+
+impl fmt::Debug for Templates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<&String> = self.registry.get_templates().keys().collect();
+        names.sort();
+        f.debug_struct("Templates").field("names", &names).finish()
+    }
+}
+
+impl Templates {
+    /// The built-in page template, with the templates and error templates
+    /// in the directories added.
+    pub fn load(dir: Option<&Path>, error_dir: Option<&Path>) -> Result<Templates> {
+        let mut templates = Templates::default();
+        if let Some(dir) = dir {
+            templates.register_dir(dir, "")?;
+        }
+        if let Some(dir) = error_dir {
+            templates.register_dir(dir, ERROR_PREFIX)?;
+        }
+        Ok(templates)
+    }
+
+    /// Register the templates in a directory, with a prefix on their names.
+    fn register_dir(&mut self, dir: &Path, prefix: &str) -> Result<()> {
+        let shown = dir.display();
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| Error::Config(format!("can't read template directory {shown}: {e}")))?;
+
+        for entry in entries {
+            let path = entry
+                .map_err(|e| Error::Config(format!("can't read template directory {shown}: {e}")))?
+                .path();
+            let is_template = path
+                .extension()
+                .is_some_and(|ext| ext == "hbs" || ext == "html");
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if !is_template || !path.is_file() {
+                continue;
+            }
+            if prefix == ERROR_PREFIX && !is_error_template_name(name) {
+                return Err(Error::Config(format!(
+                    "unexpected error template {}; expected names like 404, 5xx or default",
+                    path.display()
+                )));
+            }
+
+            let name = format!("{prefix}{name}");
+            let text = std::fs::read_to_string(&path)
+                .map_err(|e| Error::Config(format!("can't read {}: {e}", path.display())))?;
+            self.registry
+                .register_template_string(&name, text)
+                .map_err(|e| Error::Config(format!("invalid template {}: {e}", path.display())))?;
+            debug!("registered template {} from {}", name, path.display());
+        }
+        Ok(())
+    }
+
+    /// The error template for a status: the one for the status, or for its
+    /// class, or the default one, if there's any of them.
+    fn error_template(&self, status: StatusCode) -> Option<String> {
+        let code = status.as_u16();
+        [
+            format!("{ERROR_PREFIX}{code}"),
+            format!("{ERROR_PREFIX}{}xx", code / 100),
+            format!("{ERROR_PREFIX}default"),
+        ]
+        .into_iter()
+        .find(|name| self.registry.has_template(name))
+    }
+
+    /// Render the error template for a status, or `None` if there isn't one.
+    pub fn render_error<T: Serialize>(
+        &self,
+        status: StatusCode,
+        data: &T,
+    ) -> Option<Result<String>> {
+        let name = self.error_template(status)?;
+        Some(
+            self.registry
+                .render(&name, data)
+                .map_err(Error::TemplateRender),
+        )
+    }
+
+    /// Render the page template.
+    pub fn render_page<T: Serialize>(&self, data: &T) -> Result<String> {
+        self.registry
+            .render(PAGE, data)
+            .map_err(Error::TemplateRender)
+    }
+}
+
+/// Whether a file name, without its extension, is one an error template can
+/// have: a 4xx or 5xx status, one of those classes, or "default".
+fn is_error_template_name(name: &str) -> bool {
+    match name.as_bytes() {
+        [b'4' | b'5', b'x', b'x'] => true,
+        [b'4' | b'5', b, c] => b.is_ascii_digit() && c.is_ascii_digit(),
+        _ => name == "default",
+    }
+}
+
+/// The templates pages are rendered with.
+pub fn current() -> Arc<Templates> {
+    CURRENT.load_full()
+}
+
+/// Render pages with these templates from now on.
+pub fn install(templates: Arc<Templates>) {
+    CURRENT.store(templates);
+}
@@ -0,0 +1,129 @@
+//! Helpers for tests: a root directory of fixture files made at test time,
+//! and a server on a loopback port to send it requests.
+
+use crate::config::Config;
+use crate::Service;
+use arc_swap::ArcSwap;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::client::conn::http1;
+use hyper::header::{self, HeaderMap};
+use hyper::{Method, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tempfile::TempDir;
+use tokio::net::{TcpListener, TcpStream};
+
+/// A temporary root directory, removed when dropped.
+pub struct Fixture {
+    _dir: TempDir,
+    /// The canonical path of the directory, as the server resolves it.
+    root: PathBuf,
+}
+
+impl Fixture {
+    /// Make a root directory holding `files`, as relative paths and their
+    /// contents. Parent directories are created as needed.
+    pub fn new(files: &[(&str, &str)]) -> Fixture {
+        let dir = tempfile::tempdir().expect("temporary directory");
+        let root = std::fs::canonicalize(dir.path()).expect("canonical path");
+        let fixture = Fixture { _dir: dir, root };
+        for (path, contents) in files {
+            fixture.write(path, contents);
+        }
+        fixture
+    }
+
+    /// The root directory.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Write a file under the root, creating its parent directories.
+    pub fn write(&self, path: &str, contents: &str) {
+        let path = self.root.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).expect("create directories");
+        }
+        std::fs::write(path, contents).expect("write fixture");
+    }
+
+    /// Make a directory under the root.
+    pub fn mkdir(&self, path: &str) {
+        std::fs::create_dir_all(self.root.join(path)).expect("create directory");
+    }
+
+    /// The configuration for serving the root with these options.
+    pub fn config(&self, args: &[&str]) -> Config {
+        let root = self.root.to_str().expect("UTF-8 temporary path");
+        let mut all = vec![env!("CARGO_PKG_NAME"), root];
+        all.extend(args);
+        Config::from_args(&all).expect("valid arguments")
+    }
+}
+
+/// A response, with its body read.
+pub struct Reply {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Reply {
+    /// The body as text.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Serve files with `config` on a loopback port, returning its address.
+pub async fn start(config: Config) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = listener.local_addr().expect("local address");
+    let config = Arc::new(ArcSwap::from_pointee(config));
+    tokio::spawn(crate::accept_tcp(listener, config, Service::Files));
+    addr
+}
+
+/// Send a request on a new connection and read the response. The `Host` is
+/// the server's address unless `headers` gives one.
+pub async fn request(
+    addr: SocketAddr,
+    method: Method,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> Reply {
+    let stream = TcpStream::connect(addr).await.expect("connect");
+    let (mut sender, conn) = http1::handshake(TokioIo::new(stream))
+        .await
+        .expect("handshake");
+    tokio::spawn(conn);
+
+    let mut req = Request::builder().method(method).uri(path);
+    if !headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("host"))
+    {
+        req = req.header(header::HOST, addr.to_string());
+    }
+    for (name, value) in headers {
+        req = req.header(*name, *value);
+    }
+    let req = req.body(Empty::<Bytes>::new()).expect("valid request");
+
+    let resp = sender.send_request(req).await.expect("response");
+    let (parts, body) = resp.into_parts();
+    let body = body.collect().await.expect("response body").to_bytes();
+    Reply {
+        status: parts.status,
+        headers: parts.headers,
+        body,
+    }
+}
+
+/// Send a GET request with no extra headers.
+pub async fn get(addr: SocketAddr, path: &str) -> Reply {
+    request(addr, Method::GET, path, &[]).await
+}
@@ -0,0 +1,86 @@
+//! Trees served from somewhere other than the root directory on disk: an
+//! archive given as the root, or with `--embedded`, the tree built into the
+//! binary.
+//!
+//! Requests are mapped to local paths under the root as usual, and a `Vfs`
+//! answers for those paths in place of the filesystem. Without one, files
+//! are served from disk directly.
+
+use crate::error::{Error, Result};
+use futures::future::BoxFuture;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::Bytes;
+use std::io;
+use std::path::{Component, Path, PathBuf, MAIN_SEPARATOR};
+use std::time::SystemTime;
+
+/// A read-only tree of files and directories.
+pub trait Vfs: Send + Sync {
+    /// What the tree is, for the startup banner.
+    fn describe(&self) -> String;
+
+    /// Whether a local path under the root is a directory.
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// The size and modification time of a file, or `None` if a local path
+    /// under the root isn't a file.
+    fn file_meta(&self, path: &Path) -> Option<(u64, Option<SystemTime>)>;
+
+    /// The entries of a directory, as local paths under the root, with the
+    /// sizes of the files. Returns `None` if there's no such directory.
+    fn list(&self, path: &Path) -> Option<Vec<(PathBuf, Option<u64>)>>;
+
+    /// Read a file, returning its size and its contents as a body.
+    fn read<'a>(
+        &'a self,
+        path: &'a Path,
+        buffer_size: usize,
+    ) -> BoxFuture<'a, Result<(u64, BoxBody<Bytes, Error>)>>;
+
+    /// Whether a local path under the root is a file.
+    fn is_file(&self, path: &Path) -> bool {
+        self.file_meta(path).is_some()
+    }
+
+    /// Read a whole file into memory.
+    fn read_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, Result<Bytes>> {
+        Box::pin(async move {
+            let (_, body) = self.read(path, 8 * 1024).await?;
+            Ok(body.collect().await?.to_bytes())
+        })
+    }
+}
+
+/// The "/"-separated path in a tree of a local path under `root`, and
+/// whether it must be a directory, having ended with "/".
+///
+/// Returns `None` if the path isn't under the root, isn't UTF-8, or climbs
+/// out of the tree.
+pub fn tree_key(root: &Path, path: &Path) -> Option<(String, bool)> {
+    let rel = path.strip_prefix(root).ok()?;
+    let dir_only = path
+        .as_os_str()
+        .as_encoded_bytes()
+        .ends_with(&[MAIN_SEPARATOR as u8]);
+    Some((relative_key(rel)?, dir_only))
+}
+
+/// The "/"-separated path in a tree of a relative path, or `None` if it's
+/// not UTF-8 or climbs out of the tree.
+pub fn relative_key(path: &Path) -> Option<String> {
+    let mut names = Vec::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(name) => names.push(name.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(names.join("/"))
+}
+
+/// The error for a path that isn't in a tree.
+pub fn not_found() -> Error {
+    Error::from(io::Error::from(io::ErrorKind::NotFound))
+}
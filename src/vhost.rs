@@ -0,0 +1,248 @@
+//! Virtual hosts, which serve a different root directory for each host name
+//! requests are made to.
+//!
+//! A host is `HOST=DIR`. HOST is matched case-insensitively against the
+//! request's host without its port, and may start with `*.` to match every
+//! subdomain of the rest. Exact hosts are preferred over wildcards, and
+//! otherwise the first match wins.
+
+use http::uri::Authority;
+use http::{header, HeaderMap, Uri};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A `--vhost` host.
+#[derive(Clone, Debug)]
+pub struct VHost {
+    /// The lowercase host name, without any leading `*.`.
+    host: String,
+    /// Whether the host started with `*.`, matching subdomains.
+    wildcard: bool,
+    root_dir: PathBuf,
+}
+
+/// Find the index of the `--vhost` for a request's host, if any matches.
+pub fn position(vhosts: &[VHost], host: &str) -> Option<usize> {
+    let exact = vhosts.iter().position(|v| !v.wildcard && v.host == host);
+    let wildcard = || {
+        vhosts.iter().position(|v| {
+            v.wildcard
+                && host
+                    .strip_suffix(&v.host)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.'))
+        })
+    };
+    exact.or_else(wildcard)
+}
+
+/// The host a request was made to, lowercase and without the port.
+///
+/// This is the host of an absolute request URI, else the `Host` header.
+pub fn request_host(uri: &Uri, headers: &HeaderMap) -> Option<String> {
+    let host = match uri.authority() {
+        Some(authority) => authority.host().to_string(),
+        None => {
+            let value = headers.get(header::HOST)?.to_str().ok()?;
+            Authority::from_str(value).ok()?.host().to_string()
+        }
+    };
+    // "example.com." is the same host as "example.com".
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+impl VHost {
+    /// The root directory for the host.
+    pub fn root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+
+    /// Replace the root directory, once it has been checked at startup.
+    pub fn set_root_dir(&mut self, root_dir: PathBuf) {
+        self.root_dir = root_dir;
+    }
+
+    /// The host as given, with any leading `*.`.
+    pub fn host(&self) -> String {
+        if self.wildcard {
+            format!("*.{}", self.host)
+        } else {
+            self.host.clone()
+        }
+    }
+}
+
+impl FromStr for VHost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<VHost, String> {
+        let Some((host, root_dir)) = s.split_once('=') else {
+            return Err("expected HOST=DIR".to_string());
+        };
+        let (host, wildcard) = match host.strip_prefix("*.") {
+            Some(rest) => (rest, true),
+            None => (host, false),
+        };
+        // Just a host: no port, user info or further wildcards.
+        let valid = Authority::from_str(host).is_ok_and(|a| a.host() == host && a.port().is_none());
+        if !valid || host.contains('*') {
+            return Err(format!("invalid host name {host:?}"));
+        }
+        if root_dir.is_empty() {
+            return Err("DIR must not be empty".to_string());
+        }
+        Ok(VHost {
+            host: host.trim_end_matches('.').to_ascii_lowercase(),
+            wildcard,
+            root_dir: PathBuf::from(root_dir),
+        })
+    }
+}
+
+impl fmt::Display for VHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let wildcard = if self.wildcard { "*." } else { "" };
+        write!(f, "{}{}={}", wildcard, self.host, self.root_dir.display())
+    }
+}
+
+impl Serialize for VHost {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Fixture};
+    use http::{Method, StatusCode};
+
+    fn vhosts(specs: &[&str]) -> Vec<VHost> {
+        specs.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    fn host_of(uri: &str, host: Option<&str>) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        if let Some(host) = host {
+            headers.insert(header::HOST, host.parse().unwrap());
+        }
+        request_host(&uri.parse().unwrap(), &headers)
+    }
+
+    #[test]
+    fn request_host_is_lowercase_without_port() {
+        assert_eq!(
+            host_of("/", Some("A.LocalHost:4000")).as_deref(),
+            Some("a.localhost")
+        );
+        assert_eq!(
+            host_of("/", Some("a.localhost.")).as_deref(),
+            Some("a.localhost")
+        );
+        assert_eq!(host_of("/", Some("[::1]:4000")).as_deref(), Some("[::1]"));
+        assert_eq!(
+            host_of("http://B.localhost:8080/x", Some("a.localhost")).as_deref(),
+            Some("b.localhost")
+        );
+        assert_eq!(host_of("/", None), None);
+    }
+
+    #[test]
+    fn exact_hosts_win_over_wildcards() {
+        let v = vhosts(&[
+            "*.dev.localhost=dev",
+            "A.localhost=a",
+            "api.dev.localhost=api",
+        ]);
+        assert_eq!(position(&v, "a.localhost"), Some(1));
+        assert_eq!(position(&v, "x.dev.localhost"), Some(0));
+        assert_eq!(position(&v, "x.y.dev.localhost"), Some(0));
+        assert_eq!(position(&v, "api.dev.localhost"), Some(2));
+        assert_eq!(position(&v, "dev.localhost"), None);
+        assert_eq!(position(&v, "xdev.localhost"), None);
+        assert_eq!(position(&v, "b.localhost"), None);
+    }
+
+    #[test]
+    fn invalid_vhosts() {
+        for spec in [
+            "a.localhost",
+            "a.localhost:80=dir",
+            "a.*.localhost=dir",
+            "a.localhost=",
+        ] {
+            assert!(spec.parse::<VHost>().is_err(), "{spec}");
+        }
+    }
+
+    /// Serve one root with three `--vhost` hosts, returning the server's
+    /// address. Each root has a file saying which root it is.
+    async fn start(fixture: &Fixture, strict: bool) -> std::net::SocketAddr {
+        for name in ["main", "a", "dev", "api"] {
+            let path = if name == "main" {
+                "who.txt".to_string()
+            } else {
+                format!("{name}/who.txt")
+            };
+            fixture.write(&path, name);
+        }
+        let root = fixture.root().display();
+        let specs = [
+            format!("A.localhost={root}/a"),
+            format!("*.dev.localhost={root}/dev"),
+            format!("api.dev.localhost={root}/api"),
+        ];
+        let mut args = Vec::new();
+        for spec in &specs {
+            args.extend(["--vhost", spec.as_str()]);
+        }
+        if strict {
+            args.push("--vhost-strict");
+        }
+        testing::start(fixture.config(&args)).await
+    }
+
+    async fn who(addr: std::net::SocketAddr, host: &str) -> (StatusCode, String) {
+        let reply = testing::request(addr, Method::GET, "/who.txt", &[("host", host)]).await;
+        (reply.status, reply.text())
+    }
+
+    #[tokio::test]
+    async fn hosts_are_served_from_their_roots() {
+        let fixture = Fixture::new(&[]);
+        let addr = start(&fixture, false).await;
+        let cases = [
+            ("a.localhost", "a"),
+            ("A.LOCALHOST:4000", "a"),
+            ("a.localhost.", "a"),
+            ("x.dev.localhost", "dev"),
+            ("api.dev.localhost:80", "api"),
+            ("dev.localhost", "main"),
+            ("other.localhost", "main"),
+        ];
+        for (host, root) in cases {
+            assert_eq!(
+                who(addr, host).await,
+                (StatusCode::OK, root.to_string()),
+                "{host}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_hosts_refuse_others() {
+        let fixture = Fixture::new(&[]);
+        let addr = start(&fixture, true).await;
+        assert_eq!(
+            who(addr, "a.localhost").await,
+            (StatusCode::OK, "a".to_string())
+        );
+        assert_eq!(who(addr, "x.dev.localhost").await.0, StatusCode::OK);
+        for host in ["other.localhost", "dev.localhost"] {
+            let (status, _) = who(addr, host).await;
+            assert_eq!(status, StatusCode::MISDIRECTED_REQUEST, "{host}");
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Read-only WebDAV, enough for file managers to browse the server, enabled
+//! by `--webdav`.
+//!
+//! Only `PROPFIND` is supported, and every request gets the same few
+//! properties, whatever its body asks for. Other WebDAV methods are refused
+//! like any other method besides GET and HEAD.
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::reason::Reason;
+use http::StatusCode;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{self, HeaderValue};
+use hyper::{Request, Response};
+use std::fmt::Write;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// Whether a request is a `PROPFIND`.
+pub fn is_propfind(req: &Request<Incoming>) -> bool {
+    req.method().as_str() == "PROPFIND"
+}
+
+/// Answer a `PROPFIND` with a multistatus describing the requested file or
+/// directory and, with `Depth: 1`, each of a directory's entries.
+///
+/// Paths are resolved as for GET, and paths hidden from directory listings
+/// or not servable don't exist.
+pub async fn propfind(
+    req: &Request<Incoming>,
+    config: &Config,
+    path: &Path,
+) -> Result<Response<BoxBody<Bytes, Error>>> {
+    // No depth means infinity, which could mean listing the whole tree.
+    // https://tools.ietf.org/html/rfc4918#section-9.1
+    let with_entries = match req.headers().get("depth").map(HeaderValue::as_bytes) {
+        Some(b"0") => false,
+        Some(b"1") => true,
+        _ => {
+            debug!("refusing PROPFIND with infinite depth");
+            let path = super::error_page_path(config, req.uri());
+            return super::make_error_response_for_reason(Reason::InfiniteDepth, path);
+        }
+    };
+
+    let meta = tokio::fs::metadata(path).await?;
+    if !is_visible(config, path, &meta) {
+        return Err(Error::from(io::Error::from(io::ErrorKind::NotFound)));
+    }
+
+    let mut body = String::new();
+    writeln!(body, "<?xml version=\"1.0\" encoding=\"utf-8\"?>").map_err(Error::WriteInDirList)?;
+    writeln!(body, "<D:multistatus xmlns:D=\"DAV:\">").map_err(Error::WriteInDirList)?;
+    write_response(&mut body, &config.root_dir, path, &meta)?;
+
+    if with_entries && meta.is_dir() {
+        let mut dents = tokio::fs::read_dir(path).await?;
+        let mut paths: Vec<PathBuf> = Vec::new();
+        while let Some(e) = dents.next_entry().await? {
+            paths.push(e.path());
+        }
+        paths.sort();
+        for path in paths {
+            // Skip broken symlinks, as the HTML listing can't serve them either.
+            let Ok(meta) = tokio::fs::metadata(&path).await else {
+                continue;
+            };
+            if is_visible(config, &path, &meta) {
+                write_response(&mut body, &config.root_dir, &path, &meta)?;
+            }
+        }
+    }
+
+    writeln!(body, "</D:multistatus>").map_err(Error::WriteInDirList)?;
+
+    Response::builder()
+        .status(StatusCode::MULTI_STATUS)
+        .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+        .header(header::CONTENT_LENGTH, body.len() as u64)
+        .body(
+            Full::new(Bytes::from(body))
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .map_err(Error::from)
+}
+
+/// Whether a path would appear in an HTML directory listing.
+fn is_visible(config: &Config, path: &Path, meta: &Metadata) -> bool {
+    let rel = path.strip_prefix(&config.root_dir).unwrap_or(path);
+    !config.hide_patterns.is_hidden(rel) && (meta.is_dir() || config.is_servable(path))
+}
+
+/// Write the `<D:response>` element for one file or directory.
+fn write_response(buf: &mut String, root_dir: &Path, path: &Path, meta: &Metadata) -> Result<()> {
+    let rel = path
+        .strip_prefix(root_dir)
+        .map_err(Error::StripPrefixInDirList)?;
+    let Some(url) = crate::ext::encode_url_path(rel) else {
+        warn!("non-unicode path: {}", rel.to_string_lossy());
+        return Ok(());
+    };
+    let href = if meta.is_dir() && !url.is_empty() {
+        format!("/{url}/")
+    } else {
+        format!("/{url}")
+    };
+    let name = rel
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    let mut props = format!(
+        "<D:displayname>{}</D:displayname>",
+        handlebars::html_escape(&name)
+    );
+    if meta.is_dir() {
+        props.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        let mime = super::file_path_mime(path);
+        write!(
+            props,
+            "<D:resourcetype/>\
+             <D:getcontentlength>{}</D:getcontentlength>\
+             <D:getcontenttype>{}</D:getcontenttype>",
+            meta.len(),
+            handlebars::html_escape(mime.as_ref())
+        )
+        .map_err(Error::WriteInDirList)?;
+    }
+    if let Ok(modified) = meta.modified() {
+        write!(
+            props,
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            httpdate::fmt_http_date(modified)
+        )
+        .map_err(Error::WriteInDirList)?;
+    }
+
+    writeln!(
+        buf,
+        "<D:response><D:href>{}</D:href>\
+         <D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>\
+         </D:response>",
+        handlebars::html_escape(&href),
+        props
+    )
+    .map_err(Error::WriteInDirList)?;
+
+    Ok(())
+}